@@ -0,0 +1,200 @@
+use anyhow::Context;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Mutex;
+
+/// How long the agent keeps a passphrase unless told otherwise.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(15 * 60);
+// a client talking to something that isn't an agent shouldn't hang rvar
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The socket of the agent, `RUGGIT_AGENT_SOCK` or `agent.sock` in the cache.
+pub fn socket_path(cache_dir: &Path) -> PathBuf {
+    std::env::var_os("RUGGIT_AGENT_SOCK")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| cache_dir.join("agent.sock"))
+}
+
+#[derive(Default)]
+struct Held {
+    passphrase: Option<(String, Instant)>,
+}
+
+impl Held {
+    fn get(&mut self, ttl: Duration) -> Option<&str> {
+        if matches!(&self.passphrase, Some((_, at)) if at.elapsed() >= ttl) {
+            self.passphrase = None;
+        }
+        self.passphrase.as_ref().map(|(p, _)| p.as_str())
+    }
+}
+
+/// Keeps a passphrase in memory for `ttl` after it was last stored, the
+/// protocol is a line per request, `get` answered by `ok <passphrase>` or
+/// `none`, and `set <passphrase>` answered by `ok`.
+pub struct Agent {
+    listener: UnixListener,
+    ttl: Duration,
+}
+
+impl Agent {
+    /// Listens on `path`, replacing a socket left behind by a stopped agent.
+    /// The socket is bound in a directory only the user can enter and moved
+    /// to `path` once restricted, nobody else can connect in between.
+    pub fn bind(path: &Path, ttl: Duration) -> anyhow::Result<Self> {
+        if path.exists() {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                anyhow::bail!("an agent is already listening on {}", path.display())
+            }
+            std::fs::remove_file(path)?;
+        }
+        let parent = path.parent().unwrap_or(Path::new("."));
+        let private = parent.join(format!(".agent.{}", std::process::id()));
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&private)
+            .with_context(|| format!("failed to create {}", private.display()))?;
+        let bound = (|| {
+            let socket = private.join("agent.sock");
+            let listener = UnixListener::bind(&socket)
+                .with_context(|| format!("failed to listen on {}", path.display()))?;
+            std::fs::set_permissions(&socket, std::fs::Permissions::from_mode(0o600))?;
+            std::fs::rename(&socket, path)?;
+            anyhow::Ok(listener)
+        })();
+        let _ = std::fs::remove_dir_all(&private);
+        Ok(Self {
+            listener: bound?,
+            ttl,
+        })
+    }
+
+    /// Serves clients until the task is dropped.
+    pub async fn serve(self) -> anyhow::Result<()> {
+        let held = Arc::new(Mutex::new(Held::default()));
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            let held = held.clone();
+            let ttl = self.ttl;
+            tokio::spawn(async move {
+                if let Err(e) = handle(stream, &held, ttl).await {
+                    tracing::debug!("agent client failed: {e}");
+                }
+            });
+        }
+    }
+}
+
+async fn handle(stream: UnixStream, held: &Mutex<Held>, ttl: Duration) -> anyhow::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let reply = match line.split_once(' ') {
+            None if line == "get" => match held.lock().await.get(ttl) {
+                Some(passphrase) => format!("ok {passphrase}\n"),
+                None => "none\n".to_string(),
+            },
+            Some(("set", passphrase)) => {
+                held.lock().await.passphrase = Some((passphrase.to_string(), Instant::now()));
+                "ok\n".to_string()
+            }
+            _ => "error unknown request\n".to_string(),
+        };
+        write.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+async fn request(path: &Path, line: &str) -> anyhow::Result<String> {
+    let exchange = async {
+        let mut stream = UnixStream::connect(path).await?;
+        stream.write_all(format!("{line}\n").as_bytes()).await?;
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply).await?;
+        anyhow::Ok(reply.trim_end_matches('\n').to_string())
+    };
+    tokio::time::timeout(CLIENT_TIMEOUT, exchange)
+        .await
+        .context("agent did not answer")?
+}
+
+/// The passphrase held by the agent at `path`, `None` when there is no
+/// agent or it has none.
+pub async fn fetch(path: &Path) -> Option<String> {
+    match request(path, "get").await {
+        Ok(reply) => reply.strip_prefix("ok ").map(String::from),
+        Err(e) => {
+            tracing::debug!("no passphrase from agent: {e}");
+            None
+        }
+    }
+}
+
+/// Hands `passphrase` to the agent at `path`, if one is running.
+pub async fn store(path: &Path, passphrase: &str) -> anyhow::Result<()> {
+    if passphrase.contains('\n') {
+        anyhow::bail!("passphrase spans lines, not handed to the agent")
+    }
+    match request(path, &format!("set {passphrase}")).await?.as_str() {
+        "ok" => Ok(()),
+        other => anyhow::bail!("agent refused the passphrase: {other}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ruggit-agent-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("agent.sock")
+    }
+
+    #[tokio::test]
+    async fn test_fetch_and_expire() {
+        let path = socket("expire");
+        let agent = Agent::bind(&path, Duration::from_millis(200)).unwrap();
+        let server = tokio::spawn(agent.serve());
+
+        assert_eq!(fetch(&path).await, None, "nothing stored yet");
+        store(&path, "correct horse").await.unwrap();
+        assert_eq!(fetch(&path).await.as_deref(), Some("correct horse"));
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(fetch(&path).await, None, "passphrase outlived its ttl");
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_no_agent() {
+        let path = socket("missing");
+        assert_eq!(fetch(&path).await, None);
+        assert!(store(&path, "secret").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replaces_stale_socket() {
+        let path = socket("stale");
+        drop(Agent::bind(&path, DEFAULT_TTL).unwrap());
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let dir = path.parent().unwrap();
+        assert_eq!(
+            std::fs::read_dir(dir).unwrap().count(),
+            1,
+            "only the socket"
+        );
+        let agent = Agent::bind(&path, DEFAULT_TTL).unwrap();
+        let server = tokio::spawn(agent.serve());
+        assert!(
+            Agent::bind(&path, DEFAULT_TTL).is_err(),
+            "a live agent isn't replaced"
+        );
+        server.abort();
+    }
+}
@@ -0,0 +1,183 @@
+use crate::diff::{self, VariableChange};
+use crate::gapi::{self, GitlabResource, GitlabVariable};
+use anyhow::Result;
+use std::future::Future;
+
+/// The mutations needed to apply a plan.
+pub trait VariableWriter {
+    fn create(&self, variable: &GitlabVariable) -> impl Future<Output = Result<()>>;
+    fn update(&self, variable: &GitlabVariable) -> impl Future<Output = Result<()>>;
+    fn delete(&self, variable: &GitlabVariable) -> impl Future<Output = Result<()>>;
+}
+
+impl VariableWriter for GitlabResource {
+    async fn create(&self, variable: &GitlabVariable) -> Result<()> {
+        self.create_variable(variable).await.map(|_| ())
+    }
+
+    async fn update(&self, variable: &GitlabVariable) -> Result<()> {
+        self.update_variable(variable).await.map(|_| ())
+    }
+
+    async fn delete(&self, variable: &GitlabVariable) -> Result<()> {
+        self.delete_variable(variable).await
+    }
+}
+
+/// The changes turning `remote` into `desired`, variables missing from
+/// `desired` are only removed when `prune` is set. A `values_only` file can
+/// only express the `*` scope and leaves hidden variables out, so only
+/// readable `*` variables are removed.
+pub fn plan(
+    remote: &[GitlabVariable],
+    desired: &[GitlabVariable],
+    prune: bool,
+    values_only: bool,
+) -> Vec<VariableChange> {
+    diff::diff(remote, desired)
+        .into_iter()
+        .filter(|c| match c {
+            VariableChange::Removed(v) => {
+                prune && (!values_only || (v.scope() == "*" && !v.hidden))
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+/// Issues one request per change, stopping at the first failure. Keys are
+/// validated up front, an invalid one fails before anything is changed.
+pub async fn apply(writer: &impl VariableWriter, changes: &[VariableChange]) -> Result<()> {
+    for change in changes {
+        match change {
+            VariableChange::Added(v) | VariableChange::Changed { new: v, .. } => {
+                gapi::validate_key(&v.key)?
+            }
+            VariableChange::Removed(_) => (),
+        }
+    }
+    for change in changes {
+        match change {
+            VariableChange::Added(v) => writer.create(v).await?,
+            VariableChange::Changed { new, .. } => writer.update(new).await?,
+            VariableChange::Removed(v) => writer.delete(v).await?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::varfile;
+    use std::cell::RefCell;
+
+    #[derive(Default)]
+    struct MockProject {
+        requests: RefCell<Vec<String>>,
+    }
+
+    impl VariableWriter for MockProject {
+        async fn create(&self, variable: &GitlabVariable) -> Result<()> {
+            let request = format!("POST {}={}", variable.key, variable.value);
+            self.requests.borrow_mut().push(request);
+            Ok(())
+        }
+
+        async fn update(&self, variable: &GitlabVariable) -> Result<()> {
+            let request = format!("PUT {}={}", variable.key, variable.value);
+            self.requests.borrow_mut().push(request);
+            Ok(())
+        }
+
+        async fn delete(&self, variable: &GitlabVariable) -> Result<()> {
+            self.requests
+                .borrow_mut()
+                .push(format!("DELETE {}", variable.key));
+            Ok(())
+        }
+    }
+
+    // scoped as gitlab returns a variable for every environment
+    fn var(key: &str, value: &str) -> GitlabVariable {
+        GitlabVariable {
+            key: key.to_string(),
+            value: value.to_string(),
+            environment_scope: Some("*".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply() {
+        let mut token = var("TOKEN", "secret12");
+        token.masked = true;
+        token.raw = true;
+        let remote = vec![
+            var("HOST", "localhost"),
+            var("OLD", "unused"),
+            token,
+            var("UNCHANGED", "same"),
+        ];
+        let mut desired =
+            varfile::parse_dotenv("HOST=127.0.0.1\nNEW=value\nTOKEN=secret12\nUNCHANGED=same")
+                .unwrap();
+        varfile::inherit_attributes(&mut desired, &remote);
+
+        let project = MockProject::default();
+        apply(&project, &plan(&remote, &desired, false, true))
+            .await
+            .unwrap();
+        assert_eq!(
+            *project.requests.borrow(),
+            vec!["PUT HOST=127.0.0.1", "POST NEW=value"]
+        );
+
+        let project = MockProject::default();
+        apply(&project, &plan(&remote, &desired, true, true))
+            .await
+            .unwrap();
+        assert_eq!(
+            *project.requests.borrow(),
+            vec!["PUT HOST=127.0.0.1", "POST NEW=value", "DELETE OLD"]
+        );
+    }
+
+    #[test]
+    fn test_prune_values_only() {
+        let mut production = var("HOST", "prod");
+        production.environment_scope = Some("production".to_string());
+        let mut hidden = var("SECRET", "");
+        hidden.hidden = true;
+        let remote = vec![
+            var("HOST", "localhost"),
+            production,
+            hidden,
+            var("OLD", "1"),
+        ];
+        let desired = varfile::parse_dotenv("HOST=localhost").unwrap();
+        let removed = |values_only| -> Vec<String> {
+            plan(&remote, &desired, true, values_only)
+                .iter()
+                .map(|c| match c {
+                    VariableChange::Removed(v) => format!("{}:{}", v.key, v.scope()),
+                    other => panic!("unexpected {other:?}"),
+                })
+                .collect()
+        };
+        // neither the scoped nor the hidden variable could be in the file
+        assert_eq!(removed(true), vec!["OLD:*"]);
+        assert_eq!(removed(false).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_apply_invalid_key() {
+        let desired = vec![var("GOOD", "1"), var("NOT-GOOD", "2")];
+        let project = MockProject::default();
+        let error = apply(&project, &plan(&[], &desired, false, false))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("NOT-GOOD"), "{error}");
+        assert!(project.requests.borrow().is_empty(), "nothing was applied");
+    }
+}
@@ -1,53 +1,1001 @@
+use ruggit::agent;
+use ruggit::apply;
+use ruggit::cache;
 use ruggit::cmdline;
-use ruggit::crypto::PasswdProtectedFile;
-use ruggit::gapi;
-use ruggit::gitlab_cache::CachedResources;
-use ruggit::token::TokenStore;
-use ruggit::uri_meta;
+use ruggit::config;
+use ruggit::crypto::{EncryptedRW, PasswdProtectedFile};
+use ruggit::diff;
+use ruggit::gapi::{self, ClientSettings, GApi, GitlabResource, GroupStore, UserStore};
+use ruggit::gitlab_cache::{self, CachedResources, GroupCache, UserCache};
+use ruggit::lock::ProcessLock;
+use ruggit::oauth;
+use ruggit::output;
+use ruggit::output::{ErrorFormat, ErrorKind};
+use ruggit::paths;
+use ruggit::token::{self, TokenBackend, TokenChain, TokenSource, TokenStore};
+use ruggit::transform;
+use ruggit::uri_meta::{self, UriMeta};
+use ruggit::varfile;
+use ruggit::watch;
 use std::env;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+static ERROR_FORMAT: OnceLock<ErrorFormat> = OnceLock::new();
+static WATCHING: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: Notify = Notify::const_new();
+static LOCK_PATH: OnceLock<PathBuf> = OnceLock::new();
+const LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+// how long a listing of groups is reused while resolving paths
+const GROUP_TTL: Duration = Duration::from_secs(60 * 60);
+// how long the user of a token is trusted to still be valid
+const USER_TTL: Duration = Duration::from_secs(15 * 60);
 
 #[tokio::main]
 async fn main() {
-    let Some(home) = env::vars().find(|(k, _)| k == "HOME") else {
-        println!("no home-path in env");
-        std::process::exit(0);
+    // nothing below runs for these, no prompt, config or directories
+    if let Some(text) = cmdline::early_exit(env::args().skip(1)) {
+        println!("{text}");
+        return;
+    }
+    let _ = ERROR_FORMAT.set(cmdline::error_format(env::args()));
+    let (Some(config_path), Some(cache_path)) = (
+        paths::config_dir(paths::from_env),
+        paths::cache_dir(paths::from_env),
+    ) else {
+        fail(ErrorKind::Config, anyhow::anyhow!("no home-path in env"));
+    };
+    let config = config::Config::load(&config_path.join("config.toml"))
+        .unwrap_or_else(|e| fail(ErrorKind::Config, e));
+    let defaults = config
+        .default_args()
+        .unwrap_or_else(|e| fail(ErrorKind::Config, e));
+    let mut args = cmdline::parse_args_with(defaults, env::args().skip(1))
+        .unwrap_or_else(|e| fail(ErrorKind::Usage, e));
+    cmdline::init_tracing(args.verbose, args.explain, args.color);
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            // a watch ends on ctrl-c, anything else is aborted
+            match WATCHING.load(Ordering::SeqCst) {
+                true => INTERRUPTED.notify_one(),
+                false => cmdline::abort(),
+            }
+        }
+    });
+    for path in [&config_path, &cache_path] {
+        if !path.exists() {
+            if let Err(e) = std::fs::create_dir_all(path) {
+                println!("unable to create {}: {e}", path.display());
+            }
+        }
+    }
+
+    if args.command == cmdline::Command::PrintConfig {
+        let mut settings = config
+            .effective(&args)
+            .unwrap_or_else(|e| fail(ErrorKind::Config, e));
+        let config_origin = format!("${}", paths::config_dir_origin(paths::from_env));
+        let cache_origin = format!("${}", paths::cache_dir_origin(paths::from_env));
+        settings.push(config::Setting::new(
+            "config_dir",
+            config_path.display(),
+            &config_origin,
+        ));
+        settings.push(config::Setting::new(
+            "cache_dir",
+            cache_path.display(),
+            &cache_origin,
+        ));
+        println!("{}", output::render_settings(&settings));
+        return;
+    }
+
+    // only touches the config, no passphrase needed
+    if let cmdline::Command::Alias { action } = &args.command {
+        let path = config_path.join("config.toml");
+        match action {
+            cmdline::AliasAction::Add { name, target } => {
+                config::add_alias(&path, name, target)
+                    .unwrap_or_else(|e| fail(ErrorKind::Config, e));
+            }
+            cmdline::AliasAction::List => {
+                let mut aliases: Vec<_> = config.aliases.iter().collect();
+                aliases.sort();
+                for (name, target) in aliases {
+                    println!("{name} = {target}");
+                }
+            }
+            cmdline::AliasAction::Remove { name } => {
+                let removed = config::remove_alias(&path, name)
+                    .unwrap_or_else(|e| fail(ErrorKind::Config, e));
+                if !removed {
+                    fail(
+                        ErrorKind::NotFound,
+                        anyhow::anyhow!("no alias named {name}"),
+                    );
+                }
+            }
+        }
+        return;
+    }
+
+    // runs until stopped, the lock would keep every other run waiting
+    let agent_socket = agent::socket_path(&cache_path);
+    if let cmdline::Command::Agent { ttl } = args.command {
+        let agent =
+            agent::Agent::bind(&agent_socket, ttl).unwrap_or_else(|e| fail(ErrorKind::Config, e));
+        eprintln!(
+            "holding the passphrase for {} on {}",
+            humantime::format_duration(ttl),
+            agent_socket.display()
+        );
+        if let Err(e) = agent.serve().await {
+            fail(ErrorKind::Config, e);
+        }
+        return;
+    }
+
+    let mut settings = config.client_settings();
+    settings.per_page = args.per_page;
+    if let Some(jobs) = args.jobs {
+        settings.jobs = jobs;
+    }
+    let mut options = ApiOptions {
+        settings,
+        api_path: config
+            .api_path
+            .clone()
+            .unwrap_or(gapi::DEFAULT_API_PATH.to_string()),
+        insecure: args.insecure,
+        groups: None,
+        users: None,
+    };
+
+    let _ = LOCK_PATH.set(config_path.join("rvar.lock"));
+    let passphrase_lock = lock();
+
+    let backend = config.token_backend.unwrap_or_default();
+    // the caches on disk are encrypted with the passphrase as well, without
+    // one they are only kept in memory
+    let reads_caches = args.offline
+        || args.snapshot.is_some()
+        || matches!(
+            args.command,
+            cmdline::Command::Verify
+                | cmdline::Command::List
+                | cmdline::Command::CacheInfo
+                | cmdline::Command::PruneCache { .. }
+                | cmdline::Command::Snapshot { .. }
+                | cmdline::Command::Diff
+        );
+    let needs_passphrase = backend.needs_passphrase() || reads_caches;
+    if !needs_passphrase {
+        args.no_cache = true;
+    }
+    // nothing to decrypt yet, make sure the passphrase is what the user meant
+    let first_run = !config_path.join("tokens").exists() && !cache_path.join("resources").exists();
+    let mut from_agent = if first_run || !needs_passphrase {
+        None
+    } else {
+        agent::fetch(&agent_socket).await
+    };
+    let passphrase = if let Some(passphrase) = from_agent.clone() {
+        Ok(passphrase)
+    } else if !needs_passphrase {
+        Ok(String::new())
+    } else if first_run {
+        cmdline::new_passphrase(cmdline::hidden_input_with_prompt)
+    } else {
+        cmdline::hidden_input_with_prompt("passphrase: ")
     };
-    let home = home.1;
-    let config_path = std::path::PathBuf::from(&home).join(".config/ruggit");
+    let mut passphrase = match passphrase {
+        Ok(passphrase) => passphrase,
+        Err(e) => {
+            eprintln!("{e}");
+            cmdline::abort();
+        }
+    };
+    // before the token store, which would refuse a wrong passphrase itself
+    if args.command == cmdline::Command::Verify {
+        let tokens = PasswdProtectedFile::new(&passphrase, config_path.join("tokens"));
+        let resources = PasswdProtectedFile::new(&passphrase, cache_path.join("resources"));
+        let checked = [
+            (tokens.path(), TokenStore::verify(&tokens)),
+            (resources.path(), CachedResources::verify(&resources)),
+        ];
+        for (path, integrity) in &checked {
+            println!("{path}: {integrity}");
+        }
+        let unreadable = checked.iter().any(|(_, integrity)| {
+            !matches!(
+                integrity,
+                cache::Integrity::Valid | cache::Integrity::Missing
+            )
+        });
+        if unreadable {
+            std::process::exit(1);
+        }
+        return;
+    }
+    let mut tstore: Box<dyn TokenBackend> = match backend {
+        #[cfg(feature = "keyring")]
+        token::Backend::Keyring => {
+            Box::new(ruggit::token::KeyringStore::new(ruggit::token::OsKeyring))
+        }
+        #[cfg(not(feature = "keyring"))]
+        token::Backend::Keyring => fail(
+            ErrorKind::Config,
+            anyhow::anyhow!("token_backend = \"keyring\" needs a build with the keyring feature"),
+        ),
+        token::Backend::File => {
+            let tokens = |passphrase: &str| {
+                TokenStore::new(PasswdProtectedFile::new(
+                    passphrase,
+                    config_path.join("tokens"),
+                ))
+            };
+            let store = match tokens(&passphrase) {
+                // the tokens may have been encrypted anew since the agent got it
+                Err(e) if from_agent.is_some() => {
+                    tracing::warn!("the agent's passphrase failed: {e:#}");
+                    from_agent = None;
+                    passphrase =
+                        cmdline::hidden_input_with_prompt("passphrase: ").unwrap_or_else(|e| {
+                            eprintln!("{e}");
+                            cmdline::abort();
+                        });
+                    tokens(&passphrase)
+                }
+                store => store,
+            };
+            let shared = config
+                .shared_tokens
+                .iter()
+                .map(|path| PasswdProtectedFile::new(&passphrase, path.clone()));
+            let store = store
+                .unwrap_or_else(|e| fail(ErrorKind::Auth, e))
+                .with_shared(shared);
+            // the tokens decrypted, so the passphrase is worth handing to an agent
+            if from_agent.is_none() {
+                if let Err(e) = agent::store(&agent_socket, &passphrase).await {
+                    tracing::debug!("passphrase not kept: {e}");
+                }
+            }
+            Box::new(store)
+        }
+    };
+    drop(passphrase_lock);
+
+    if args.command == cmdline::Command::List {
+        let cache = CachedResources::new(PasswdProtectedFile::new(
+            &passphrase,
+            cache_path.join("resources"),
+        ));
+        let mut described: Vec<String> = cache
+            .list()
+            .iter()
+            .filter_map(|identifier| cache.get(identifier))
+            .map(|resource| resource.meta.describe())
+            .collect();
+        described.sort();
+        for line in described {
+            println!("{line}");
+        }
+        return;
+    }
+
+    let users_file = PasswdProtectedFile::new(&passphrase, cache_path.join("users"));
+    options.users = Some(Arc::new(match args.no_cache {
+        true => UserCache::ephemeral(users_file, USER_TTL),
+        false => UserCache::new(users_file, USER_TTL),
+    }));
+
+    if let cmdline::Command::EnsureToken { from } = &args.command {
+        let domain = config::expand_alias(&args.source, &config.aliases);
+        let read = || from.read(std::io::stdin().lock());
+        let _lock = lock();
+        match token::ensure_token(&mut *tstore, &domain, read) {
+            Ok(true) => eprintln!("stored token for {domain}"),
+            Ok(false) => eprintln!("token for {domain} already stored, left as is"),
+            Err(e) => fail(ErrorKind::Auth, e),
+        }
+        return;
+    }
+
+    if args.command == cmdline::Command::CacheInfo {
+        let path = cache_path.join("resources");
+        let size = std::fs::metadata(&path).ok().map(|m| m.len());
+        let cache = CachedResources::new(PasswdProtectedFile::new(&passphrase, path.clone()));
+        println!(
+            "{}",
+            output::render_cache_info(&path.to_string_lossy(), size, &cache.stats())
+        );
+        return;
+    }
+
+    if let cmdline::Command::PruneCache { dry_run } = args.command {
+        let urimeta = UriMeta {
+            domain: config::expand_alias(&args.source, &config.aliases),
+            ..Default::default()
+        };
+        let mut cache = CachedResources::new(PasswdProtectedFile::new(
+            &passphrase,
+            cache_path.join("resources"),
+        ))
+        .with_format(config.cache_format.unwrap_or_default());
+        let (_, pruned) = authorized(&args, &options, &urimeta, &mut *tstore, async |gclient| {
+            Ok(gitlab_cache::prune(&mut cache, gclient, dry_run).await)
+        })
+        .await;
+        let pruned = pruned.unwrap_or_else(|e| fail(ErrorKind::Resolution, e));
+        let verb = match dry_run {
+            true => "would prune",
+            false => "pruned",
+        };
+        for identifier in pruned {
+            println!("{verb} {identifier}");
+        }
+        return;
+    }
+
+    if args.command == cmdline::Command::Whoami {
+        let urimeta = UriMeta {
+            domain: config::expand_alias(&args.source, &config.aliases),
+            ..Default::default()
+        };
+        let (_, user) = authorized(&args, &options, &urimeta, &mut *tstore, async |gclient| {
+            gclient.whoami().await
+        })
+        .await;
+        let user = user.unwrap_or_else(|e| fail(ErrorKind::Auth, e));
+        println!(
+            "logged in to {} as {} ({})",
+            urimeta.domain, user.username, user.name
+        );
+        return;
+    }
+
+    if let cmdline::Command::Browse { projects } = args.command {
+        let urimeta = UriMeta {
+            domain: config::expand_alias(&args.source, &config.aliases),
+            ..Default::default()
+        };
+        let (_, browsed) = authorized(&args, &options, &urimeta, &mut *tstore, async |gclient| {
+            gclient.browse(projects).await
+        })
+        .await;
+        let browsed = browsed.unwrap_or_else(|e| fail(ErrorKind::Resolution, e));
+        println!("{}", output::render_tree(&browsed));
+        return;
+    }
+
+    if args.command == cmdline::Command::CheckTokens {
+        let tokens = tstore
+            .domains()
+            .into_iter()
+            .filter_map(|domain| Some((domain.clone(), tstore.token(&domain)?)))
+            .collect();
+        let mut reports = token::check_tokens(tokens, options.settings.jobs, |domain, token| {
+            options.api(domain, token)
+        })
+        .await;
+        for report in &mut reports {
+            report.origin = tstore.origin(&report.domain);
+        }
+        println!("{}", output::render_token_reports(&reports));
+        return;
+    }
+
+    let source = config.source(&args.source);
+    let mut urimeta = uri_meta::UriMeta::new(&source).unwrap_or_else(|e| fail(ErrorKind::Usage, e));
+
+    let resource_file = PasswdProtectedFile::new(&passphrase, cache_path.join("resources"));
+    let mut cache = match args.no_cache {
+        true => CachedResources::ephemeral(resource_file),
+        false => CachedResources::new(resource_file),
+    }
+    .with_format(config.cache_format.unwrap_or_default())
+    .with_domain(&urimeta.domain);
+    let groups_file = PasswdProtectedFile::new(&passphrase, cache_path.join("groups"));
+    options.groups = Some(Arc::new(match args.no_cache {
+        true => GroupCache::ephemeral(groups_file, GROUP_TTL),
+        false => GroupCache::new(groups_file, GROUP_TTL),
+    }));
+
+    if args.command == cmdline::Command::Triggers {
+        let (_, triggers) = authorized(&args, &options, &urimeta, &mut *tstore, async |gclient| {
+            resolve(gclient, &urimeta).await?.triggers().await
+        })
+        .await;
+        let triggers = triggers.unwrap_or_else(|e| fail(ErrorKind::Resolution, e));
+        print_json(&triggers);
+        return;
+    }
+
+    if let cmdline::Command::ByTopic { topic } = &args.command {
+        let (_, found) = authorized(&args, &options, &urimeta, &mut *tstore, async |gclient| {
+            let group = resolve(gclient, &urimeta).await?;
+            if group.meta.full_path.is_none() {
+                anyhow::bail!("by-topic expects a group, {} is a project", args.source)
+            }
+            gclient.variables_by_topic(group.meta.id, topic).await
+        })
+        .await;
+        let found = found.unwrap_or_else(|e| fail(ErrorKind::Resolution, e));
+        for (meta, variables) in &found {
+            cache.insert(meta, variables);
+        }
+        print_json(&output::aggregate_by_project(
+            &found,
+            args.environment.as_deref(),
+            args.expand,
+        ));
+        return;
+    }
+
+    if args.command == cmdline::Command::Raw {
+        let (_, raw) = authorized(&args, &options, &urimeta, &mut *tstore, async |gclient| {
+            resolve(gclient, &urimeta).await?.raw().await
+        })
+        .await;
+        let raw = raw.unwrap_or_else(|e| fail(ErrorKind::Resolution, e));
+        print_json(&raw);
+        return;
+    }
+
+    if args.command == cmdline::Command::DeployTokens {
+        let (_, tokens) = authorized(&args, &options, &urimeta, &mut *tstore, async |gclient| {
+            resolve(gclient, &urimeta).await?.deploy_tokens().await
+        })
+        .await;
+        let tokens = tokens.unwrap_or_else(|e| fail(ErrorKind::Resolution, e));
+        print_json(&tokens);
+        return;
+    }
+
+    if args.command == cmdline::Command::Diff {
+        let (_, fetched) = authorized(&args, &options, &urimeta, &mut *tstore, async |gclient| {
+            let resource = resolve(gclient, &urimeta).await?;
+            let variables = resource.variables().await?;
+            Ok((resource, variables))
+        })
+        .await;
+        let (resource, live) = fetched.unwrap_or_else(|e| fail(ErrorKind::Resolution, e));
+        let identifier = gitlab_cache::identifier(&resource.meta);
+        let cached = cache
+            .get(&identifier)
+            .map(|r| r.variables)
+            .unwrap_or_default();
+        let changes = diff::diff(&cached, &live);
+        if !changes.is_empty() {
+            println!("{}", diff::render(&changes, args.show_values));
+        }
+        return;
+    }
+
+    if let cmdline::Command::RotateToken { id } = &args.command {
+        let (gclient, resource) =
+            authorized(&args, &options, &urimeta, &mut *tstore, async |gclient| {
+                resolve(gclient, &urimeta).await
+            })
+            .await;
+        let resource = resource.unwrap_or_else(|e| fail(ErrorKind::Resolution, e));
+        let id = match id {
+            Some(id) => *id,
+            None => select_access_token(&resource).await,
+        };
+        // only the token the domain is accessed with is replaced
+        let current = match gclient.current_token().await {
+            Ok(current) => Some(current.id),
+            Err(e) => {
+                tracing::debug!("unable to tell which token is in use: {e}");
+                None
+            }
+        };
+        let rotated = {
+            let _lock = lock();
+            token::rotate_and_store(&resource, &mut *tstore, &urimeta.domain, id, current).await
+        };
+        match rotated {
+            Ok((_, Ok(true))) => eprintln!("rotated token {id}, stored for {}", urimeta.domain),
+            Ok((token, Ok(false))) => {
+                eprintln!(
+                    "rotated token {id}, not the token of {} so it wasn't stored",
+                    urimeta.domain
+                );
+                println!("{token}");
+            }
+            Ok((token, Err(e))) => {
+                // the new value can't be fetched again, make sure it isn't lost
+                eprintln!("rotated token {id} but failed to store it: {e}");
+                println!("{token}");
+                std::process::exit(1);
+            }
+            Err(e) => fail(ErrorKind::Resolution, e),
+        }
+        return;
+    }
 
-    if !config_path.exists() {
-        if let Err(e) = std::fs::create_dir_all(&config_path) {
-            println!("unable to create config path: {e}");
+    if let cmdline::Command::Apply { file } = &args.command {
+        let mut desired = varfile::parse_file(file).unwrap_or_else(|e| fail(ErrorKind::Usage, e));
+        warn_sizes(&args, &desired.variables);
+        let (_, applied) = authorized(&args, &options, &urimeta, &mut *tstore, async |gclient| {
+            let resource = resolve(gclient, &urimeta).await?;
+            let remote = resource.variables().await?;
+            if desired.values_only {
+                varfile::inherit_attributes(&mut desired.variables, &remote);
+            }
+            let changes = apply::plan(&remote, &desired.variables, args.prune, desired.values_only);
+            if !changes.is_empty() {
+                println!("{}", diff::render(&changes, args.show_values));
+            }
+            apply::apply(&resource, &changes).await?;
+            Ok((resource.meta.clone(), resource.variables().await?))
+        })
+        .await;
+        let (meta, variables) = applied.unwrap_or_else(|e| fail(ErrorKind::Resolution, e));
+        cache.insert(&meta, &variables);
+        return;
+    }
+
+    if let Some(interval) = args.watch {
+        let (_, resource) = authorized(&args, &options, &urimeta, &mut *tstore, async |gclient| {
+            resolve(gclient, &urimeta).await
+        })
+        .await;
+        let resource = resource.unwrap_or_else(|e| fail(ErrorKind::Resolution, e));
+        // resolved once, each refresh only lists the variables
+        let formatter = formatter(&args);
+        WATCHING.store(true, Ordering::SeqCst);
+        watch::watch(
+            interval,
+            || gapi::with_deadline(args.deadline, resource.variables()),
+            |fetched| {
+                let rendered = fetched.and_then(|variables| {
+                    let variables = transform::overlay(&variables, &args.overrides);
+                    let variables = transform::apply_all(&variables, &args.transforms)?;
+                    formatter.format(&gitlab_cache::Resource {
+                        meta: resource.meta.clone(),
+                        variables,
+                        fetched_at: Some(oauth::now()),
+                        domain: Some(urimeta.domain.clone()),
+                    })
+                });
+                print!("{}{}", termion::clear::All, termion::cursor::Goto(1, 1));
+                match rendered {
+                    Ok(rendered) => println!("{rendered}"),
+                    Err(e) => eprintln!("{e:#}"),
+                }
+            },
+            tokio::time::sleep,
+            INTERRUPTED.notified(),
+        )
+        .await;
+        return;
+    }
+
+    let mut identifier = urimeta.identifier.clone();
+    // what was cached before this run, --since compares against it
+    let mut previous = None;
+    // a check gates pipelines, it shouldn't pass on stale variables
+    let check = matches!(args.command, cmdline::Command::Check { .. });
+    let snapshotting = matches!(args.command, cmdline::Command::Snapshot { .. });
+    // snapshots are only ever read from the cache, and a cached resource
+    // has no resolution to explain
+    let refetch = args.since.is_some()
+        || check
+        || snapshotting
+        || args.explain
+        || args.with_ancestors
+        || !cache.contains(&identifier);
+    if args.snapshot.is_none() && !args.offline && refetch {
+        let (gclient, fetched) =
+            authorized(&args, &options, &urimeta, &mut *tstore, async |gclient| {
+                let resource = resolve(gclient, &urimeta).await?;
+                let variables = resource.variables().await?;
+                Ok((resource, variables))
+            })
+            .await;
+        let (resource, variables) = fetched.unwrap_or_else(|e| fail(ErrorKind::Resolution, e));
+        previous = cache.get(&gitlab_cache::identifier(&resource.meta));
+        identifier = cache.insert_resolved(&mut urimeta, &resource.meta, &variables);
+        if args.with_ancestors {
+            let cached = gapi::with_deadline(
+                args.deadline,
+                gitlab_cache::cache_ancestors(&mut cache, &gclient, &resource.meta),
+            )
+            .await
+            .unwrap_or_else(|e| fail(ErrorKind::Resolution, e));
+            tracing::info!("cached the variables of {}", cached.join(", "));
+        }
+    }
+    if let cmdline::Command::Snapshot { tag } = &args.command {
+        if let Err(e) = cache.snapshot(&identifier, tag) {
+            fail(ErrorKind::Usage, e);
+        }
+        eprintln!("kept the variables of {identifier} as {tag}");
+        return;
+    }
+    let found = match &args.snapshot {
+        Some(tag) => cache.get_snapshot(&identifier, tag).ok_or_else(|| {
+            (
+                ErrorKind::NotFound,
+                anyhow::anyhow!("{identifier} has no snapshot {tag}"),
+            )
+        }),
+        None if args.offline => cache.get(&identifier).ok_or_else(|| {
+            (
+                ErrorKind::NotFound,
+                anyhow::anyhow!("{identifier} isn't cached, run without --offline to fetch it"),
+            )
+        }),
+        None => cache.get(&identifier).ok_or_else(|| {
+            (
+                ErrorKind::Internal,
+                anyhow::anyhow!("{identifier} missing from the cache"),
+            )
+        }),
+    };
+    let mut resource = found.unwrap_or_else(|(kind, e)| fail(kind, e));
+    // inherited variables go first, the resource's own override them in
+    // `output::effective_variables`, which get-var and extract look keys up in
+    if args.with_ancestors {
+        let mut variables = cache.inherited(&identifier);
+        variables.append(&mut resource.variables);
+        resource.variables = variables;
+    }
+    if let Some(note) = output::empty_note(&identifier, &resource.variables) {
+        eprintln!("{note}");
+    }
+    warn_sizes(&args, &resource.variables);
+    if let Some(since) = args.since {
+        let snapshot = previous.as_ref().and_then(|p| {
+            Some(diff::Snapshot {
+                fetched_at: p.fetched_at?,
+                variables: &p.variables,
+            })
+        });
+        resource.variables = diff::changed_since(&resource.variables, since, snapshot.as_ref());
+    }
+    if !args.overrides.is_empty() {
+        resource.variables = transform::overlay(&resource.variables, &args.overrides);
+    }
+    if !args.transforms.is_empty() {
+        resource.variables = transform::apply_all(&resource.variables, &args.transforms)
+            .unwrap_or_else(|e| fail(ErrorKind::Usage, e));
+    }
+    if let cmdline::Command::Check { required } = &args.command {
+        let variables = match args.environment.as_deref() {
+            Some(environment) => {
+                output::effective_variables(&resource.variables, Some(environment))
+                    .into_values()
+                    .collect()
+            }
+            None => resource.variables.clone(),
+        };
+        let missing = output::missing_keys(&variables, required);
+        if !missing.is_empty() {
+            let e = anyhow::anyhow!("missing required variables: {}", missing.join(", "));
+            fail(ErrorKind::NotFound, e);
+        }
+        return;
+    }
+    if let cmdline::Command::GetVar { key } = &args.command {
+        let environment = args.environment.as_deref();
+        match output::get_var(&resource.variables, key, environment, args.redact) {
+            Ok(value) => println!("{value}"),
+            Err(e) => fail(ErrorKind::NotFound, e),
+        }
+        return;
+    }
+    if let cmdline::Command::Extract { key, out } = &args.command {
+        let environment = args.environment.as_deref();
+        let value = output::get_var(&resource.variables, key, environment, false)
+            .unwrap_or_else(|e| fail(ErrorKind::NotFound, e));
+        if let Err(e) = output::write_private(out, &value) {
+            fail(ErrorKind::Internal, e);
         }
+        return;
     }
+    let rendered = formatter(&args)
+        .format(&resource)
+        .unwrap_or_else(|e| fail(ErrorKind::Usage, e));
+    match &args.out {
+        // only key names are free of secrets
+        Some(out) => {
+            let mode = match args.format {
+                output::OutputFormat::Keys => None,
+                _ => Some(0o600),
+            };
+            if let Err(e) = paths::write_atomic(out, format!("{rendered}\n").as_bytes(), mode) {
+                let e = anyhow::Error::new(e).context(format!("failed to write {}", out.display()));
+                fail(ErrorKind::Internal, e);
+            }
+        }
+        None => println!("{rendered}"),
+    }
+    if let Some(path) = &args.path {
+        let command = output::vault_kv_command(path, args.out.as_deref())
+            .unwrap_or_else(|e| fail(ErrorKind::Usage, e));
+        eprintln!("store it with: {command}");
+    }
+}
+
+fn formatter(args: &cmdline::Args) -> Box<dyn output::OutputFormatter> {
+    args.format.formatter(output::FormatOptions {
+        environment: args.environment.clone(),
+        comments: args.comments,
+        name: args.name.clone(),
+        expand: args.expand,
+        namespace: args.namespace.clone(),
+    })
+}
+
+async fn select_access_token(resource: &GitlabResource) -> u32 {
+    let tokens = resource
+        .access_tokens()
+        .await
+        .unwrap_or_else(|e| fail(ErrorKind::Resolution, e));
+    let tokens: Vec<_> = tokens.into_iter().filter(|t| t.active).collect();
+    let options: Vec<String> = tokens
+        .iter()
+        .map(|t| format!("{} ({}) [{}]", t.name, t.id, t.scopes.join(",")))
+        .collect();
+    let choices = options.clone();
+    let _lock = lock();
+    let selected =
+        cmdline::prompt_async(move || cmdline::select_option("token to rotate: ", &choices))
+            .await
+            .unwrap_or_else(|e| fail(ErrorKind::Usage, e));
+    let index = options
+        .iter()
+        .position(|o| *o == selected)
+        .unwrap_or_default();
+    tokens[index].id
+}
 
-    let passphrase = cmdline::hidden_input_with_prompt("passphrase: ").unwrap();
-    let config_file = PasswdProtectedFile::new(&passphrase, config_path.join("tokens"));
+// the library leaves choosing among partial matches to whoever can ask
+async fn select_project(ambiguous: gapi::Ambiguous) -> anyhow::Result<gapi::GitlabResourceMeta> {
+    let options: Vec<String> = ambiguous
+        .candidates
+        .iter()
+        .filter_map(|p| p.path_with_namespace.clone())
+        .collect();
+    let prompt = format!("no exact match for {}, select project: ", ambiguous.path);
+    let _lock = lock();
+    let selected = cmdline::prompt_async(move || cmdline::select_option(&prompt, &options)).await?;
+    let project = ambiguous
+        .candidates
+        .into_iter()
+        .find(|p| p.path_with_namespace.as_ref() == Some(&selected))
+        .ok_or_else(|| anyhow::anyhow!("selected project vanished"))?;
+    tracing::info!(target: gapi::EXPLAIN, step = "selected", id = project.id);
+    Ok(project)
+}
+
+/// Keeps concurrent runs from interleaving prompts and token writes, held
+/// only around those and never across a whole run.
+fn lock() -> ProcessLock {
+    let path = LOCK_PATH.get().expect("lock path set before prompting");
+    ProcessLock::acquire(path, LOCK_TIMEOUT).unwrap_or_else(|e| fail(ErrorKind::Config, e))
+}
 
-    let mut tstore = TokenStore::new(config_file);
+fn fail(kind: ErrorKind, e: anyhow::Error) -> ! {
+    let e = match e.downcast_ref::<gapi::ApiError>() {
+        Some(gapi::ApiError::Maintenance(_) | gapi::ApiError::Unavailable(_)) => {
+            e.context("gitlab is unavailable, --offline shows the cached variables")
+        }
+        _ => e,
+    };
+    let format = ERROR_FORMAT.get().copied().unwrap_or_default();
+    eprintln!("{}", output::render_error(&e, kind, format));
+    std::process::exit(1);
+}
 
-    let source = cmdline::parse_source(&env::args().nth(1).unwrap());
-    let urimeta = uri_meta::UriMeta::new(&source).unwrap();
+fn warn_sizes(args: &cmdline::Args, variables: &[gapi::GitlabVariable]) {
+    let threshold = args.size_warning.unwrap_or(output::DEFAULT_SIZE_WARNING);
+    for warning in output::size_warnings(variables, threshold) {
+        eprintln!("{warning}");
+    }
+}
 
-    let resource_file = PasswdProtectedFile::new(&passphrase, config_path.join("resources"));
-    let mut cache = CachedResources::new(resource_file);
-    let identifier = &urimeta.identifier;
-    if !cache.list().contains(identifier) {
-        let token = 'a: {
-            if let Some(token) = tstore.get(&urimeta.domain) {
-                break 'a token;
+fn print_json(value: &impl serde::Serialize) {
+    match serde_json::to_string(value) {
+        Ok(rendered) => println!("{rendered}"),
+        Err(e) => fail(ErrorKind::Internal, e.into()),
+    }
+}
+
+/// An api client for the domain of `urimeta` with a personal token,
+/// prompting for one if none of the token sources know the domain.
+async fn client(
+    args: &cmdline::Args,
+    options: &ApiOptions,
+    urimeta: &UriMeta,
+    tstore: &mut dyn TokenBackend,
+) -> GApi {
+    let token = 'a: {
+        let found = {
+            let mut sources = TokenChain::new();
+            if let Some(inline) = &args.token {
+                let _lock = lock();
+                let inline = token::inline_token(
+                    inline,
+                    &urimeta.domain,
+                    args.save,
+                    tstore,
+                    &mut std::io::stderr(),
+                )
+                .unwrap_or_else(|e| fail(ErrorKind::Config, e));
+                sources.push(inline);
             }
-            let org = cmdline::input_with_prompt("domain: ").unwrap();
-            let token = cmdline::hidden_input_with_prompt("token: ").unwrap();
-            tstore.add_token(&org, &token).unwrap();
-            token
+            sources.push(&*tstore);
+            sources.token(&urimeta.domain)
+        };
+        if let Some(token) = found {
+            break 'a token;
+        }
+        let _lock = lock();
+        let prompted = cmdline::prompt_async(|| {
+            let org = cmdline::input_with_prompt("domain: ")?;
+            let token = cmdline::hidden_input_with_prompt("token: ")?;
+            Ok((org, token))
+        })
+        .await;
+        let Ok((org, token)) = prompted else {
+            cmdline::abort();
         };
+        if let Err(e) = tstore.add_token(&org, &token) {
+            fail(ErrorKind::Config, e);
+        }
+        token
+    };
+
+    options.api(&urimeta.domain, &token)
+}
+
+/// Runs `request` with the client of `urimeta`, bounded by `--deadline`.
+/// Should gitlab refuse the token, an oauth token is refreshed while
+/// replacing a stored one is offered, and the request runs once more with
+/// the new token. Hands back the client the request last ran with.
+async fn authorized<T>(
+    args: &cmdline::Args,
+    options: &ApiOptions,
+    urimeta: &UriMeta,
+    tstore: &mut dyn TokenBackend,
+    mut request: impl AsyncFnMut(&GApi) -> anyhow::Result<T>,
+) -> (GApi, anyhow::Result<T>) {
+    if args.offline {
+        fail(
+            ErrorKind::Usage,
+            anyhow::anyhow!("--offline only reads cached variables, this needs gitlab"),
+        );
+    }
+    let domain = &urimeta.domain;
+    // a token given inline is used over any stored one, oauth included
+    let oauth = match &args.token {
+        Some(_) => None,
+        None => tstore.oauth(domain),
+    };
+    if let Some(mut oauth) = oauth {
+        let refresher = oauth::GitlabRefresher::new(options.scheme(domain), domain)
+            .with_client_settings(&options.settings)
+            .unwrap_or_else(|e| fail(ErrorKind::Config, e));
+        let stored = oauth.clone();
+        let result =
+            oauth::call_with_refresh(&mut oauth, &refresher, oauth::now(), async |access| {
+                let gclient = options.api(domain, &access).with_oauth();
+                gapi::with_deadline(args.deadline, request(&gclient)).await
+            })
+            .await;
+        if oauth != stored {
+            let _lock = lock();
+            if let Err(e) = tstore.set_oauth(domain, oauth.clone()) {
+                tracing::warn!("failed to store refreshed token: {e}");
+            }
+        }
+        return (
+            options.api(domain, &oauth.access_token).with_oauth(),
+            result,
+        );
+    }
+    let gclient = client(args, options, urimeta, tstore).await;
+    let result = gapi::with_deadline(args.deadline, request(&gclient)).await;
+    let refused = result
+        .as_ref()
+        .err()
+        .and_then(|e| e.downcast_ref::<gapi::ApiError>())
+        .is_some_and(|e| matches!(e, gapi::ApiError::Unauthorized));
+    // inline tokens are never stored, there's nothing to replace
+    if !refused || args.token.is_some() {
+        return (gclient, result);
+    }
+    let status = gclient
+        .token_status()
+        .await
+        .unwrap_or(gapi::TokenStatus::Invalid);
+    let prompting = lock();
+    let domain = urimeta.domain.clone();
+    let replaced = cmdline::prompt_async(move || {
+        cmdline::replace_token(
+            &domain,
+            status,
+            cmdline::input_with_prompt,
+            cmdline::hidden_input_with_prompt,
+        )
+    })
+    .await;
+    match replaced {
+        Ok(Some(replacement)) => {
+            if let Err(e) = tstore.add_token(&urimeta.domain, &replacement) {
+                fail(ErrorKind::Config, e);
+            }
+            drop(prompting);
+            let gclient = options.api(&urimeta.domain, &replacement);
+            let result = gapi::with_deadline(args.deadline, request(&gclient)).await;
+            (gclient, result)
+        }
+        Ok(None) => (gclient, result),
+        Err(_) => cmdline::abort(),
+    }
+}
 
-        let gclient = gapi::GApi::new(&urimeta.domain, &token);
-        let resource = gclient.resource_from_uri(&urimeta).await.unwrap();
-        let variables = resource.variables().await.unwrap();
-        cache.insert(&resource.meta, &variables);
+/// How api clients are built, from the config and flags.
+struct ApiOptions {
+    settings: ClientSettings,
+    api_path: String,
+    // talk plain http
+    insecure: bool,
+    // shared by every client, only available once the passphrase is known
+    groups: Option<Arc<dyn GroupStore>>,
+    users: Option<Arc<dyn UserStore>>,
+}
+
+impl ApiOptions {
+    fn scheme(&self, domain: &str) -> &'static str {
+        match self.insecure {
+            true => "http",
+            false => gapi::default_scheme(domain),
+        }
     }
-    let resource = cache.get(identifier).unwrap();
-    println!("{}", serde_json::to_string(&resource).unwrap());
+
+    fn api(&self, domain: &str, token: &str) -> GApi {
+        let api = GApi::new(domain, token)
+            .with_scheme(self.scheme(domain))
+            .with_api_path(&self.api_path)
+            .with_client_settings(self.settings.clone())
+            .unwrap_or_else(|e| fail(ErrorKind::Config, e))
+            .with_page_memo();
+        let api = match &self.users {
+            Some(users) => api.with_user_store(users.clone()),
+            None => api,
+        };
+        match &self.groups {
+            Some(groups) => api.with_group_store(groups.clone()),
+            None => api,
+        }
+    }
+}
+
+async fn resolve(gclient: &GApi, urimeta: &UriMeta) -> anyhow::Result<GitlabResource> {
+    let resource = match urimeta.id {
+        Some(_) => gclient.resource_from_id(urimeta).await?,
+        None => match gclient.resource_from_uri(urimeta).await {
+            Ok(resource) => resource,
+            Err(e) => match e.downcast::<gapi::Ambiguous>() {
+                Ok(ambiguous) => gclient.project_resource(select_project(ambiguous).await?),
+                Err(e) => return Err(e),
+            },
+        },
+    };
+    tracing::info!(
+        "resolved {} to {} [{}]",
+        urimeta.url,
+        resource.url(),
+        resource.meta.describe()
+    );
+    Ok(resource)
 }
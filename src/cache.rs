@@ -1,6 +1,83 @@
 use crate::crypto::{CryptoError, EncryptedRW};
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+
+/// How a cache is encoded on disk. Reads detect either, the format only
+/// decides what is written.
+#[derive(Deserialize, Clone, Copy, Default, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheFormat {
+    #[default]
+    Json,
+    // compact, for caches of many resources
+    #[cfg(feature = "msgpack")]
+    Msgpack,
+}
+
+impl std::fmt::Display for CacheFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Json => write!(f, "json"),
+            #[cfg(feature = "msgpack")]
+            Self::Msgpack => write!(f, "msgpack"),
+        }
+    }
+}
+
+// json caches start with `{` or `[`, while msgpack maps and arrays have
+// their high bit set
+fn decode<T: DeserializeOwned>(content: &[u8]) -> anyhow::Result<T> {
+    match content.first() {
+        #[cfg(feature = "msgpack")]
+        Some(b) if *b >= 0x80 => Ok(rmp_serde::from_slice(content)?),
+        #[cfg(not(feature = "msgpack"))]
+        Some(b) if *b >= 0x80 => {
+            anyhow::bail!("cache written as msgpack, a build without it can't read it")
+        }
+        _ => Ok(serde_json::from_slice(content)?),
+    }
+}
+
+/// The state of an encrypted file, as found by [`verify`].
+#[derive(Debug, PartialEq)]
+pub enum Integrity {
+    Missing,
+    Valid,
+    WrongPassphrase,
+    // decrypted, or failed to for another reason than the passphrase
+    Corrupt(String),
+}
+
+impl std::fmt::Display for Integrity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing => write!(f, "missing"),
+            Self::Valid => write!(f, "valid"),
+            Self::WrongPassphrase => write!(f, "not readable, wrong passphrase?"),
+            Self::Corrupt(reason) => write!(f, "corrupt: {reason}"),
+        }
+    }
+}
+
+/// Decrypts `file` and parses it as `T` without changing anything, unlike
+/// [`Cache::new`] which starts over on unreadable content.
+pub fn verify<T: DeserializeOwned>(file: &impl EncryptedRW) -> Integrity {
+    let content = match file.read() {
+        Ok(content) => content,
+        Err(CryptoError::IO(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Integrity::Missing
+        }
+        Err(CryptoError::DecryptError(
+            age::DecryptError::DecryptionFailed | age::DecryptError::NoMatchingKeys,
+        )) => return Integrity::WrongPassphrase,
+        Err(CryptoError::DecryptError(e)) => return Integrity::Corrupt(e.to_string()),
+        Err(e) => return Integrity::Corrupt(e.to_string()),
+    };
+    match decode::<T>(&content) {
+        Ok(_) => Integrity::Valid,
+        Err(e) => Integrity::Corrupt(e.to_string()),
+    }
+}
 
 pub trait Cachable {
     fn update_cache(&self) -> anyhow::Result<()>;
@@ -9,40 +86,77 @@ pub trait Cachable {
 pub struct Cache<T: Serialize + DeserializeOwned + Default, U: EncryptedRW> {
     pub in_mem: T,
     persistent: U,
+    // ephemeral caches never touch `persistent`
+    ephemeral: bool,
+    format: CacheFormat,
 }
 
 impl<T: Serialize + DeserializeOwned + Default, U: EncryptedRW> Cache<T, U> {
     pub fn new(on_disk: U) -> Self {
+        let _span = tracing::debug_span!("cache_read", path = %on_disk.path()).entered();
         let content = match on_disk.read() {
             Ok(content) => content,
             Err(e) => match e {
                 CryptoError::IO(err) => {
-                    println!("({}) {}: clearing cache", on_disk.path(), err);
+                    tracing::info!("{}: clearing cache", err);
                     return Self {
                         in_mem: T::default(),
                         persistent: on_disk,
+                        ephemeral: false,
+                        format: CacheFormat::default(),
                     };
                 }
                 err => {
-                    println!("unrecoverable error: {}", err);
+                    tracing::error!("unrecoverable error: {}", err);
                     std::process::exit(1);
                 }
             },
         };
 
-        let in_mem = if let Ok(in_mem) = serde_json::from_slice(&content) {
-            in_mem
-        } else {
-            T::default()
+        let (in_mem, ephemeral) = match decode(&content) {
+            Ok(in_mem) => (in_mem, false),
+            Err(e) => {
+                tracing::warn!("ignoring unreadable cache {}: {e}", on_disk.path());
+                // a cache another build can read is left for it
+                let foreign = !cfg!(feature = "msgpack") && content.first() >= Some(&0x80);
+                (T::default(), foreign)
+            }
         };
         Self {
             in_mem,
             persistent: on_disk,
+            ephemeral,
+            format: CacheFormat::default(),
+        }
+    }
+
+    /// A cache living only in memory, neither reading nor writing `on_disk`.
+    pub fn ephemeral(on_disk: U) -> Self {
+        Self {
+            in_mem: T::default(),
+            persistent: on_disk,
+            ephemeral: true,
+            format: CacheFormat::default(),
         }
     }
 
+    /// Writes in `format` from now on, whatever the file was read as.
+    pub fn with_format(mut self, format: CacheFormat) -> Self {
+        self.format = format;
+        self
+    }
+
     pub fn update(&self) -> anyhow::Result<()> {
-        let content = serde_json::to_vec(&self.in_mem)?;
+        if self.ephemeral {
+            return Ok(());
+        }
+        let _span = tracing::debug_span!("cache_write", path = %self.persistent.path()).entered();
+        let content = match self.format {
+            CacheFormat::Json => serde_json::to_vec(&self.in_mem)?,
+            #[cfg(feature = "msgpack")]
+            // as maps, fields are found by name like in json
+            CacheFormat::Msgpack => rmp_serde::to_vec_named(&self.in_mem)?,
+        };
         Ok(self.persistent.write(&content)?)
     }
 }
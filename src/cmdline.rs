@@ -1,11 +1,866 @@
-use crate::uri_meta::Source;
+use crate::gapi::TokenStatus;
+use crate::output::{ColorChoice, ErrorFormat, OutputFormat};
+use crate::transform::Transform;
+use crate::uri_meta::{Source, PATTERN_ID};
 use anyhow::Context;
 use regex::Regex;
 use std::env;
 use std::io::prelude::Write;
-use termion::input::TermRead;
+use std::path::PathBuf;
+use std::time::Duration;
+use termion::raw::IntoRawMode;
+
+#[derive(PartialEq, Default, Debug)]
+pub enum Command {
+    // print every variable of the resource
+    #[default]
+    Show,
+    // print the raw value of a single variable
+    GetVar {
+        key: String,
+    },
+    // list pipeline trigger tokens
+    Triggers,
+    // list deploy tokens, without their values
+    DeployTokens,
+    // print the resource as gitlab returns it
+    Raw,
+    // fail unless every required variable is defined
+    Check {
+        required: Vec<String>,
+    },
+    // print the settings in effect and where they come from
+    PrintConfig,
+    // keep the current variables under a tag
+    Snapshot {
+        tag: String,
+    },
+    // compare cached variables with the live ones
+    Diff,
+    // sync variables from a file to gitlab
+    Apply {
+        file: PathBuf,
+    },
+    // write the value of a single variable to a file
+    Extract {
+        key: String,
+        out: PathBuf,
+    },
+    // list the cached resources
+    List,
+    // validate every stored token
+    CheckTokens,
+    // print where the cache lives and how much it holds
+    CacheInfo,
+    // drop cached resources that no longer exist on a domain
+    PruneCache {
+        dry_run: bool,
+    },
+    // list the groups of a domain, and their projects
+    Browse {
+        projects: bool,
+    },
+    // rotate a project access token, storing the new value
+    RotateToken {
+        id: Option<u32>,
+    },
+    // store a token for a domain unless one is stored already
+    EnsureToken {
+        from: TokenInput,
+    },
+    // manage the aliases saved in the config
+    Alias {
+        action: AliasAction,
+    },
+    // check that the encrypted files decrypt and parse
+    Verify,
+    // print the user the token of a domain belongs to
+    Whoami,
+    // hold the passphrase in memory for later runs
+    Agent {
+        ttl: Duration,
+    },
+    // print the variables of every project of a group with a topic
+    ByTopic {
+        topic: String,
+    },
+}
+
+#[derive(PartialEq, Debug)]
+pub enum AliasAction {
+    Add { name: String, target: String },
+    List,
+    Remove { name: String },
+}
+
+/// Where a token is read from without it showing up in the arguments.
+#[derive(PartialEq, Debug)]
+pub enum TokenInput {
+    File(PathBuf),
+    Stdin,
+    // the RUGGIT_TOKEN environment variable
+    Env,
+}
+
+impl TokenInput {
+    /// The token with surrounding whitespace removed, `stdin` is only read
+    /// for [`TokenInput::Stdin`].
+    pub fn read(&self, stdin: impl std::io::BufRead) -> anyhow::Result<String> {
+        let token = match self {
+            Self::File(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read token from {}", path.display()))?,
+            Self::Stdin => {
+                let mut line = String::new();
+                std::io::BufRead::read_line(&mut stdin.take(4096), &mut line)?;
+                line
+            }
+            Self::Env => env::var("RUGGIT_TOKEN").context("RUGGIT_TOKEN is not set")?,
+        };
+        let token = token.trim();
+        if token.is_empty() {
+            anyhow::bail!("no token given")
+        }
+        Ok(token.to_string())
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct Args {
+    pub command: Command,
+    pub source: String,
+    pub format: OutputFormat,
+    pub error_format: ErrorFormat,
+    pub color: ColorChoice,
+    pub deadline: Option<Duration>,
+    // unix seconds, only variables changed after it are shown
+    pub since: Option<u64>,
+    pub per_page: Option<u32>,
+    // values larger than this many bytes are warned about
+    pub size_warning: Option<usize>,
+    // applied in order to every value before output
+    pub transforms: Vec<Transform>,
+    pub verbose: bool,
+    pub token: Option<String>,
+    // persist the inline token
+    pub save: bool,
+    pub token_file: Option<PathBuf>,
+    pub token_stdin: bool,
+    // neither read from nor write to the resource cache
+    pub no_cache: bool,
+    // environment to select scoped variables for
+    pub environment: Option<String>,
+    // of the k8s-secret output
+    pub name: Option<String>,
+    pub namespace: Option<String>,
+    // vault path the vault-kv output is for, printed in a `vault kv put`
+    pub path: Option<String>,
+    pub redact: bool,
+    pub show_values: bool,
+    // include variable descriptions as comments
+    pub comments: bool,
+    // expand references to other variables in the effective values
+    pub expand: bool,
+    pub file: Option<PathBuf>,
+    // written to instead of stdout
+    pub out: Option<PathBuf>,
+    pub prune: bool,
+    // browse projects too, not only groups
+    pub projects: bool,
+    // report what would change without changing it
+    pub dry_run: bool,
+    // keys the check command expects
+    pub require: Vec<String>,
+    // key and value overlaid on the fetched variables, in order
+    pub overrides: Vec<(String, String)>,
+    pub tag: Option<String>,
+    // projects of a group are selected by
+    pub topic: Option<String>,
+    // show the variables kept under this tag instead of the current ones
+    pub snapshot: Option<String>,
+    // how long the agent holds the passphrase
+    pub ttl: Option<Duration>,
+    // refetch and print the variables this often, until interrupted
+    pub watch: Option<Duration>,
+    // talk plain http to the gitlab instance
+    pub insecure: bool,
+    // only read the cache, never contact gitlab
+    pub offline: bool,
+    // resources fetched for at once by commands covering many
+    pub jobs: Option<usize>,
+    // print how the resource was resolved to stderr
+    pub explain: bool,
+    // fetch and cache the variables of the groups above the resource too,
+    // and include them
+    pub with_ancestors: bool,
+}
+
+/// A flag as `--help` lists it, the parser refuses any flag not listed.
+pub struct Flag {
+    pub names: &'static [&'static str],
+    // the flag and its value as shown, `--env <environment>`
+    pub usage: &'static str,
+    pub help: &'static str,
+}
+
+const fn flag(names: &'static [&'static str], usage: &'static str, help: &'static str) -> Flag {
+    Flag { names, usage, help }
+}
+
+/// Every flag [`parse_args`] takes, in the order `--help` lists them.
+pub const FLAGS: &[Flag] = &[
+    flag(
+        &["--format"],
+        "--format <json|scoped|dotenv|keys|k8s-secret|csv|vault-kv>",
+        "how variables are printed, json by default",
+    ),
+    flag(&["--keys-only"], "--keys-only", "same as --format keys"),
+    flag(&["--env"], "--env <environment>", "select scoped variables"),
+    flag(
+        &["--out"],
+        "--out <path>",
+        "write to a file instead of stdout",
+    ),
+    flag(
+        &["--name"],
+        "--name <name>",
+        "name of the --format k8s-secret",
+    ),
+    flag(
+        &["--namespace"],
+        "--namespace <namespace>",
+        "namespace of the --format k8s-secret",
+    ),
+    flag(
+        &["--path"],
+        "--path <vault path>",
+        "print how to store --format vault-kv there",
+    ),
+    flag(
+        &["--expand"],
+        "--expand",
+        "expand $REFS in the values of an environment",
+    ),
+    flag(
+        &["--comments"],
+        "--comments",
+        "include variable descriptions as comments",
+    ),
+    flag(
+        &["--transform"],
+        "--transform <none|trim|base64-decode>",
+        "rewrite every value, repeatable",
+    ),
+    flag(
+        &["--since"],
+        "--since <timestamp|duration>",
+        "only variables changed since then",
+    ),
+    flag(
+        &["--set"],
+        "--set <key>=<value>",
+        "override a variable locally, repeatable",
+    ),
+    flag(
+        &["--require"],
+        "--require <keys>",
+        "comma separated keys check expects",
+    ),
+    flag(&["--redact"], "--redact", "hide masked values from get-var"),
+    flag(
+        &["--show-values"],
+        "--show-values",
+        "show values in diffs and apply plans",
+    ),
+    flag(&["--file"], "--file <path>", "variables apply syncs from"),
+    flag(
+        &["--prune"],
+        "--prune",
+        "let apply delete variables not in the file",
+    ),
+    flag(
+        &["--dry-run"],
+        "--dry-run",
+        "list what prune-cache would remove",
+    ),
+    flag(
+        &["--tag"],
+        "--tag <tag>",
+        "tag snapshot keeps the variables under",
+    ),
+    flag(
+        &["--snapshot"],
+        "--snapshot <tag>",
+        "show the variables kept under a tag",
+    ),
+    flag(
+        &["--topic"],
+        "--topic <topic>",
+        "topic by-topic selects projects by",
+    ),
+    flag(
+        &["--projects"],
+        "--projects",
+        "browse the projects of groups too",
+    ),
+    flag(
+        &["--triggers"],
+        "--triggers",
+        "list pipeline trigger tokens",
+    ),
+    flag(
+        &["--deploy-tokens"],
+        "--deploy-tokens",
+        "list deploy tokens, without their values",
+    ),
+    flag(
+        &["--raw"],
+        "--raw",
+        "print the resource as gitlab returns it",
+    ),
+    flag(
+        &["--with-ancestors"],
+        "--with-ancestors",
+        "include the variables of the parent groups",
+    ),
+    flag(
+        &["--token"],
+        "--token <token>",
+        "use a token instead of the stored one",
+    ),
+    flag(&["--save"], "--save", "store the --token given"),
+    flag(
+        &["--token-file"],
+        "--token-file <path>",
+        "ensure-token reads it, else RUGGIT_TOKEN",
+    ),
+    flag(
+        &["--token-stdin"],
+        "--token-stdin",
+        "let ensure-token read the token from stdin",
+    ),
+    flag(
+        &["--no-cache"],
+        "--no-cache",
+        "skip the cache entirely, unlike --offline",
+    ),
+    flag(&["--offline"], "--offline", "only read the cache"),
+    flag(
+        &["--watch"],
+        "--watch <secs>",
+        "refetch and print until interrupted",
+    ),
+    flag(
+        &["--deadline"],
+        "--deadline <secs>",
+        "give up on gitlab after this long",
+    ),
+    flag(
+        &["--jobs"],
+        "--jobs <n>",
+        "resources fetched for at once, 4 by default",
+    ),
+    flag(
+        &["--per-page"],
+        "--per-page <n>",
+        "items gitlab returns per page",
+    ),
+    flag(
+        &["--size-warning"],
+        "--size-warning <bytes>",
+        "warn about values larger than this",
+    ),
+    flag(
+        &["--insecure-http"],
+        "--insecure-http",
+        "talk plain http to the gitlab instance",
+    ),
+    flag(
+        &["--ttl"],
+        "--ttl <duration>",
+        "how long the agent holds the passphrase",
+    ),
+    flag(
+        &["--explain"],
+        "--explain",
+        "print how the source was resolved",
+    ),
+    flag(
+        &["--print-config"],
+        "--print-config",
+        "print the settings in effect",
+    ),
+    flag(
+        &["--color"],
+        "--color <auto|always|never>",
+        "color the output, auto by default",
+    ),
+    flag(
+        &["--error-format"],
+        "--error-format <text|json>",
+        "how errors are printed",
+    ),
+    flag(&["--verbose", "-v"], "-v, --verbose", ""),
+    flag(&["--help", "-h"], "-h, --help", ""),
+    flag(&["--version", "-V"], "-V, --version", ""),
+];
+
+const COMMANDS: &[(&str, &str)] = &[
+    ("get <source>", "print every variable, the default"),
+    (
+        "get-var, cat <source> <key>",
+        "print the raw value of a variable",
+    ),
+    (
+        "extract <source> <key> --out",
+        "write the value of a variable to a file",
+    ),
+    (
+        "check <source> --require <keys>",
+        "fail unless every key is defined",
+    ),
+    (
+        "diff <source>",
+        "compare cached variables with the live ones",
+    ),
+    (
+        "apply <source> --file <path>",
+        "sync variables from a file to gitlab",
+    ),
+    (
+        "snapshot <source> --tag <tag>",
+        "keep the current variables under a tag",
+    ),
+    ("browse <domain>", "list the groups of a domain"),
+    (
+        "by-topic <group> --topic <t>",
+        "print the variables of a group's projects",
+    ),
+    (
+        "rotate-token <source> [id]",
+        "rotate a project access token",
+    ),
+    (
+        "ensure-token <domain>",
+        "store a token unless one is stored",
+    ),
+    ("whoami <domain>", "print the user of a domain's token"),
+    (
+        "tokens check",
+        "validate every stored token and tell its file",
+    ),
+    ("list", "list the cached resources"),
+    ("cache info", "print where the cache lives and its size"),
+    (
+        "prune-cache <domain>",
+        "drop cached resources that are gone",
+    ),
+    ("alias add|list|remove", "manage aliases in the config"),
+    ("verify", "check that the encrypted files decrypt"),
+    (
+        "agent [--ttl <duration>]",
+        "hold the passphrase for later runs",
+    ),
+];
+
+/// Printed by `--help`, listing [`FLAGS`] so that none goes undocumented.
+pub fn usage() -> String {
+    // help starts on a column of its own, below usages too long for it
+    let line = |usage: &str, help: &str| match (help.is_empty(), usage.len() < 32) {
+        (true, _) => format!("  {usage}"),
+        (false, true) => format!("  {usage:<31} {help}"),
+        (false, false) => format!("  {usage}\n  {:<31} {help}", ""),
+    };
+    let commands: Vec<String> = COMMANDS.iter().map(|(u, h)| line(u, h)).collect();
+    let flags: Vec<String> = FLAGS.iter().map(|f| line(f.usage, f.help)).collect();
+    format!(
+        "\
+usage: rvar [flags] [command] <source>
+
+  source is a gitlab url, `domain:org/repo`, an alias or a local checkout
+
+commands:
+{}
+
+flags:
+{}",
+        commands.join("\n"),
+        flags.join("\n")
+    )
+}
+
+fn documented(flag: &str) -> bool {
+    FLAGS.iter().any(|f| f.names.contains(&flag))
+}
+
+/// What `--help` or `--version` print when either is among `args`, looked
+/// up before anything else so that neither reads the config, creates
+/// directories or prompts for the passphrase.
+pub fn early_exit(mut args: impl Iterator<Item = String>) -> Option<String> {
+    args.find_map(|arg| match arg.as_str() {
+        "--help" | "-h" => Some(usage()),
+        "--version" | "-V" => Some(format!("rvar {}", env!("CARGO_PKG_VERSION"))),
+        _ => None,
+    })
+}
+
+pub fn parse_args(args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    parse_args_with(Args::default(), args)
+}
+
+/// Like [`parse_args`], with flags overriding the values of `defaults`.
+pub fn parse_args_with(
+    defaults: Args,
+    mut args: impl Iterator<Item = String>,
+) -> anyhow::Result<Args> {
+    let mut parsed = defaults;
+    let mut positional = vec![];
+    while let Some(arg) = args.next() {
+        if arg.starts_with('-') && !documented(&arg) {
+            anyhow::bail!("unknown flag: {arg}")
+        }
+        match arg.as_str() {
+            "--format" => {
+                parsed.format = args.next().context("--format expects a value")?.parse()?;
+            }
+            "--color" => {
+                parsed.color = args.next().context("--color expects a value")?.parse()?;
+            }
+            "--error-format" => {
+                parsed.error_format = args
+                    .next()
+                    .context("--error-format expects a value")?
+                    .parse()?;
+            }
+            "--verbose" | "-v" => parsed.verbose = true,
+            "--token" => parsed.token = Some(args.next().context("--token expects a value")?),
+            "--save" => parsed.save = true,
+            "--token-file" => {
+                parsed.token_file = Some(args.next().context("--token-file expects a path")?.into())
+            }
+            "--token-stdin" => parsed.token_stdin = true,
+            "--no-cache" => parsed.no_cache = true,
+            "--env" => {
+                parsed.environment = Some(args.next().context("--env expects a value")?);
+            }
+            "--name" => parsed.name = Some(args.next().context("--name expects a value")?),
+            "--namespace" => {
+                parsed.namespace = Some(args.next().context("--namespace expects a value")?);
+            }
+            "--path" => parsed.path = Some(args.next().context("--path expects a value")?),
+            "--redact" => parsed.redact = true,
+            "--keys-only" => parsed.format = OutputFormat::Keys,
+            "--show-values" => parsed.show_values = true,
+            "--comments" => parsed.comments = true,
+            "--expand" => parsed.expand = true,
+            "--prune" => parsed.prune = true,
+            "--projects" => parsed.projects = true,
+            "--dry-run" => parsed.dry_run = true,
+            "--tag" => parsed.tag = Some(args.next().context("--tag expects a value")?),
+            "--topic" => parsed.topic = Some(args.next().context("--topic expects a value")?),
+            "--snapshot" => {
+                parsed.snapshot = Some(args.next().context("--snapshot expects a tag")?)
+            }
+            "--set" => {
+                let pair = args.next().context("--set expects KEY=value")?;
+                let (key, value) = pair
+                    .split_once('=')
+                    .with_context(|| format!("--set expects KEY=value, got {pair}"))?;
+                crate::gapi::validate_key(key)?;
+                parsed.overrides.push((key.to_string(), value.to_string()));
+            }
+            "--require" => {
+                let keys = args.next().context("--require expects a list of keys")?;
+                parsed.require.extend(
+                    keys.split(',')
+                        .map(str::trim)
+                        .filter(|k| !k.is_empty())
+                        .map(String::from),
+                );
+            }
+            "--out" => {
+                parsed.out = Some(args.next().context("--out expects a path")?.into());
+            }
+            "--file" => {
+                parsed.file = Some(args.next().context("--file expects a path")?.into());
+            }
+            "--triggers" => parsed.command = Command::Triggers,
+            "--deploy-tokens" => parsed.command = Command::DeployTokens,
+            "--raw" => parsed.command = Command::Raw,
+            "--print-config" => parsed.command = Command::PrintConfig,
+            "--insecure-http" => parsed.insecure = true,
+            "--offline" => parsed.offline = true,
+            "--explain" => parsed.explain = true,
+            "--with-ancestors" => parsed.with_ancestors = true,
+            "--deadline" => {
+                let secs = args.next().context("--deadline expects a value")?;
+                let secs = secs
+                    .parse::<u64>()
+                    .with_context(|| format!("invalid deadline: {secs}"))?;
+                parsed.deadline = Some(Duration::from_secs(secs));
+            }
+            "--jobs" => {
+                let jobs = args.next().context("--jobs expects a value")?;
+                let jobs = jobs
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|j| *j > 0)
+                    .with_context(|| format!("invalid number of jobs: {jobs}"))?;
+                parsed.jobs = Some(jobs);
+            }
+            "--watch" => {
+                let secs = args.next().context("--watch expects a value")?;
+                let secs = secs
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|s| *s > 0)
+                    .with_context(|| format!("invalid watch interval: {secs}"))?;
+                parsed.watch = Some(Duration::from_secs(secs));
+            }
+            "--transform" => parsed.transforms.push(
+                args.next()
+                    .context("--transform expects a value")?
+                    .parse()?,
+            ),
+            "--per-page" => {
+                let per_page = args.next().context("--per-page expects a value")?;
+                parsed.per_page = Some(
+                    per_page
+                        .parse()
+                        .with_context(|| format!("invalid page size: {per_page}"))?,
+                );
+            }
+            "--size-warning" => {
+                let bytes = args.next().context("--size-warning expects a value")?;
+                parsed.size_warning = Some(
+                    bytes
+                        .parse()
+                        .with_context(|| format!("invalid size: {bytes}"))?,
+                );
+            }
+            "--ttl" => {
+                let ttl = args.next().context("--ttl expects a duration")?;
+                parsed.ttl = Some(
+                    humantime::parse_duration(&ttl)
+                        .with_context(|| format!("invalid ttl: {ttl}"))?,
+                );
+            }
+            "--since" => {
+                let since = args.next().context("--since expects a value")?;
+                parsed.since = Some(parse_since(&since, crate::oauth::now())?);
+            }
+            flag if flag.starts_with('-') => anyhow::bail!("{flag} is not handled"),
+            _ => positional.push(arg),
+        }
+    }
+    if parsed.format == OutputFormat::K8sSecret && parsed.name.is_none() {
+        anyhow::bail!("--format k8s-secret expects --name")
+    }
+    if parsed.no_cache && parsed.offline {
+        anyhow::bail!("--no-cache and --offline are mutually exclusive")
+    }
+    if parsed.no_cache && parsed.snapshot.is_some() {
+        anyhow::bail!("--snapshot reads the cache, it can't go with --no-cache")
+    }
+    if parsed.path.is_some() && parsed.format != OutputFormat::VaultKv {
+        anyhow::bail!("--path only applies to --format vault-kv")
+    }
+    let mut positional = positional.into_iter();
+    let mut source = positional.next();
+    match source.as_deref() {
+        Some("get-var" | "cat") => {
+            source = positional.next();
+            let key = positional
+                .next()
+                .context("get-var expects a variable key")?;
+            parsed.command = Command::GetVar { key };
+        }
+        Some("diff") => {
+            source = positional.next();
+            parsed.command = Command::Diff;
+        }
+        Some("extract") => {
+            source = positional.next();
+            let key = positional
+                .next()
+                .context("extract expects a variable key")?;
+            let out = parsed.out.clone().context("extract expects --out")?;
+            parsed.command = Command::Extract { key, out };
+        }
+        Some("rotate-token") => {
+            source = positional.next();
+            let id = match positional.next() {
+                Some(id) => Some(
+                    id.parse()
+                        .with_context(|| format!("invalid token id: {id}"))?,
+                ),
+                None => None,
+            };
+            parsed.command = Command::RotateToken { id };
+        }
+        Some("browse") => {
+            source = positional.next();
+            parsed.command = Command::Browse {
+                projects: parsed.projects,
+            };
+        }
+        Some("tokens") => {
+            match positional.next().as_deref() {
+                Some("check") => parsed.command = Command::CheckTokens,
+                Some(other) => anyhow::bail!("unknown tokens command: {other}"),
+                None => anyhow::bail!("tokens expects a command, such as check"),
+            }
+            source = positional.next();
+        }
+        Some("cache") => {
+            match positional.next().as_deref() {
+                Some("info") => parsed.command = Command::CacheInfo,
+                Some(other) => anyhow::bail!("unknown cache command: {other}"),
+                None => anyhow::bail!("cache expects a command, such as info"),
+            }
+            source = positional.next();
+        }
+        Some("prune-cache") => {
+            source = positional.next();
+            parsed.command = Command::PruneCache {
+                dry_run: parsed.dry_run,
+            };
+        }
+        Some("ensure-token") => {
+            source = positional.next();
+            let from = match (parsed.token_file.clone(), parsed.token_stdin) {
+                (Some(_), true) => {
+                    anyhow::bail!("--token-file and --token-stdin are mutually exclusive")
+                }
+                (Some(path), false) => TokenInput::File(path),
+                (None, true) => TokenInput::Stdin,
+                (None, false) => TokenInput::Env,
+            };
+            parsed.command = Command::EnsureToken { from };
+        }
+        Some("get") => {
+            source = positional.next();
+            parsed.command = Command::Show;
+        }
+        Some("snapshot") => {
+            source = positional.next();
+            let tag = parsed.tag.clone().context("snapshot expects --tag")?;
+            // a snapshot lives in the cache
+            if parsed.no_cache {
+                anyhow::bail!("snapshot can't be taken with --no-cache")
+            }
+            parsed.command = Command::Snapshot { tag };
+        }
+        Some("check") => {
+            source = positional.next();
+            if parsed.require.is_empty() {
+                anyhow::bail!("check expects --require")
+            }
+            parsed.command = Command::Check {
+                required: parsed.require.clone(),
+            };
+        }
+        Some("alias") => {
+            let action = match positional.next().as_deref() {
+                Some("add") => {
+                    let name = positional.next().context("alias add expects a name")?;
+                    let target = positional.next().context("alias add expects a uri")?;
+                    AliasAction::Add { name, target }
+                }
+                Some("list") => AliasAction::List,
+                Some("remove") => AliasAction::Remove {
+                    name: positional.next().context("alias remove expects a name")?,
+                },
+                Some(other) => anyhow::bail!("unknown alias command: {other}"),
+                None => anyhow::bail!("alias expects a command, such as add"),
+            };
+            source = positional.next();
+            parsed.command = Command::Alias { action };
+        }
+        Some("whoami") => {
+            source = positional.next();
+            parsed.command = Command::Whoami;
+        }
+        Some("verify") => {
+            source = positional.next();
+            parsed.command = Command::Verify;
+        }
+        Some("agent") => {
+            source = positional.next();
+            parsed.command = Command::Agent {
+                ttl: parsed.ttl.unwrap_or(crate::agent::DEFAULT_TTL),
+            };
+        }
+        Some("list") => {
+            source = positional.next();
+            parsed.command = Command::List;
+        }
+        Some("by-topic") => {
+            source = positional.next();
+            let topic = parsed.topic.clone().context("by-topic expects --topic")?;
+            parsed.command = Command::ByTopic { topic };
+        }
+        Some("apply") => {
+            source = positional.next();
+            let file = parsed.file.clone().context("apply expects --file")?;
+            parsed.command = Command::Apply { file };
+        }
+        _ => (),
+    }
+    let sourceless = matches!(
+        parsed.command,
+        Command::List
+            | Command::CheckTokens
+            | Command::CacheInfo
+            | Command::Alias { .. }
+            | Command::PrintConfig
+            | Command::Agent { .. }
+            | Command::Verify
+    );
+    match (sourceless, source) {
+        (true, Some(source)) => anyhow::bail!("unexpected argument: {source}"),
+        (true, None) => (),
+        (false, source) => parsed.source = source.context("missing source path or url")?,
+    }
+    if let Some(arg) = positional.next() {
+        anyhow::bail!("unexpected argument: {arg}")
+    }
+    if parsed.watch.is_some() && parsed.command != Command::Show {
+        anyhow::bail!("--watch only applies to printing every variable")
+    }
+    if parsed.dry_run && !matches!(parsed.command, Command::PruneCache { .. }) {
+        anyhow::bail!("--dry-run only applies to prune-cache")
+    }
+    Ok(parsed)
+}
+
+/// Unix seconds of either a timestamp, `2024-05-01T12:00:00Z`, or a
+/// duration before `now`, `3days`.
+fn parse_since(since: &str, now: u64) -> anyhow::Result<u64> {
+    if let Ok(time) = humantime::parse_rfc3339_weak(since) {
+        return Ok(time.duration_since(std::time::UNIX_EPOCH)?.as_secs());
+    }
+    let ago = humantime::parse_duration(since)
+        .with_context(|| format!("invalid --since, expected a timestamp or duration: {since}"))?;
+    Ok(now.saturating_sub(ago.as_secs()))
+}
+
+/// The requested error format, looked up separately from [`parse_args`] so
+/// that argument errors can be reported in it too.
+pub fn error_format(args: impl Iterator<Item = String>) -> ErrorFormat {
+    let args: Vec<String> = args.collect();
+    args.windows(2)
+        .find(|w| w[0] == "--error-format")
+        .and_then(|w| w[1].parse().ok())
+        .unwrap_or_default()
+}
 
 pub fn parse_source(input: &str) -> Source {
+    parse_source_with_hosts(input, &[])
+}
+
+/// Like [`parse_source`], also taking inputs on any of `hosts` as urls,
+/// for self-hosted instances without gitlab in their domain.
+pub fn parse_source_with_hosts(input: &str, hosts: &[String]) -> Source {
     let mut input = input.to_string();
     /* A gitlab uri should be on the pattern
      * gitlab.<optional selfhosted org>.<tld>/<org>/<group>/../<repo>
@@ -18,8 +873,20 @@ pub fn parse_source(input: &str) -> Source {
      *
      * There could definetly be bugs in the pattern here
      */
+    if Regex::new(PATTERN_ID).unwrap().is_match(&input) {
+        return Source::Id(input);
+    }
+    // the host's colons aren't delimiters, leave them be
+    if crate::uri_meta::split_ipv6_host(&input).is_some() {
+        return Source::Web(input);
+    }
     let webpattern = Regex::new(r"gitlab.*\.[a-z, A-Z, 0-9]*(:|\/)").unwrap();
-    if webpattern.is_match(&input) {
+    let on_host = hosts.iter().any(|host| {
+        input
+            .strip_prefix(host.as_str())
+            .is_some_and(|rest| rest.starts_with(['/', ':']))
+    });
+    if on_host || webpattern.is_match(&input) {
         // if we get something like gitlab.com:org/group...
         input = input.replacen(":", "/", 1);
         return Source::Web(input);
@@ -61,12 +928,53 @@ pub fn select_option(msg: &str, options: &[String]) -> anyhow::Result<String> {
     Ok(options[selected - 1].clone())
 }
 
+/// Runs the blocking `prompt` on tokio's blocking pool, so that waiting on
+/// the user doesn't stall other tasks of the runtime.
+pub async fn prompt_async<T: Send + 'static>(
+    prompt: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    tokio::task::spawn_blocking(prompt)
+        .await
+        .context("prompt task failed")?
+}
+
+/// The longest line read at a prompt, far beyond any token or passphrase.
+pub const MAX_INPUT: usize = 4096;
+/// Echoed in place of hidden input, of the same width whatever its length.
+pub const MASK: &str = "*********";
+
+// reads a line of at most `max` bytes, `None` when aborted by ctrl-c or
+// ctrl-d, which in raw mode arrive as bytes, as does backspace
+fn read_capped(input: impl std::io::BufRead, max: usize) -> anyhow::Result<Option<String>> {
+    let mut line = vec![];
+    for byte in input.bytes() {
+        match byte? {
+            b'\n' | b'\r' => break,
+            3 | 4 => return Ok(None),
+            // drops a whole character, continuation bytes up to its first
+            0x7f | 0x08 => while line.pop().is_some_and(|b| b & 0xc0 == 0x80) {},
+            _ if line.len() == max => anyhow::bail!("input is longer than {max} bytes"),
+            byte => line.push(byte),
+        }
+    }
+    Ok(Some(
+        String::from_utf8(line).context("input is not valid utf-8")?,
+    ))
+}
+
+// ends a hidden entry, the echo tells nothing about what was entered
+fn masked(input: anyhow::Result<Option<String>>, err: &mut impl Write) -> anyhow::Result<String> {
+    let input = input?.context("aborted")?;
+    writeln!(err, "{MASK}")?;
+    err.flush()?;
+    Ok(input.trim().to_string())
+}
+
 pub fn input_with_prompt(prompt: &str) -> anyhow::Result<String> {
     let mut err = std::io::stderr();
     write!(err, "{}", prompt)?;
     err.flush()?;
-    let mut input = String::new();
-    std::io::stdin().read_line(&mut input)?;
+    let input = read_capped(std::io::stdin().lock(), MAX_INPUT)?.unwrap_or_default();
     Ok(input.trim().to_string())
 }
 
@@ -74,17 +982,533 @@ pub fn hidden_input_with_prompt(prompt: &str) -> anyhow::Result<String> {
     let mut err = std::io::stderr();
     write!(err, "{}", prompt)?;
     err.flush()?;
-    let input = std::io::stdin()
-        .read_passwd(&mut err)?
-        .context("failed to read stdin")?;
-    writeln!(err, "*********")?;
-    err.flush()?;
-    return Ok(input.trim().to_string());
+    let input = {
+        // no echo while typing
+        let _raw = std::io::stderr().into_raw_mode()?;
+        read_capped(std::io::stdin().lock(), MAX_INPUT)
+    };
+    masked(input, &mut err)
+}
+
+/// Logs to stderr, filtered by RUST_LOG when set. Otherwise only warnings
+/// are shown unless `verbose` is set, and the resolution steps, see
+/// [`crate::gapi::EXPLAIN`], with `explain`. Levels are colored as `color`
+/// decides.
+pub fn init_tracing(verbose: bool, explain: bool, color: ColorChoice) {
+    use std::io::IsTerminal;
+    use tracing_subscriber::fmt::format::FmtSpan;
+    use tracing_subscriber::EnvFilter;
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| match verbose {
+        true => EnvFilter::new("ruggit=debug,rvar=debug"),
+        false => EnvFilter::new("warn"),
+    });
+    let filter = match explain {
+        true => filter.add_directive(
+            format!("{}=info", crate::gapi::EXPLAIN)
+                .parse()
+                .expect("valid directive"),
+        ),
+        false => filter,
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_ansi(color.enabled(crate::paths::from_env, std::io::stderr().is_terminal()))
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+/// Asks for a new passphrase twice, failing if the entries differ so that
+/// a typo doesn't silently become the passphrase.
+pub fn new_passphrase(
+    mut prompt: impl FnMut(&str) -> anyhow::Result<String>,
+) -> anyhow::Result<String> {
+    let passphrase = prompt("new passphrase: ")?;
+    let confirmed = prompt("confirm passphrase: ")?;
+    if passphrase != confirmed {
+        anyhow::bail!("passphrases don't match")
+    }
+    Ok(passphrase)
+}
+
+/// Offers to replace the token stored for `domain` that gitlab rejected,
+/// reading the answer with `confirm` and the new token with `secret`.
+/// Anything but yes keeps the old token, yielding None.
+pub fn replace_token(
+    domain: &str,
+    status: TokenStatus,
+    mut confirm: impl FnMut(&str) -> anyhow::Result<String>,
+    mut secret: impl FnMut(&str) -> anyhow::Result<String>,
+) -> anyhow::Result<Option<String>> {
+    let status = match status {
+        TokenStatus::Valid => return Ok(None),
+        TokenStatus::Invalid => "invalid",
+        TokenStatus::Expired => "expired",
+    };
+    let answer = confirm(&format!(
+        "existing token for {domain} is {status}, replace? [y/N] "
+    ))?;
+    if !matches!(answer.to_lowercase().as_str(), "y" | "yes") {
+        return Ok(None);
+    }
+    Ok(Some(secret("token: ")?))
+}
+
+/// Puts the terminal back into a sane state, after being interrupted mid
+/// prompt the cursor might be left hidden and the line unterminated.
+pub fn restore_terminal(out: &mut impl Write) -> std::io::Result<()> {
+    write!(
+        out,
+        "{}{}\r\n",
+        termion::cursor::Show,
+        termion::style::Reset
+    )?;
+    out.flush()
+}
+
+/// Restores the terminal and exits as if killed by SIGINT.
+pub fn abort() -> ! {
+    let mut err = std::io::stderr();
+    let _ = restore_terminal(&mut err);
+    let _ = writeln!(err, "aborted");
+    std::process::exit(130);
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    fn args(args: &[&str]) -> anyhow::Result<Args> {
+        parse_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn test_flags_documented() {
+        let help = usage();
+        for flag in FLAGS.iter().flat_map(|f| f.names) {
+            assert!(help.contains(flag), "{flag} missing from --help");
+            if early_exit(std::iter::once(flag.to_string())).is_some() {
+                continue;
+            }
+            let parsed = args(&[flag, "."]);
+            if let Err(e) = parsed {
+                let e = e.to_string();
+                assert!(
+                    !e.contains("not handled") && !e.contains("unknown flag"),
+                    "{e}"
+                );
+            }
+        }
+        assert!(args(&["--undocumented", "."])
+            .unwrap_err()
+            .to_string()
+            .starts_with("unknown flag"));
+        assert!(args(&["--no-cache", "--offline", "."]).is_err());
+        // no line runs past the terminal
+        assert!(help.lines().all(|l| l.len() <= 80), "{help}");
+    }
+
+    #[test]
+    fn test_early_exit() {
+        let early = |args: &[&str]| early_exit(args.iter().map(|a| a.to_string()));
+        assert_eq!(early(&["--help"]), Some(usage()));
+        assert_eq!(early(&["get", "gitlab.com/org/foo", "-h"]), Some(usage()));
+        assert_eq!(
+            early(&["--version"]),
+            Some(format!("rvar {}", env!("CARGO_PKG_VERSION")))
+        );
+        assert_eq!(early(&["gitlab.com/org/foo"]), None);
+        // help needs no source, nor anything else that parsing insists on
+        assert!(args(&["snapshot", "--help"]).is_err());
+        assert!(early(&["snapshot", "--help"]).is_some());
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let parsed = args(&["gitlab.com/org/foo"]).unwrap();
+        assert_eq!(parsed.source, "gitlab.com/org/foo");
+        assert_eq!(parsed.format, OutputFormat::Json);
+
+        let parsed = args(&["--format", "scoped", "."]).unwrap();
+        assert_eq!(parsed.format, OutputFormat::Scoped);
+
+        let parsed = args(&["--format", "dotenv", "--env", "production", "."]).unwrap();
+        assert_eq!(parsed.format, OutputFormat::Dotenv);
+        assert_eq!(parsed.environment.as_deref(), Some("production"));
+        assert!(
+            args(&["--format", "dotenv", "--comments", "."])
+                .unwrap()
+                .comments
+        );
+        assert!(!args(&["--format", "dotenv", "."]).unwrap().expand);
+        assert!(
+            args(&["--format", "dotenv", "--expand", "."])
+                .unwrap()
+                .expand
+        );
+
+        let parsed = args(&[".", "--deadline", "30"]).unwrap();
+        assert_eq!(parsed.deadline, Some(Duration::from_secs(30)));
+        assert!(args(&[".", "--deadline", "soon"]).is_err());
+        assert_eq!(
+            args(&[".", "--since", "2024-01-01T00:00:00Z"])
+                .unwrap()
+                .since,
+            Some(1704067200)
+        );
+        assert!(args(&[".", "--since", "yesterday"]).is_err());
+        let parsed = args(&[".", "--transform", "base64-decode", "--transform", "trim"]).unwrap();
+        assert_eq!(
+            parsed.transforms,
+            vec![Transform::Base64Decode, Transform::Trim]
+        );
+        assert!(args(&[".", "--transform", "rot13"]).is_err());
+
+        assert!(args(&["-v", "."]).unwrap().verbose);
+        assert!(args(&[".", "--no-cache"]).unwrap().no_cache);
+        let parsed = args(&[".", "--token", "glpat-x", "--save"]).unwrap();
+        assert_eq!(parsed.token.as_deref(), Some("glpat-x"));
+        assert!(parsed.save);
+
+        let parsed = args(&["get-var", "gitlab.com/org/foo", "HOST"]).unwrap();
+        assert_eq!(
+            parsed.command,
+            Command::GetVar {
+                key: "HOST".to_string()
+            }
+        );
+        assert_eq!(parsed.source, "gitlab.com/org/foo");
+        assert!(args(&["cat", ".", "HOST", "--redact"]).unwrap().redact);
+        assert_eq!(
+            args(&["--keys-only", "."]).unwrap().format,
+            OutputFormat::Keys
+        );
+        let parsed = args(&[
+            "--format",
+            "k8s-secret",
+            "--name",
+            "app",
+            "--namespace",
+            "prod",
+            ".",
+        ])
+        .unwrap();
+        assert_eq!(parsed.format, OutputFormat::K8sSecret);
+        assert_eq!(parsed.name.as_deref(), Some("app"));
+        assert_eq!(parsed.namespace.as_deref(), Some("prod"));
+        assert!(args(&["--format", "k8s-secret", "."]).is_err());
+        let parsed = args(&["--format", "vault-kv", "--path", "secret/app", "."]).unwrap();
+        assert_eq!(parsed.format, OutputFormat::VaultKv);
+        assert_eq!(parsed.path.as_deref(), Some("secret/app"));
+        assert!(args(&["--format", "vault-kv", "."]).unwrap().path.is_none());
+        assert!(args(&["--path", "secret/app", "."]).is_err());
+        assert!(args(&["get-var", "."]).is_err(), "missing key");
+
+        let parsed = args(&["diff", ".", "--show-values"]).unwrap();
+        assert_eq!(parsed.command, Command::Diff);
+        assert_eq!(parsed.source, ".");
+        assert!(parsed.show_values);
+
+        let parsed = args(&["apply", ".", "--file", "vars.env", "--prune"]).unwrap();
+        assert_eq!(
+            parsed.command,
+            Command::Apply {
+                file: PathBuf::from("vars.env")
+            }
+        );
+        assert!(parsed.prune);
+        assert!(args(&["apply", "."]).is_err(), "missing file");
+
+        let parsed = args(&["by-topic", "gitlab.corp/ops", "--topic", "deploy"]).unwrap();
+        assert_eq!(
+            parsed.command,
+            Command::ByTopic {
+                topic: "deploy".to_string()
+            }
+        );
+        assert_eq!(parsed.source, "gitlab.corp/ops");
+        assert!(
+            args(&["by-topic", "gitlab.corp/ops"]).is_err(),
+            "missing topic"
+        );
+
+        let parsed = args(&["extract", ".", "KUBECONFIG", "--out", "kube.yml"]).unwrap();
+        assert_eq!(
+            parsed.command,
+            Command::Extract {
+                key: "KUBECONFIG".to_string(),
+                out: PathBuf::from("kube.yml")
+            }
+        );
+        assert!(
+            args(&["extract", ".", "KUBECONFIG"]).is_err(),
+            "missing out"
+        );
+
+        assert_eq!(args(&["list"]).unwrap().command, Command::List);
+        let parsed = args(&["browse", "gitlab.example.com", "--projects"]).unwrap();
+        assert_eq!(parsed.command, Command::Browse { projects: true });
+        assert_eq!(parsed.source, "gitlab.example.com");
+        assert!(args(&["browse"]).is_err(), "browse expects a domain");
+        assert!(args(&["list", "."]).is_err());
+        assert_eq!(
+            args(&["tokens", "check"]).unwrap().command,
+            Command::CheckTokens
+        );
+        assert!(args(&["tokens"]).is_err());
+        assert_eq!(
+            args(&["cache", "info"]).unwrap().command,
+            Command::CacheInfo
+        );
+        assert!(args(&["cache", "info", "org/repo"]).is_err());
+        let parsed = args(&["ensure-token", "gitlab.example.com", "--token-stdin"]).unwrap();
+        assert_eq!(
+            parsed.command,
+            Command::EnsureToken {
+                from: TokenInput::Stdin
+            }
+        );
+        assert_eq!(parsed.source, "gitlab.example.com");
+        assert_eq!(
+            args(&["ensure-token", "gitlab.example.com"])
+                .unwrap()
+                .command,
+            Command::EnsureToken {
+                from: TokenInput::Env
+            }
+        );
+        assert!(args(&["ensure-token", "x", "--token-file", "t", "--token-stdin"]).is_err());
+        let parsed = args(&["prune-cache", "gitlab.example.com", "--dry-run"]).unwrap();
+        assert_eq!(parsed.command, Command::PruneCache { dry_run: true });
+        assert_eq!(parsed.source, "gitlab.example.com");
+        assert!(
+            args(&["apply", ".", "--file", "vars.env", "--prune", "--dry-run"]).is_err(),
+            "apply would still write"
+        );
+        assert!(
+            args(&["prune-cache"]).is_err(),
+            "prune-cache expects a domain"
+        );
+        assert!(args(&["tokens", "purge"]).is_err());
+        assert_eq!(
+            args(&["agent", "--ttl", "1h"]).unwrap().command,
+            Command::Agent {
+                ttl: Duration::from_secs(3600)
+            }
+        );
+        assert!(args(&["agent", "--ttl", "soon"]).is_err());
+        assert_eq!(args(&["verify"]).unwrap().command, Command::Verify);
+        let parsed = args(&["whoami", "gitlab.example.com"]).unwrap();
+        assert_eq!(parsed.command, Command::Whoami);
+        assert_eq!(parsed.source, "gitlab.example.com");
+        assert!(args(&["whoami"]).is_err(), "whoami expects a domain");
+        assert_eq!(
+            args(&["--watch", "30", "org/repo"]).unwrap().watch,
+            Some(Duration::from_secs(30))
+        );
+        assert!(args(&["--watch", "0", "org/repo"]).is_err());
+        assert!(args(&["diff", "org/repo", "--watch", "30"]).is_err());
+        assert!(args(&["verify", "org/repo"]).is_err());
+        let parsed = args(&["rotate-token", ".", "12"]).unwrap();
+        assert_eq!(parsed.command, Command::RotateToken { id: Some(12) });
+        let parsed = args(&["rotate-token", "."]).unwrap();
+        assert_eq!(parsed.command, Command::RotateToken { id: None });
+        assert!(args(&["rotate-token", ".", "abc"]).is_err());
+
+        assert!(args(&[".", "--insecure-http"]).unwrap().insecure);
+        assert!(args(&[".", "--offline"]).unwrap().offline);
+        assert!(args(&[".", "--explain"]).unwrap().explain);
+        assert!(args(&[".", "--with-ancestors"]).unwrap().with_ancestors);
+        assert_eq!(args(&[".", "--jobs", "8"]).unwrap().jobs, Some(8));
+        assert!(args(&[".", "--jobs", "0"]).is_err());
+        let parsed = args(&["--set", "HOST=a=b", "--set", "EMPTY=", "."]).unwrap();
+        assert_eq!(
+            parsed.overrides,
+            [("HOST", "a=b"), ("EMPTY", "")].map(|(k, v)| (k.to_string(), v.to_string()))
+        );
+        assert!(args(&["--set", "HOST", "."]).is_err());
+        assert!(args(&["--set", "=value", "."]).is_err());
+        assert_eq!(
+            args(&["--triggers", "."]).unwrap().command,
+            Command::Triggers
+        );
+        assert_eq!(
+            args(&["--deploy-tokens", "."]).unwrap().command,
+            Command::DeployTokens
+        );
+        assert_eq!(args(&["--raw", "."]).unwrap().command, Command::Raw);
+        let parsed = args(&["check", ".", "--require", "A, B", "--require", "C"]).unwrap();
+        assert_eq!(
+            parsed.command,
+            Command::Check {
+                required: vec!["A".into(), "B".into(), "C".into()]
+            }
+        );
+        assert!(args(&["check", "."]).is_err(), "check expects --require");
+        let parsed = args(&["snapshot", ".", "--tag", "release-1.2"]).unwrap();
+        assert_eq!(
+            parsed.command,
+            Command::Snapshot {
+                tag: "release-1.2".into()
+            }
+        );
+        assert!(args(&["snapshot", "."]).is_err(), "snapshot expects --tag");
+        assert!(args(&["snapshot", ".", "--tag", "v1", "--no-cache"]).is_err());
+        assert!(args(&[".", "--snapshot", "v1", "--no-cache"]).is_err());
+        assert_eq!(
+            args(&["--print-config"]).unwrap().command,
+            Command::PrintConfig
+        );
+        let parsed = args(&["get", "org/repo", "--snapshot", "release-1.2"]).unwrap();
+        assert_eq!(parsed.command, Command::Show);
+        assert_eq!(parsed.source, "org/repo");
+        assert_eq!(parsed.snapshot.as_deref(), Some("release-1.2"));
+        let parsed = args(&["alias", "add", "prod", "gitlab.corp/ops/prod-deploy"]).unwrap();
+        assert_eq!(
+            parsed.command,
+            Command::Alias {
+                action: AliasAction::Add {
+                    name: "prod".into(),
+                    target: "gitlab.corp/ops/prod-deploy".into()
+                }
+            }
+        );
+        assert_eq!(
+            args(&["alias", "list"]).unwrap().command,
+            Command::Alias {
+                action: AliasAction::List
+            }
+        );
+        assert!(args(&["alias", "remove"]).is_err());
+        assert!(args(&["alias", "list", "extra"]).is_err());
+
+        assert_eq!(
+            args(&[".", "--color", "always"]).unwrap().color,
+            ColorChoice::Always
+        );
+        assert!(args(&[".", "--color", "sometimes"]).is_err());
+        let parsed = args(&[".", "--error-format", "json"]).unwrap();
+        assert_eq!(parsed.error_format, ErrorFormat::Json);
+        assert!(args(&[".", "--error-format", "xml"]).is_err());
+        let raw = ["--bogus", "--error-format", "json"].map(|a| a.to_string());
+        assert_eq!(error_format(raw.into_iter()), ErrorFormat::Json);
+
+        assert!(args(&[]).is_err(), "source is required");
+        assert!(args(&[".", "--format"]).is_err(), "missing flag value");
+        assert!(args(&[".", "--bogus"]).is_err(), "unknown flag");
+        assert!(args(&[".", "other"]).is_err(), "multiple sources");
+    }
+    #[test]
+    fn test_read_capped() {
+        let read = |input: &[u8], max| read_capped(input, max);
+        assert_eq!(read(b"token\nrest", 5).unwrap().as_deref(), Some("token"));
+        assert_eq!(read(b"token\r", 5).unwrap().as_deref(), Some("token"));
+        assert_eq!(read(b"", 5).unwrap().as_deref(), Some(""));
+        assert_eq!(read(b"tok\x03en", 5).unwrap(), None, "ctrl-c");
+        assert_eq!(read(b"tox\x7fken\n", 5).unwrap().as_deref(), Some("token"));
+        assert_eq!(read(b"\x08\x7fab\x08\n", 5).unwrap().as_deref(), Some("a"));
+        let accented = "caf\u{e9}\x7fe\n".as_bytes();
+        assert_eq!(read(accented, 5).unwrap().as_deref(), Some("cafe"));
+        let pasted = "x".repeat(MAX_INPUT * 100);
+        let error = read(pasted.as_bytes(), MAX_INPUT).unwrap_err();
+        assert_eq!(error.to_string(), "input is longer than 4096 bytes");
+        assert!(read(b"\xff\n", 5).is_err());
+    }
+
+    #[test]
+    fn test_masked() {
+        let echo = |input: &str| {
+            let mut err = vec![];
+            let entered = masked(Ok(Some(input.to_string())), &mut err).unwrap();
+            (entered, String::from_utf8(err).unwrap())
+        };
+        let (entered, short) = echo(" ab ");
+        assert_eq!(entered, "ab");
+        let (_, long) = echo(&"x".repeat(MAX_INPUT));
+        assert_eq!(short, format!("{MASK}\n"));
+        assert_eq!(long, short, "the echo doesn't give the length away");
+        assert!(masked(Ok(None), &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_new_passphrase() {
+        let scripted = |entries: [&'static str; 2]| {
+            let mut entries = entries.into_iter();
+            move |_: &str| Ok(entries.next().unwrap().to_string())
+        };
+        assert_eq!(
+            new_passphrase(scripted(["secret", "secret"])).unwrap(),
+            "secret"
+        );
+        assert!(new_passphrase(scripted(["secret", "secert"])).is_err());
+    }
+
+    #[test]
+    fn test_replace_token() {
+        let answer = |answer: &'static str| move |_: &str| Ok(answer.to_string());
+        let unreachable = |_: &str| -> anyhow::Result<String> { panic!("asked for a token") };
+
+        let mut prompted = String::new();
+        let replaced = replace_token(
+            "gitlab.com",
+            TokenStatus::Expired,
+            |prompt: &str| {
+                prompted = prompt.to_string();
+                Ok("y".to_string())
+            },
+            answer("new"),
+        )
+        .unwrap();
+        assert_eq!(replaced.as_deref(), Some("new"));
+        assert_eq!(
+            prompted,
+            "existing token for gitlab.com is expired, replace? [y/N] "
+        );
+
+        // declining, or just hitting enter, keeps the old token
+        for declined in ["", "n", "nope"] {
+            let kept = replace_token(
+                "gitlab.com",
+                TokenStatus::Invalid,
+                answer(declined),
+                unreachable,
+            );
+            assert_eq!(kept.unwrap(), None);
+        }
+        let valid = replace_token("gitlab.com", TokenStatus::Valid, unreachable, unreachable);
+        assert_eq!(valid.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_async() {
+        let (answer, typed) = std::sync::mpsc::channel();
+        // can only answer if the prompt leaves the runtime thread free
+        tokio::spawn(async move {
+            tokio::task::yield_now().await;
+            answer.send("y".to_string()).unwrap();
+        });
+        let prompt = prompt_async(move || Ok(typed.recv_timeout(Duration::from_secs(5))?));
+        assert_eq!(prompt.await.unwrap(), "y");
+    }
+
+    #[test]
+    fn test_restore_terminal() {
+        let mut out = vec![];
+        restore_terminal(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with(&termion::cursor::Show.to_string()));
+        assert!(out.contains(&termion::style::Reset.to_string()));
+        assert!(out.ends_with("\r\n"));
+    }
+
+    #[test]
+    fn test_parse_since() {
+        assert_eq!(parse_since("2024-01-01 00:00:00", 0).unwrap(), 1704067200);
+        assert_eq!(parse_since("2h", 10_000).unwrap(), 10_000 - 7200);
+        assert_eq!(parse_since("1day", 10).unwrap(), 0);
+    }
+
     #[test]
     fn test_parse_source() {
         let home = env::vars().find(|(k, _)| k == "HOME").unwrap().1;
@@ -103,6 +1527,16 @@ mod tests {
             Source::Web("gitlab.com/org/foo.git".to_string()),
             "failed to parse web url"
         );
+        assert_eq!(
+            parse_source("[::1]:8080/org/foo"),
+            Source::Web("[::1]:8080/org/foo".to_string()),
+            "failed to parse ipv6 url"
+        );
+        assert_eq!(
+            parse_source("id:123@gitlab.com"),
+            Source::Id("id:123@gitlab.com".to_string()),
+            "failed to parse id"
+        );
         assert_eq!(
             parse_source("~/git/foo"),
             Source::Disk(home + "/git/foo"),
@@ -0,0 +1,424 @@
+use crate::cache::CacheFormat;
+use crate::cmdline::{self, Args};
+use crate::gapi::ClientSettings;
+use crate::token;
+use crate::uri_meta::Source;
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// User configuration, read from `config.toml` in the config directory.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Config {
+    // short name -> gitlab domain, or a whole uri
+    pub aliases: HashMap<String, String>,
+    // replaces the default `ruggit/<version>` user agent
+    pub user_agent: Option<String>,
+    // defaults of the flags by the same name, which take precedence
+    pub format: Option<String>,
+    pub deadline: Option<u64>,
+    pub per_page: Option<u32>,
+    pub size_warning: Option<usize>,
+    // domain of sources given as a bare path, `org/repo`
+    pub domain: Option<String>,
+    // self-hosted instances without gitlab in their domain
+    pub hosts: Vec<String>,
+    // where the rest api is mounted, `api/v4` unless behind a proxy
+    pub api_path: Option<String>,
+    // token files distributed to a team, personal tokens take precedence
+    pub shared_tokens: Vec<PathBuf>,
+    // how the resource cache is written, read in either
+    pub cache_format: Option<CacheFormat>,
+    // where tokens are kept, a keyring spares the passphrase but leaves the
+    // caches in memory
+    pub token_backend: Option<token::Backend>,
+}
+
+/// A setting in effect and where its value came from.
+#[derive(Debug, PartialEq)]
+pub struct Setting {
+    pub name: &'static str,
+    pub value: String,
+    // flag, config, default or the environment variable
+    pub source: String,
+}
+
+impl Setting {
+    pub fn new(name: &'static str, value: impl ToString, source: &str) -> Self {
+        Self {
+            name,
+            value: value.to_string(),
+            source: source.to_string(),
+        }
+    }
+}
+
+impl Config {
+    /// Http client settings, with the configured user agent if any.
+    pub fn client_settings(&self) -> ClientSettings {
+        let mut settings = ClientSettings::default();
+        if let Some(user_agent) = &self.user_agent {
+            settings.user_agent = user_agent.clone();
+        }
+        settings.per_page = self.per_page;
+        settings
+    }
+
+    /// The arguments to parse the command line over, flags given there
+    /// override these.
+    pub fn default_args(&self) -> anyhow::Result<Args> {
+        let mut args = Args::default();
+        if let Some(format) = &self.format {
+            args.format = format.parse().context("invalid format in config")?;
+        }
+        args.deadline = self.deadline.map(Duration::from_secs);
+        args.per_page = self.per_page;
+        args.size_warning = self.size_warning;
+        Ok(args)
+    }
+
+    /// The settings in effect with `args`, the command line parsed over
+    /// [`Config::default_args`]. Flags are told apart from the config by
+    /// differing from what it alone yields. Tokens are never included.
+    pub fn effective(&self, args: &Args) -> anyhow::Result<Vec<Setting>> {
+        let configured = self.default_args()?;
+        let source = |flagged: bool, in_config: bool| match (flagged, in_config) {
+            (true, _) => "flag",
+            (false, true) => "config",
+            (false, false) => "default",
+        };
+        let or_none = |value: Option<String>| value.unwrap_or("none".to_string());
+        let list = |items: Vec<String>| or_none(Some(items.join(", ")).filter(|l| !l.is_empty()));
+        let configured_or = |name, value: &Option<String>, default: &str| match value {
+            Some(value) => Setting::new(name, value, "config"),
+            None => Setting::new(name, default, "default"),
+        };
+        let size_warning = args
+            .size_warning
+            .unwrap_or(crate::output::DEFAULT_SIZE_WARNING);
+        Ok(vec![
+            Setting::new(
+                "format",
+                args.format,
+                source(args.format != configured.format, self.format.is_some()),
+            ),
+            Setting::new(
+                "deadline",
+                or_none(args.deadline.map(|d| format!("{}s", d.as_secs()))),
+                source(
+                    args.deadline != configured.deadline,
+                    self.deadline.is_some(),
+                ),
+            ),
+            Setting::new(
+                "per_page",
+                or_none(args.per_page.map(|p| p.to_string())),
+                source(
+                    args.per_page != configured.per_page,
+                    self.per_page.is_some(),
+                ),
+            ),
+            Setting::new(
+                "size_warning",
+                format!("{size_warning} bytes"),
+                source(
+                    args.size_warning != configured.size_warning,
+                    self.size_warning.is_some(),
+                ),
+            ),
+            Setting::new(
+                "domain",
+                or_none(self.domain.clone()),
+                source(false, self.domain.is_some()),
+            ),
+            Setting::new(
+                "hosts",
+                list(self.hosts.clone()),
+                source(false, !self.hosts.is_empty()),
+            ),
+            configured_or("api_path", &self.api_path, crate::gapi::DEFAULT_API_PATH),
+            configured_or("user_agent", &self.user_agent, crate::gapi::USER_AGENT),
+            Setting::new(
+                "shared_tokens",
+                list(
+                    self.shared_tokens
+                        .iter()
+                        .map(|p| p.display().to_string())
+                        .collect(),
+                ),
+                source(false, !self.shared_tokens.is_empty()),
+            ),
+            Setting::new(
+                "cache_format",
+                self.cache_format.unwrap_or_default(),
+                source(false, self.cache_format.is_some()),
+            ),
+            Setting::new(
+                "token_backend",
+                self.token_backend.unwrap_or_default(),
+                source(false, self.token_backend.is_some()),
+            ),
+        ])
+    }
+
+    /// The source `input` refers to, with aliases expanded and the default
+    /// domain prepended to bare paths that don't exist on disk.
+    pub fn source(&self, input: &str) -> Source {
+        let expanded = expand_alias(input, &self.aliases);
+        let mut hosts = self.hosts.clone();
+        hosts.extend(self.domain.clone());
+        let source = cmdline::parse_source_with_hosts(&expanded, &hosts);
+        match (&source, &self.domain) {
+            (Source::Disk(path), Some(domain))
+                if !Path::new(path).exists() && !expanded.starts_with(['.', '/', '~']) =>
+            {
+                Source::Web(format!("{domain}/{expanded}"))
+            }
+            _ => source,
+        }
+    }
+
+    /// Loads the config at `path`, a missing file yields the defaults.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        toml::from_str(&content).with_context(|| format!("invalid config {}", path.display()))
+    }
+}
+
+// loads the config at `path` for editing, a missing file starts out empty
+fn edit_config<T>(
+    path: &Path,
+    edit: impl FnOnce(&mut toml_edit::DocumentMut) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let mut document: toml_edit::DocumentMut = content
+        .parse()
+        .with_context(|| format!("invalid config {}", path.display()))?;
+    let edited = edit(&mut document)?;
+    std::fs::write(path, document.to_string())?;
+    Ok(edited)
+}
+
+/// Saves `name` as an alias of `target` in the config at `path`, replacing
+/// any alias by that name. The rest of the file is kept as written.
+pub fn add_alias(path: &Path, name: &str, target: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name.contains([':', '/']) {
+        anyhow::bail!("invalid alias {name:?}, it can't contain ':' or '/'")
+    }
+    edit_config(path, |document| {
+        let aliases = document
+            .entry("aliases")
+            .or_insert(toml_edit::table())
+            .as_table_mut()
+            .context("aliases in config is not a table")?;
+        aliases[name] = toml_edit::value(target);
+        Ok(())
+    })
+}
+
+/// Drops the alias `name` from the config at `path`, returning whether it
+/// was there.
+pub fn remove_alias(path: &Path, name: &str) -> anyhow::Result<bool> {
+    edit_config(path, |document| {
+        let removed = document
+            .get_mut("aliases")
+            .and_then(|aliases| aliases.as_table_mut())
+            .and_then(|aliases| aliases.remove(name));
+        Ok(removed.is_some())
+    })
+}
+
+/// Replaces a leading alias in `input`, such as `corp/org/repo` or
+/// `corp:org/repo`. Anything else is returned unchanged.
+pub fn expand_alias(input: &str, aliases: &HashMap<String, String>) -> String {
+    let end = input.find([':', '/']).unwrap_or(input.len());
+    match aliases.get(&input[..end]) {
+        Some(domain) => format!("{}{}", domain, &input[end..]),
+        None => input.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::OutputFormat;
+
+    #[test]
+    fn test_expand_alias() {
+        let aliases = HashMap::from([("corp".to_string(), "gitlab.internal.corp".to_string())]);
+
+        assert_eq!(
+            expand_alias("corp/org/repo", &aliases),
+            "gitlab.internal.corp/org/repo"
+        );
+        assert_eq!(
+            expand_alias("corp:org/repo.git", &aliases),
+            "gitlab.internal.corp:org/repo.git"
+        );
+        assert_eq!(expand_alias("corp", &aliases), "gitlab.internal.corp");
+        // unknown aliases and partial matches fall through
+        assert_eq!(expand_alias("other/org/repo", &aliases), "other/org/repo");
+        assert_eq!(expand_alias("corporate/repo", &aliases), "corporate/repo");
+        assert_eq!(expand_alias("~/git/corp", &aliases), "~/git/corp");
+    }
+
+    const SAMPLE: &str = r#"
+format = "dotenv"
+deadline = 30
+per_page = 100
+size_warning = 4096
+api_path = "proxy/api/v4"
+domain = "git.corp.example"
+hosts = ["code.other.example"]
+shared_tokens = ["/etc/ruggit/team-tokens"]
+cache_format = "json"
+token_backend = "keyring"
+
+[aliases]
+corp = "gitlab.internal.corp"
+"#;
+
+    #[test]
+    fn test_defaults_precedence() {
+        let config: Config = toml::from_str(SAMPLE).unwrap();
+        let args = |flags: &[&str]| {
+            let flags = flags.iter().map(|s| s.to_string());
+            cmdline::parse_args_with(config.default_args().unwrap(), flags).unwrap()
+        };
+
+        let parsed = args(&["."]);
+        assert_eq!(parsed.format, OutputFormat::Dotenv);
+        assert_eq!(parsed.deadline, Some(Duration::from_secs(30)));
+        assert_eq!(parsed.per_page, Some(100));
+        assert_eq!(parsed.size_warning, Some(4096));
+        assert_eq!(config.api_path.as_deref(), Some("proxy/api/v4"));
+
+        let parsed = args(&[
+            "--format",
+            "json",
+            "--deadline",
+            "5",
+            "--per-page",
+            "20",
+            ".",
+        ]);
+        assert_eq!(parsed.format, OutputFormat::Json);
+        assert_eq!(parsed.deadline, Some(Duration::from_secs(5)));
+        assert_eq!(parsed.per_page, Some(20));
+
+        let invalid: Config = toml::from_str("format = \"yaml\"").unwrap();
+        assert!(invalid.default_args().is_err());
+    }
+
+    #[test]
+    fn test_effective() {
+        let config: Config = toml::from_str(SAMPLE).unwrap();
+        let flags = ["--per-page", "50", "."].map(String::from);
+        let args =
+            cmdline::parse_args_with(config.default_args().unwrap(), flags.into_iter()).unwrap();
+        let settings = config.effective(&args).unwrap();
+        let setting = |name: &str| {
+            let s = settings.iter().find(|s| s.name == name).unwrap();
+            (s.value.as_str(), s.source.as_str())
+        };
+        assert_eq!(setting("format"), ("dotenv", "config"));
+        assert_eq!(setting("deadline"), ("30s", "config"));
+        assert_eq!(setting("per_page"), ("50", "flag"));
+        assert_eq!(setting("size_warning"), ("4096 bytes", "config"));
+        assert_eq!(setting("domain"), ("git.corp.example", "config"));
+        assert_eq!(setting("api_path"), ("proxy/api/v4", "config"));
+        assert_eq!(setting("user_agent"), (crate::gapi::USER_AGENT, "default"));
+        assert_eq!(setting("cache_format"), ("json", "config"));
+        assert_eq!(setting("token_backend"), ("keyring", "config"));
+
+        let config = Config::default();
+        let settings = config.effective(&config.default_args().unwrap()).unwrap();
+        assert!(
+            settings.iter().all(|s| s.source == "default"),
+            "{settings:?}"
+        );
+    }
+
+    #[test]
+    fn test_source() {
+        let config: Config = toml::from_str(SAMPLE).unwrap();
+        let web = |s: &str| Source::Web(s.to_string());
+        assert_eq!(config.source("org/repo"), web("git.corp.example/org/repo"));
+        assert_eq!(
+            config.source("code.other.example:org/repo"),
+            web("code.other.example/org/repo")
+        );
+        assert_eq!(
+            config.source("corp/org/repo"),
+            web("gitlab.internal.corp/org/repo")
+        );
+        assert_eq!(config.source("."), Source::Disk(".".to_string()));
+        // without a default domain bare paths stay on disk
+        assert_eq!(
+            Config::default().source("org/repo"),
+            Source::Disk("org/repo".to_string())
+        );
+    }
+
+    #[test]
+    fn test_alias_crud() {
+        let path = std::env::temp_dir().join(format!("ruggit-aliases-{}.toml", std::process::id()));
+        std::fs::write(&path, "# my settings\ndomain = \"git.corp.example\"\n").unwrap();
+
+        add_alias(&path, "prod", "gitlab.corp/ops/prod-deploy").unwrap();
+        add_alias(&path, "corp", "gitlab.internal.corp").unwrap();
+        add_alias(&path, "prod", "gitlab.corp/ops/prod").unwrap();
+        assert!(add_alias(&path, "ops/prod", "gitlab.corp").is_err());
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.aliases.len(), 2);
+        assert_eq!(config.aliases["prod"], "gitlab.corp/ops/prod");
+        assert_eq!(config.domain.as_deref(), Some("git.corp.example"));
+
+        assert!(remove_alias(&path, "prod").unwrap());
+        assert!(!remove_alias(&path, "prod").unwrap());
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("# my settings\n"), "{content}");
+        assert_eq!(Config::load(&path).unwrap().aliases.len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_alias_precedence() {
+        let config: Config =
+            toml::from_str("[aliases]\nsrc = \"gitlab.corp/ops/prod-deploy\"\n").unwrap();
+        let web = |s: &str| Source::Web(s.to_string());
+        // wins over the directory by that name
+        assert_eq!(config.source("src"), web("gitlab.corp/ops/prod-deploy"));
+        assert_eq!(config.source("./src"), Source::Disk("./src".to_string()));
+        assert_eq!(config.source("prod"), Source::Disk("prod".to_string()));
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config: Config =
+            toml::from_str("[aliases]\ncorp = \"gitlab.internal.corp\"\n").unwrap();
+        assert_eq!(config.aliases["corp"], "gitlab.internal.corp");
+        assert_eq!(config.client_settings(), ClientSettings::default());
+        let config: Config = toml::from_str("").unwrap();
+        assert!(config.aliases.is_empty());
+        assert!(config.shared_tokens.is_empty());
+        let config: Config = toml::from_str(SAMPLE).unwrap();
+        assert_eq!(
+            config.shared_tokens,
+            [PathBuf::from("/etc/ruggit/team-tokens")]
+        );
+        let config: Config = toml::from_str("user_agent = \"corp-ci/1.0\"\n").unwrap();
+        assert_eq!(config.client_settings().user_agent, "corp-ci/1.0");
+    }
+}
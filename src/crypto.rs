@@ -56,7 +56,8 @@ impl EncryptedRW for PasswdProtectedFile {
 
     fn write(&self, content: &[u8]) -> Result<(), CryptoError> {
         let content = self.encrypt(content)?;
-        Ok(std::fs::write(&self.path, content)?)
+        // an interrupted write would leave nothing to decrypt
+        Ok(crate::paths::write_atomic(&self.path, &content, None)?)
     }
 
     fn path(&self) -> String {
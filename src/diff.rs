@@ -0,0 +1,267 @@
+use crate::gapi::GitlabVariable;
+use std::collections::BTreeMap;
+use std::time::UNIX_EPOCH;
+
+static HIDDEN: &str = "****";
+
+#[derive(PartialEq, Debug)]
+pub enum VariableChange {
+    Added(GitlabVariable),
+    Removed(GitlabVariable),
+    Changed {
+        old: GitlabVariable,
+        new: GitlabVariable,
+    },
+}
+
+// variables are unique per key and environment scope
+fn by_identity(variables: &[GitlabVariable]) -> BTreeMap<(String, String), &GitlabVariable> {
+    variables
+        .iter()
+        .map(|v| {
+            let scope = v.environment_scope.clone().unwrap_or("*".to_string());
+            ((v.key.clone(), scope), v)
+        })
+        .collect()
+}
+
+fn differs(old: &GitlabVariable, new: &GitlabVariable) -> bool {
+    old.value != new.value
+        || old.masked != new.masked
+        || old.protected != new.protected
+        || old.raw != new.raw
+        || old.description != new.description
+}
+
+/// What it takes to go from `old` to `new`, ordered by key and scope.
+pub fn diff(old: &[GitlabVariable], new: &[GitlabVariable]) -> Vec<VariableChange> {
+    let old = by_identity(old);
+    let new = by_identity(new);
+    let mut identities: Vec<_> = old.keys().chain(new.keys()).collect();
+    identities.sort();
+    identities.dedup();
+
+    identities
+        .into_iter()
+        .filter_map(|id| match (old.get(id), new.get(id)) {
+            (None, Some(new)) => Some(VariableChange::Added((*new).clone())),
+            (Some(old), None) => Some(VariableChange::Removed((*old).clone())),
+            (Some(old), Some(new)) if differs(old, new) => Some(VariableChange::Changed {
+                old: (*old).clone(),
+                new: (*new).clone(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Variables as cached at `fetched_at`, in unix seconds.
+pub struct Snapshot<'a> {
+    pub fetched_at: u64,
+    pub variables: &'a [GitlabVariable],
+}
+
+fn updated_at(variable: &GitlabVariable) -> Option<u64> {
+    let updated_at = humantime::parse_rfc3339_weak(variable.updated_at.as_deref()?).ok()?;
+    updated_at
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .ok()
+}
+
+/// The variables of `live` changed after `since`, in unix seconds.
+///
+/// Variables with an `updated_at` are judged by it. The others fall back
+/// to whether they differ from `snapshot`, which can only tell what changed
+/// after the snapshot was taken, so it's an approximation unless the
+/// snapshot was fetched right at `since`. Without a snapshot they're kept.
+pub fn changed_since(
+    live: &[GitlabVariable],
+    since: u64,
+    snapshot: Option<&Snapshot>,
+) -> Vec<GitlabVariable> {
+    if let Some(snapshot) = snapshot {
+        if snapshot.fetched_at < since {
+            tracing::debug!("snapshot predates --since, older changes may be included");
+        }
+    }
+    let cached = snapshot.map(|s| by_identity(s.variables));
+    live.iter()
+        .filter(|v| match (updated_at(v), &cached) {
+            (Some(updated_at), _) => updated_at > since,
+            (None, Some(cached)) => {
+                let identity = (v.key.clone(), v.scope().to_string());
+                cached.get(&identity).is_none_or(|old| differs(old, v))
+            }
+            (None, None) => true,
+        })
+        .cloned()
+        .collect()
+}
+
+fn label(variable: &GitlabVariable) -> String {
+    match variable.environment_scope.as_deref() {
+        None | Some("*") => variable.key.clone(),
+        Some(scope) => format!("{} ({})", variable.key, scope),
+    }
+}
+
+fn value(variable: &GitlabVariable, show_values: bool) -> &str {
+    match show_values {
+        true => &variable.value,
+        false => HIDDEN,
+    }
+}
+
+/// One line per change, values are hidden unless `show_values` is set.
+pub fn render(changes: &[VariableChange], show_values: bool) -> String {
+    let mut lines = vec![];
+    for change in changes {
+        match change {
+            VariableChange::Added(v) => {
+                lines.push(format!("+ {}={}", label(v), value(v, show_values)))
+            }
+            VariableChange::Removed(v) => {
+                lines.push(format!("- {}={}", label(v), value(v, show_values)))
+            }
+            VariableChange::Changed { old, new } => {
+                let mut details = vec![];
+                if old.value != new.value {
+                    details.push(format!(
+                        "value {} -> {}",
+                        value(old, show_values),
+                        value(new, show_values)
+                    ));
+                }
+                if old.masked != new.masked {
+                    details.push(format!("masked {} -> {}", old.masked, new.masked));
+                }
+                if old.protected != new.protected {
+                    details.push(format!("protected {} -> {}", old.protected, new.protected));
+                }
+                if old.raw != new.raw {
+                    details.push(format!("raw {} -> {}", old.raw, new.raw));
+                }
+                if old.description != new.description {
+                    details.push("description changed".to_string());
+                }
+                lines.push(format!("~ {}: {}", label(new), details.join(", ")));
+            }
+        }
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(key: &str, value: &str) -> GitlabVariable {
+        GitlabVariable {
+            key: key.to_string(),
+            value: value.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn updated(key: &str, value: &str, updated_at: &str) -> GitlabVariable {
+        GitlabVariable {
+            updated_at: Some(updated_at.to_string()),
+            ..var(key, value)
+        }
+    }
+
+    #[test]
+    fn test_changed_since_timestamps() {
+        // 2024-01-01T00:00:00Z
+        let since = 1704067200;
+        let live = vec![
+            updated("OLD", "1", "2023-12-31T23:59:59Z"),
+            updated("NEW", "2", "2024-01-01T00:00:01.000Z"),
+        ];
+        // the timestamps win over the snapshot
+        let snapshot = Snapshot {
+            fetched_at: since,
+            variables: &[],
+        };
+        let expected = vec![live[1].clone()];
+        assert_eq!(changed_since(&live, since, Some(&snapshot)), expected);
+        assert_eq!(changed_since(&live, since, None), expected);
+    }
+
+    #[test]
+    fn test_changed_since_snapshot() {
+        let cached = vec![var("SAME", "1"), var("EDITED", "old"), var("REMOVED", "x")];
+        let live = vec![var("SAME", "1"), var("EDITED", "new"), var("ADDED", "y")];
+        let snapshot = Snapshot {
+            fetched_at: 100,
+            variables: &cached,
+        };
+        assert_eq!(
+            changed_since(&live, 100, Some(&snapshot)),
+            vec![var("EDITED", "new"), var("ADDED", "y")]
+        );
+        // nothing to go by, everything is kept
+        assert_eq!(changed_since(&live, 100, None), live);
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut scoped = var("HOST", "prod.example.com");
+        scoped.environment_scope = Some("production".to_string());
+        let mut masked = var("TOKEN", "secret12");
+        masked.masked = true;
+
+        let old = vec![
+            var("HOST", "localhost"),
+            scoped.clone(),
+            var("REMOVED", "gone"),
+            var("TOKEN", "secret12"),
+            var("UNCHANGED", "same"),
+        ];
+        let new = vec![
+            var("ADDED", "new"),
+            var("HOST", "127.0.0.1"),
+            scoped,
+            masked.clone(),
+            var("UNCHANGED", "same"),
+        ];
+
+        let changes = diff(&old, &new);
+        assert_eq!(
+            changes,
+            vec![
+                VariableChange::Added(var("ADDED", "new")),
+                VariableChange::Changed {
+                    old: var("HOST", "localhost"),
+                    new: var("HOST", "127.0.0.1"),
+                },
+                VariableChange::Removed(var("REMOVED", "gone")),
+                VariableChange::Changed {
+                    old: var("TOKEN", "secret12"),
+                    new: masked,
+                },
+            ]
+        );
+        assert!(diff(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_render() {
+        let mut masked = var("TOKEN", "secret12");
+        masked.masked = true;
+        let changes = diff(
+            &[var("HOST", "localhost"), var("TOKEN", "secret12")],
+            &[var("HOST", "127.0.0.1"), masked, var("NEW", "value")],
+        );
+
+        assert_eq!(
+            render(&changes, false),
+            "~ HOST: value **** -> ****\n+ NEW=****\n~ TOKEN: masked false -> true"
+        );
+        assert_eq!(
+            render(&changes, true),
+            "~ HOST: value localhost -> 127.0.0.1\n+ NEW=value\n~ TOKEN: masked false -> true"
+        );
+    }
+}
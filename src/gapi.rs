@@ -1,20 +1,124 @@
-use crate::uri_meta::UriMeta;
+use crate::uri_meta::{Resource, UriMeta};
 use anyhow::Context;
-use reqwest::{header, Client, Url};
+use reqwest::{header, redirect, Client, RequestBuilder, Response, Url};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
 
 #[derive(Clone)]
 pub struct GApi {
+    scheme: String,
     domain: String,
-    auth_token: String,
+    auth_token: AuthToken,
     client: Client,
+    settings: ClientSettings,
+    // where the rest api is mounted, relative to the domain
+    api_path: String,
+    group_store: Option<Arc<dyn GroupStore>>,
+    user_store: Option<Arc<dyn UserStore>>,
+    // shared between clones
+    page_memo: Option<Arc<Mutex<PageMemo>>>,
+}
+
+// paginated listings by url
+type PageMemo = HashMap<String, Vec<serde_json::Value>>;
+
+/// Somewhere group listings can be kept between resolutions, so resolving
+/// several paths on one instance doesn't list every group each time.
+pub trait GroupStore: Send + Sync {
+    /// The groups listed for `domain`, unless missing or stale.
+    fn get(&self, domain: &str) -> Option<Vec<GitlabResourceMeta>>;
+    fn put(&self, domain: &str, groups: &[GitlabResourceMeta]);
+}
+
+/// Somewhere the user a token belongs to can be kept, so it needn't be
+/// fetched again to tell who is logged in or whether the token works.
+pub trait UserStore: Send + Sync {
+    /// The user `token` authenticates as on `domain`, unless missing, stale
+    /// or stored for another token.
+    fn get(&self, domain: &str, token: &str) -> Option<GitlabUser>;
+    fn put(&self, domain: &str, token: &str, user: &GitlabUser);
+}
+
+pub const DEFAULT_API_PATH: &str = "api/v4";
+
+/// Sent unless configured otherwise, so admins can tell the tool apart.
+pub const USER_AGENT: &str = concat!("ruggit/", env!("CARGO_PKG_VERSION"));
+
+/// Tracing target of the decisions taken resolving a resource, each event
+/// has a `step` naming the decision.
+pub const EXPLAIN: &str = "ruggit::explain";
+
+/// Connection tuning for the underlying http client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientSettings {
+    pub user_agent: String,
+    // idle connections kept open per host for reuse
+    pub pool_max_idle_per_host: usize,
+    // talk HTTP/2 without negotiating, only for instances known to support it
+    pub http2_prior_knowledge: bool,
+    // page size of listings, gitlab's own default is 20
+    pub per_page: Option<u32>,
+    // requests in flight at once when fetching for many resources
+    pub jobs: usize,
+}
+
+/// Resources fetched for at once unless configured otherwise, few enough
+/// not to hammer an instance.
+pub const DEFAULT_JOBS: usize = 4;
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        Self {
+            user_agent: USER_AGENT.to_string(),
+            pool_max_idle_per_host: usize::MAX,
+            http2_prior_knowledge: false,
+            per_page: None,
+            jobs: DEFAULT_JOBS,
+        }
+    }
+}
+
+impl ClientSettings {
+    pub(crate) fn build(&self) -> anyhow::Result<Client> {
+        let mut builder = Client::builder()
+            .redirect(redirect_policy())
+            .user_agent(&self.user_agent)
+            .pool_max_idle_per_host(self.pool_max_idle_per_host);
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        builder.build().context("failed to build http client")
+    }
+}
+
+/// What a [`GitlabResource`] is, which decides the endpoints it has.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResourceKind {
+    Project,
+    Group,
+}
+
+impl std::fmt::Display for ResourceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Project => "project",
+            Self::Group => "group",
+        };
+        f.write_str(name)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct GitlabResource {
     url: String,
-    auth_token: String,
+    kind: ResourceKind,
+    auth_token: AuthToken,
     client: Client,
+    per_page: Option<u32>,
     pub meta: GitlabResourceMeta,
 }
 
@@ -25,13 +129,276 @@ pub struct GitlabResourceMeta {
     pub full_path: Option<String>,
     // exists only for repos
     pub path_with_namespace: Option<String>,
+    // absent from caches written before these were captured
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    // only projects have topics
+    #[serde(default)]
+    pub topics: Vec<String>,
 }
 
-#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+impl GitlabResourceMeta {
+    /// One line summary: the path followed by the name and description
+    /// when known.
+    pub fn describe(&self) -> String {
+        let path = self
+            .full_path
+            .as_deref()
+            .or(self.path_with_namespace.as_deref())
+            .unwrap_or_default();
+        let mut line = path.to_string();
+        if let Some(name) = &self.name {
+            line.push_str(&format!(" ({name})"));
+        }
+        match self.description.as_deref() {
+            Some(description) if !description.is_empty() => {
+                line.push_str(&format!(": {description}"))
+            }
+            _ => (),
+        }
+        line
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, PartialEq)]
 pub struct GitlabVariable {
     pub key: String,
+    // empty for hidden variables, see `readable_value`
+    #[serde(default, deserialize_with = "null_as_empty")]
     pub value: String,
     pub description: Option<String>,
+    // "*" when the variable applies to every environment
+    #[serde(default)]
+    pub environment_scope: Option<String>,
+    #[serde(default)]
+    pub masked: bool,
+    #[serde(default)]
+    pub protected: bool,
+    // only some instances report it, rfc3339
+    #[serde(default)]
+    pub updated_at: Option<String>,
+    // the api never returns the value of hidden variables
+    #[serde(default, alias = "masked_and_hidden")]
+    pub hidden: bool,
+    // `$OTHER` references in the value are kept as they are
+    #[serde(default)]
+    pub raw: bool,
+}
+
+#[cfg(feature = "graphql")]
+const GRAPHQL_VARIABLES: &str = "query($fullPath: ID!, $after: String) {
+  project(fullPath: $fullPath) {
+    ciVariables(first: 100, after: $after) {
+      nodes { key value description environmentScope masked protected raw hidden }
+      pageInfo { hasNextPage endCursor }
+    }
+  }
+}";
+
+#[cfg(feature = "graphql")]
+#[derive(Deserialize)]
+struct GraphqlResponse {
+    data: Option<GraphqlData>,
+    errors: Option<Vec<GraphqlError>>,
+}
+
+#[cfg(feature = "graphql")]
+#[derive(Deserialize)]
+struct GraphqlError {
+    message: String,
+}
+
+#[cfg(feature = "graphql")]
+#[derive(Deserialize)]
+struct GraphqlData {
+    project: Option<GraphqlProject>,
+}
+
+#[cfg(feature = "graphql")]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphqlProject {
+    ci_variables: GraphqlVariables,
+}
+
+#[cfg(feature = "graphql")]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphqlVariables {
+    nodes: Vec<GraphqlVariable>,
+    page_info: GraphqlPageInfo,
+}
+
+#[cfg(feature = "graphql")]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphqlPageInfo {
+    has_next_page: bool,
+    end_cursor: Option<String>,
+}
+
+// a node of `ciVariables`, the same fields as the rest api in camel case
+#[cfg(feature = "graphql")]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphqlVariable {
+    key: String,
+    #[serde(default, deserialize_with = "null_as_empty")]
+    value: String,
+    description: Option<String>,
+    environment_scope: Option<String>,
+    #[serde(default)]
+    masked: bool,
+    #[serde(default)]
+    protected: bool,
+    #[serde(default)]
+    raw: bool,
+    #[serde(default)]
+    hidden: bool,
+}
+
+#[cfg(feature = "graphql")]
+impl From<GraphqlVariable> for GitlabVariable {
+    fn from(v: GraphqlVariable) -> Self {
+        Self {
+            key: v.key,
+            value: v.value,
+            description: v.description,
+            environment_scope: v.environment_scope,
+            masked: v.masked,
+            protected: v.protected,
+            raw: v.raw,
+            hidden: v.hidden,
+            updated_at: None,
+        }
+    }
+}
+
+fn null_as_empty<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+impl GitlabVariable {
+    /// The value, unless the variable is hidden and it can't be read.
+    pub fn readable_value(&self) -> Option<&str> {
+        match self.hidden {
+            true => None,
+            false => Some(&self.value),
+        }
+    }
+
+    /// The environment scope, variables without one apply everywhere.
+    pub fn scope(&self) -> &str {
+        self.environment_scope.as_deref().unwrap_or("*")
+    }
+}
+
+/// Whether gitlab accepts a token.
+#[derive(Serialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenStatus {
+    Valid,
+    Invalid,
+    Expired,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("unauthorized (401), the token might have expired or been revoked")]
+    Unauthorized,
+    #[error("access to {0} denied (403), the token might lack the api scope or role")]
+    Forbidden(String),
+    #[error("timed out after {0}s, try the full project path to avoid scanning every group")]
+    Timeout(f32),
+    #[error("{0} redirects to {1}, use {1} as the domain instead")]
+    Redirected(String, String),
+    #[error("{0} is in maintenance or read-only mode, try again later")]
+    Maintenance(String),
+    #[error("{0} is unavailable (503), try again later")]
+    Unavailable(String),
+}
+
+/// Projects partially match a path none matches exactly, whether and which
+/// one was meant is up to the caller, even for a single candidate, see
+/// [`GApi::project_resource`].
+#[derive(Debug, thiserror::Error)]
+#[error("no exact match for {path}, candidates: {}", candidate_paths(.candidates).join(", "))]
+pub struct Ambiguous {
+    pub path: String,
+    pub candidates: Vec<GitlabResourceMeta>,
+}
+
+fn candidate_paths(candidates: &[GitlabResourceMeta]) -> Vec<&str> {
+    candidates
+        .iter()
+        .filter_map(|p| p.path_with_namespace.as_deref())
+        .collect()
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum MaskError {
+    #[error("masked values must be at least {MIN_MASKED_LEN} characters")]
+    TooShort,
+    #[error("masked values must be a single line")]
+    MultiLine,
+    #[error("masked values can't contain whitespace")]
+    Whitespace,
+    #[error("masked values can't contain '{0}'")]
+    InvalidChar(char),
+}
+
+const MIN_MASKED_LEN: usize = 8;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum KeyError {
+    #[error("variable keys can't be empty")]
+    Empty,
+    #[error("variable key {0} is longer than {MAX_KEY_LEN} characters")]
+    TooLong(String),
+    #[error("variable key {0} can't contain '{1}', only letters, digits and '_' are allowed")]
+    InvalidChar(String, char),
+}
+
+const MAX_KEY_LEN: usize = 255;
+
+/// Checks `key` against the charset gitlab allows for variable keys.
+pub fn validate_key(key: &str) -> Result<(), KeyError> {
+    if key.is_empty() {
+        return Err(KeyError::Empty);
+    }
+    if let Some(c) = key
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && *c != '_')
+    {
+        return Err(KeyError::InvalidChar(key.to_string(), c));
+    }
+    if key.len() > MAX_KEY_LEN {
+        return Err(KeyError::TooLong(key.to_string()));
+    }
+    Ok(())
+}
+
+/// Checks `value` against the rules gitlab applies to masked variables,
+/// catching the failure before it turns into a 400.
+pub fn validate_maskable(value: &str) -> Result<(), MaskError> {
+    if value.contains(['\n', '\r']) {
+        return Err(MaskError::MultiLine);
+    }
+    if value.chars().any(char::is_whitespace) {
+        return Err(MaskError::Whitespace);
+    }
+    if let Some(c) = value
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && !"@:.~+/=-_".contains(*c))
+    {
+        return Err(MaskError::InvalidChar(c));
+    }
+    if value.chars().count() < MIN_MASKED_LEN {
+        return Err(MaskError::TooShort);
+    }
+    Ok(())
 }
 
 async fn get_page(
@@ -40,131 +407,2749 @@ async fn get_page(
     page: u32,
     headers: header::HeaderMap,
 ) -> anyhow::Result<String> {
-    let url = url
-        .clone()
-        .join(&format!("?page={}", page))
-        .context("failed to create paged url")?;
-    let resp = client.get(url).headers(headers.clone()).send().await?;
+    let mut url = url.clone();
+    // keep any query the caller already set, e.g. include_subgroups
+    url.query_pairs_mut().append_pair("page", &page.to_string());
+    let resp = client
+        .get(url.clone())
+        .headers(headers.clone())
+        .send()
+        .await?;
 
-    Ok(resp.text().await?)
+    Ok(check_status(resp, &url).await?.text().await?)
 }
-async fn get_all_pages<T: DeserializeOwned>(
+
+const BROWSE_RETRIES: u32 = 2;
+
+/// A page [`get_all_pages_best_effort`] failed to fetch or parse.
+#[derive(Debug)]
+pub struct PageError {
+    pub page: u32,
+    pub error: anyhow::Error,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+pub struct GitlabTrigger {
+    pub id: u32,
+    pub description: Option<String>,
+    pub token: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, PartialEq)]
+pub struct GitlabUser {
+    pub id: u32,
+    pub username: String,
+    #[serde(default)]
+    pub name: String,
+}
+
+/// A deploy token as listed, gitlab never returns the value after creation.
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+pub struct GitlabDeployToken {
+    pub id: u32,
+    pub name: String,
+    pub username: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    pub expires_at: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+pub struct GitlabAccessToken {
+    pub id: u32,
+    pub name: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    pub expires_at: Option<String>,
+    #[serde(default)]
+    pub active: bool,
+    // only ever returned once, when the token is created or rotated
+    pub token: Option<String>,
+}
+
+/// Follows redirects within the same host and port, and from http to https
+/// on the default ports. Anything pointing elsewhere is not followed, the
+/// token would be sent to a host the user never configured, or in the clear.
+fn redirect_policy() -> redirect::Policy {
+    redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() >= 10 {
+            return attempt.error("too many redirects");
+        }
+        let from = &attempt.previous()[0];
+        if followable(from, attempt.url()) {
+            return attempt.follow();
+        }
+        tracing::warn!(
+            "{} redirects to {}, consider using it as the domain",
+            origin(from, attempt.url()),
+            origin(attempt.url(), from)
+        );
+        attempt.stop()
+    })
+}
+
+// same host, and either the same scheme and port or an upgrade to https
+fn followable(from: &Url, to: &Url) -> bool {
+    if from.host_str() != to.host_str() {
+        return false;
+    }
+    match (from.scheme(), to.scheme()) {
+        (a, b) if a == b => from.port_or_known_default() == to.port_or_known_default(),
+        ("http", "https") => from.port().is_none() && to.port().is_none(),
+        _ => false,
+    }
+}
+
+// `url` as told apart from `other`, the scheme only shown when they differ
+fn origin(url: &Url, other: &Url) -> String {
+    let host = match url.port() {
+        Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+    match url.scheme() == other.scheme() {
+        true => host,
+        false => format!("{}://{host}", url.scheme()),
+    }
+}
+
+// set by instances in maintenance mode, next to the 503
+const MAINTENANCE_HEADER: &str = "x-gitlab-maintenance-mode";
+
+// what a read-only instance, such as a geo secondary, answers writes with
+const READ_ONLY_MESSAGE: &str = "You cannot perform write operations on a read-only instance";
+
+/// Whether a 503 comes from maintenance or a read-only instance rather
+/// than an overloaded or broken one.
+fn is_maintenance(headers: &header::HeaderMap, body: &str) -> bool {
+    headers.contains_key(MAINTENANCE_HEADER) || body.contains(READ_ONLY_MESSAGE)
+}
+
+/// Turns the error statuses into [`ApiError`] where there's advice to give.
+async fn check_status(response: Response, url: &Url) -> anyhow::Result<Response> {
+    let status = response.status();
+    if status == reqwest::StatusCode::SERVICE_UNAVAILABLE {
+        let headers = response.headers().clone();
+        let body = response.text().await.unwrap_or_default();
+        if is_maintenance(&headers, &body) {
+            let host = url.host_str().unwrap_or_default().to_string();
+            return Err(ApiError::Maintenance(host).into());
+        }
+        return Err(ApiError::Unavailable(url.to_string()).into());
+    }
+    if status == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(ApiError::Unauthorized.into());
+    }
+    if status == reqwest::StatusCode::FORBIDDEN {
+        return Err(ApiError::Forbidden(url.to_string()).into());
+    }
+    if status.is_redirection() {
+        // only redirects the policy refuses are left unfollowed
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| url.join(l).ok())
+            .filter(|l| l.host_str().is_some());
+        let Some(location) = location else {
+            anyhow::bail!("{url} redirected without a location")
+        };
+        return Err(ApiError::Redirected(origin(url, &location), origin(&location, url)).into());
+    }
+    Ok(response.error_for_status()?)
+}
+
+async fn send(request: RequestBuilder, auth_token: &AuthToken) -> anyhow::Result<Response> {
+    let response = request.headers(auth_token.header()?).send().await?;
+    let url = response.url().clone();
+    check_status(response, &url).await
+}
+
+async fn send_json<T: DeserializeOwned>(
+    request: RequestBuilder,
+    auth_token: &AuthToken,
+) -> anyhow::Result<T> {
+    let response = send(request, auth_token).await?;
+    Ok(serde_json::from_str(&response.text().await?)?)
+}
+
+fn is_not_found(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+        .is_some_and(|s| s == reqwest::StatusCode::NOT_FOUND)
+}
+
+async fn get_json<T: DeserializeOwned>(
     client: &Client,
     url: Url,
-    auth_token: &str,
-) -> anyhow::Result<Vec<T>> {
-    let mut header = header::HeaderMap::new();
-    header.insert("PRIVATE-TOKEN", header::HeaderValue::from_str(auth_token)?);
+    auth_token: &AuthToken,
+) -> anyhow::Result<T> {
+    send_json(client.get(url), auth_token).await
+}
+
+async fn post_json<T: DeserializeOwned>(
+    client: &Client,
+    url: Url,
+    auth_token: &AuthToken,
+    body: &serde_json::Value,
+) -> anyhow::Result<T> {
+    let request = client
+        .post(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body.to_string());
+    send_json(request, auth_token).await
+}
+
+async fn put_json<T: DeserializeOwned>(
+    client: &Client,
+    url: Url,
+    auth_token: &AuthToken,
+    body: &serde_json::Value,
+) -> anyhow::Result<T> {
+    let request = client
+        .put(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body.to_string());
+    send_json(request, auth_token).await
+}
+
+/// The request body creating `variable`, without a scope gitlab would
+/// create it for every environment.
+fn variable_body(variable: &GitlabVariable) -> serde_json::Value {
+    serde_json::json!({
+        "key": variable.key,
+        "value": variable.value,
+        "description": variable.description,
+        "environment_scope": variable.environment_scope.as_deref().unwrap_or("*"),
+        "masked": variable.masked,
+        "protected": variable.protected,
+        "raw": variable.raw,
+    })
+}
+
+/// A token and how gitlab expects it, oauth access tokens only as a bearer
+/// token and every other kind in the `PRIVATE-TOKEN` header.
+#[derive(Clone, Debug)]
+struct AuthToken {
+    token: String,
+    oauth: bool,
+}
+
+impl AuthToken {
+    fn header(&self) -> anyhow::Result<header::HeaderMap> {
+        let mut header = header::HeaderMap::new();
+        match self.oauth {
+            true => header.insert(
+                header::AUTHORIZATION,
+                header::HeaderValue::from_str(&format!("Bearer {}", self.token))?,
+            ),
+            false => header.insert("PRIVATE-TOKEN", header::HeaderValue::from_str(&self.token)?),
+        };
+        Ok(header)
+    }
+}
 
+/// The number of pages behind `url`, which gets the requested page size.
+async fn page_count(
+    client: &Client,
+    url: &mut Url,
+    header: &header::HeaderMap,
+    per_page: Option<u32>,
+) -> anyhow::Result<u32> {
+    if let Some(per_page) = per_page {
+        url.query_pairs_mut()
+            .append_pair("per_page", &per_page.to_string());
+    }
     let response = client
         .get(url.clone())
         .headers(header.clone())
         .send()
         .await?;
-    let rheaders = response.headers();
-
-    let total_pages = rheaders
+    let response = check_status(response, url).await?;
+    let total_pages = response
+        .headers()
         .get("x-total-pages")
         .context("expected paged result but got something else")?
         .to_str()?
         .parse::<u32>()?;
+    tracing::debug!(total_pages);
+    Ok(total_pages)
+}
+
+/// Fetches `pages` concurrently, each result paired with its page.
+async fn fetch_pages<T: DeserializeOwned>(
+    client: &Client,
+    url: &Url,
+    header: &header::HeaderMap,
+    pages: impl Iterator<Item = u32>,
+) -> Vec<(u32, anyhow::Result<Vec<T>>)> {
     let mut tasks = vec![];
-    for i in 1..=total_pages {
+    for page in pages {
         let url = url.clone();
-        let page = i;
         let header = header.clone();
         let client = client.clone();
-        tasks.push(tokio::spawn(async move {
-            get_page(client, url, page, header).await
-        }));
+        tasks.push((
+            page,
+            tokio::spawn(async move { get_page(client, url, page, header).await }),
+        ));
     }
+    let mut fetched = vec![];
+    for (page, task) in tasks {
+        let parsed = async { Ok(serde_json::from_str::<Vec<T>>(&task.await??)?) };
+        fetched.push((page, parsed.await));
+    }
+    fetched
+}
+
+#[tracing::instrument(skip_all, fields(%url))]
+async fn get_all_pages<T: DeserializeOwned>(
+    client: &Client,
+    mut url: Url,
+    auth_token: &AuthToken,
+    per_page: Option<u32>,
+) -> anyhow::Result<Vec<T>> {
+    let header = auth_token.header()?;
+    let total_pages = page_count(client, &mut url, &header, per_page).await?;
     let mut result = vec![];
-    for task in tasks {
-        result.append(&mut serde_json::from_str::<Vec<T>>(&task.await??)?);
+    for (_, page) in fetch_pages(client, &url, &header, 1..=total_pages).await {
+        result.append(&mut page?);
     }
     Ok(result)
 }
 
+/// Like [`get_all_pages`], but pages failing to load don't fail the rest.
+/// Failed pages are retried up to `retries` times, those still failing are
+/// returned next to what could be fetched.
+#[tracing::instrument(skip_all, fields(%url))]
+async fn get_all_pages_best_effort<T: DeserializeOwned>(
+    client: &Client,
+    mut url: Url,
+    auth_token: &AuthToken,
+    per_page: Option<u32>,
+    retries: u32,
+) -> anyhow::Result<(Vec<T>, Vec<PageError>)> {
+    let header = auth_token.header()?;
+    let total_pages = page_count(client, &mut url, &header, per_page).await?;
+    let mut fetched = vec![];
+    let mut failed = vec![];
+    let mut pending: Vec<u32> = (1..=total_pages).collect();
+    for attempt in 0..=retries {
+        if pending.is_empty() {
+            break;
+        }
+        if attempt > 0 {
+            tracing::debug!(attempt, pages = ?pending, "retrying failed pages");
+        }
+        failed.clear();
+        for (page, result) in fetch_pages(client, &url, &header, pending.drain(..)).await {
+            match result {
+                Ok(items) => fetched.push((page, items)),
+                Err(error) => failed.push(PageError { page, error }),
+            }
+        }
+        pending = failed.iter().map(|f| f.page).collect();
+    }
+    fetched.sort_by_key(|(page, _)| *page);
+    let result = fetched.into_iter().flat_map(|(_, items)| items).collect();
+    Ok((result, failed))
+}
+
+/// Finds the group closest to the resource described by `tokens`, trying the
+/// longest parent path first and working towards the top-level namespace.
+fn containing_group<'a>(
+    groups: &'a [GitlabResourceMeta],
+    tokens: &[String],
+) -> Option<&'a GitlabResourceMeta> {
+    (1..tokens.len()).rev().find_map(|len| {
+        let prefix = tokens[..len].join("/");
+        groups
+            .iter()
+            .find(|g| g.full_path.as_ref().is_some_and(|x| same_path(x, &prefix)))
+    })
+}
+
+/// Gitlab looks paths up case insensitively, though it keeps their casing.
+fn same_path(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+/// https, unless `domain` is a local instance as those rarely have
+/// certificates set up.
+pub fn default_scheme(domain: &str) -> &'static str {
+    let host = match domain.find(']') {
+        Some(end) if domain.starts_with('[') => &domain[..=end],
+        _ => domain.split(':').next().unwrap_or(domain),
+    };
+    match host {
+        "localhost" | "127.0.0.1" | "[::1]" => "http",
+        _ => "https",
+    }
+}
+
+/// Projects whose path starts with `partial`, or if there are none, the
+/// ones containing it.
+fn partial_matches<'a>(
+    projects: &'a [GitlabResourceMeta],
+    partial: &str,
+) -> Vec<&'a GitlabResourceMeta> {
+    let partial = partial.to_lowercase();
+    let paths = || {
+        projects.iter().filter_map(|p| {
+            p.path_with_namespace
+                .as_ref()
+                .map(|path| (p, path.to_lowercase()))
+        })
+    };
+    let prefixed: Vec<_> = paths()
+        .filter(|(_, path)| path.starts_with(&partial))
+        .map(|(p, _)| p)
+        .collect();
+    if !prefixed.is_empty() {
+        return prefixed;
+    }
+    paths()
+        .filter(|(_, path)| path.contains(&partial))
+        .map(|(p, _)| p)
+        .collect()
+}
+
+/// The one project whose last path segment is `name`, listing the
+/// candidates when there are several.
+fn unique_name_match<'a>(
+    projects: &'a [GitlabResourceMeta],
+    name: &str,
+) -> anyhow::Result<&'a GitlabResourceMeta> {
+    let matches: Vec<_> = projects
+        .iter()
+        .filter(|p| {
+            p.path_with_namespace
+                .as_ref()
+                .is_some_and(|path| path.rsplit('/').next().is_some_and(|n| same_path(n, name)))
+        })
+        .collect();
+    match matches.as_slice() {
+        [] => anyhow::bail!("found no gitlab resource"),
+        [project] => Ok(project),
+        _ => {
+            let mut candidates: Vec<_> = matches
+                .iter()
+                .filter_map(|p| p.path_with_namespace.as_deref())
+                .collect();
+            candidates.sort();
+            anyhow::bail!("{name} is ambiguous, candidates: {}", candidates.join(", "))
+        }
+    }
+}
+
+/// Runs `fut` to completion unless `deadline` elapses first.
+pub async fn with_deadline<T>(
+    deadline: Option<Duration>,
+    fut: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    let Some(deadline) = deadline else {
+        return fut.await;
+    };
+    match tokio::time::timeout(deadline, fut).await {
+        Ok(result) => result,
+        Err(_) => Err(ApiError::Timeout(deadline.as_secs_f32()).into()),
+    }
+}
+
 impl GApi {
     pub fn new(domain: &str, token: &str) -> Self {
         Self {
+            scheme: default_scheme(domain).to_string(),
             domain: domain.to_string(),
-            auth_token: token.to_string(),
-            client: Client::new(),
+            auth_token: AuthToken {
+                token: token.to_string(),
+                oauth: false,
+            },
+            client: ClientSettings::default()
+                .build()
+                .expect("default http client"),
+            settings: ClientSettings::default(),
+            api_path: DEFAULT_API_PATH.to_string(),
+            group_store: None,
+            user_store: None,
+            page_memo: None,
+        }
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    /// Keeps every paginated listing fetched through this client, and its
+    /// clones, so asking again doesn't go over the network.
+    pub fn with_page_memo(mut self) -> Self {
+        self.page_memo = Some(Arc::default());
+        self
+    }
+
+    /// Forgets the memoized listings, the next ones are fetched again.
+    pub fn clear_page_memo(&self) {
+        if let Some(memo) = &self.page_memo {
+            memo.lock().unwrap().clear();
+        }
+    }
+
+    /// [`get_all_pages`] through the page memo, when there is one.
+    async fn pages<T: DeserializeOwned>(&self, url: Url) -> anyhow::Result<Vec<T>> {
+        let Some(memo) = &self.page_memo else {
+            return get_all_pages(&self.client, url, &self.auth_token, self.per_page()).await;
+        };
+        let key = url.to_string();
+        let cached = memo.lock().unwrap().get(&key).cloned();
+        let values = match cached {
+            Some(values) => {
+                tracing::debug!(%url, "using memoized pages");
+                values
+            }
+            None => {
+                let values: Vec<serde_json::Value> =
+                    get_all_pages(&self.client, url, &self.auth_token, self.per_page()).await?;
+                memo.lock().unwrap().insert(key, values.clone());
+                values
+            }
+        };
+        Ok(values
+            .into_iter()
+            .map(serde_json::from_value)
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// Consults `store` before listing groups during resolution.
+    pub fn with_group_store(mut self, store: Arc<dyn GroupStore>) -> Self {
+        self.group_store = Some(store);
+        self
+    }
+
+    /// Consults `store` before fetching the user of the token.
+    pub fn with_user_store(mut self, store: Arc<dyn UserStore>) -> Self {
+        self.user_store = Some(store);
+        self
+    }
+
+    /// Mounts the api at `path` instead of `api/v4`, for proxies serving it
+    /// under another prefix.
+    pub fn with_api_path(mut self, path: &str) -> Self {
+        self.api_path = path.trim_matches('/').to_string();
+        self
+    }
+
+    /// Rebuilds the http client with `settings`.
+    pub fn with_client_settings(mut self, settings: ClientSettings) -> anyhow::Result<Self> {
+        self.client = settings.build()?;
+        self.settings = settings;
+        Ok(self)
+    }
+
+    pub fn client_settings(&self) -> &ClientSettings {
+        &self.settings
+    }
+
+    /// Builds the resource straight from its numeric id, skipping any group
+    /// scanning. The meta is only populated with the id.
+    pub fn resource_by_id(&self, id: u32, kind: &Resource) -> GitlabResource {
+        let meta = GitlabResourceMeta {
+            id,
+            ..Default::default()
+        };
+        match kind {
+            Resource::Group => self.group_resource(meta),
+            Resource::Repo => self.project_resource(meta),
         }
     }
 
+    #[tracing::instrument(skip_all, fields(id = ?uri.id))]
+    pub async fn resource_from_id(&self, uri: &UriMeta) -> anyhow::Result<GitlabResource> {
+        let id = uri.id.context("no resource id")?;
+        let kind = uri.resource.as_ref().unwrap_or(&Resource::Repo);
+        let mut resource = self.resource_by_id(id, kind);
+        tracing::info!(target: EXPLAIN, step = "by_id", id, kind = ?kind);
+        resource.meta = get_json(&self.client, Url::parse(&resource.url)?, &self.auth_token)
+            .await
+            .context("failed to fetch resource by id")?;
+        Ok(resource)
+    }
+
+    #[tracing::instrument(skip_all, fields(uri = %uri.url))]
     pub async fn resource_from_uri(&self, uri: &UriMeta) -> anyhow::Result<GitlabResource> {
-        let groups = self.groups().await?;
         let expected_path = uri.tokens.join("/");
-
-        let containing_group = 'a: {
+        // looking the project up directly only requires access to the project
+        // itself, not to every group along its path
+        if uri.resource != Some(Resource::Group) {
+            let project = self.project_by_path(&expected_path).await?;
+            tracing::info!(
+                target: EXPLAIN,
+                step = "direct_lookup",
+                path = expected_path,
+                found = ?project.as_ref().map(|p| p.id),
+            );
+            if let Some(project) = project {
+                // gitlab redirects the old path of a renamed project to it
+                if let Some(moved) = project
+                    .path_with_namespace
+                    .as_deref()
+                    .filter(|p| !same_path(p, &expected_path))
+                {
+                    tracing::warn!("{expected_path} was renamed to {moved}, use the new path");
+                }
+                return Ok(self.project_resource(project));
+            }
+        }
+        let mut relisted = false;
+        let containing_group = 'a: loop {
+            let (groups, stored) = self.cached_groups(relisted).await?;
+            tracing::info!(target: EXPLAIN, step = "list_groups", count = groups.len(), stored);
             for group in groups.iter() {
                 if group
                     .full_path
                     .as_ref()
-                    .is_some_and(|x| *x == expected_path)
+                    .is_some_and(|x| same_path(x, &expected_path))
                 {
                     // early return the requested resource was in fact a group
-                    return Ok(GitlabResource {
-                        url: format!("https://{}/api/v4/groups/{}", self.domain, group.id)
-                            .to_string(),
-                        auth_token: self.auth_token.clone(),
-                        client: self.client.clone(),
-                        meta: group.clone(),
-                    });
+                    tracing::info!(target: EXPLAIN, step = "exact_group", id = group.id);
+                    return Ok(self.group_resource(group.clone()));
                 }
             }
-            let probable_group_path = uri.tokens[..uri.tokens.len() - 1].join("/");
-            for group in groups.into_iter() {
-                if group
-                    .full_path
-                    .as_ref()
-                    .is_some_and(|x| *x == probable_group_path)
-                {
-                    break 'a group;
+            // the listing only holds groups the token is a member of, so a
+            // subgroup reached through an ancestor's membership can be missing
+            if !relisted && uri.resource != Some(Resource::Repo) && uri.tokens.len() > 1 {
+                let group = self.group_by_path(&expected_path).await?;
+                tracing::info!(
+                    target: EXPLAIN,
+                    step = "subgroup_lookup",
+                    path = expected_path,
+                    found = ?group.as_ref().map(|g| g.id),
+                );
+                if let Some(group) = group {
+                    return Ok(self.group_resource(group));
                 }
             }
+            if let Some(group) = containing_group(&groups, &uri.tokens) {
+                tracing::info!(
+                    target: EXPLAIN,
+                    step = "containing_group",
+                    id = group.id,
+                    path = group.full_path.as_deref(),
+                );
+                break 'a group.clone();
+            }
+            // a bare project name, look for it among every accessible project
+            if let (true, [name]) = (uri.resource != Some(Resource::Group), uri.tokens.as_slice()) {
+                let projects = self.search_projects(name).await?;
+                let project = unique_name_match(&projects, name)?;
+                tracing::info!(
+                    target: EXPLAIN,
+                    step = "search",
+                    name,
+                    candidates = projects.len(),
+                    id = project.id,
+                );
+                return Ok(self.project_resource(project.clone()));
+            }
+            // the stored listing may predate the group, list them once more
+            if stored {
+                relisted = true;
+                continue;
+            }
             anyhow::bail!("no containing group found")
         };
 
-        // we've got a group that should contain a project macthing the expected_path
-        let projects = self.projects(containing_group.id).await?;
-        for project in projects.into_iter() {
+        // we've got a group that should contain a project macthing the expected_path,
+        // though it might be further up than the immediate parent, in which case
+        // the project lives in one of its subgroups
+        let nested = containing_group
+            .full_path
+            .as_ref()
+            .is_some_and(|x| x.split('/').count() < uri.tokens.len() - 1);
+        let projects = self.projects(containing_group.id, nested, None).await?;
+        tracing::info!(
+            target: EXPLAIN,
+            step = "list_projects",
+            group = containing_group.id,
+            nested,
+            count = projects.len(),
+        );
+        for project in projects.iter() {
             if project
                 .path_with_namespace
                 .as_ref()
-                .is_some_and(|x| *x == expected_path)
+                .is_some_and(|x| same_path(x, &expected_path))
             {
-                return Ok(GitlabResource {
-                    url: format!("https://{}/api/v4/projects/{}", self.domain, project.id)
-                        .to_string(),
-                    auth_token: self.auth_token.clone(),
-                    client: self.client.clone(),
-                    meta: project.clone(),
-                });
+                tracing::info!(target: EXPLAIN, step = "project_in_group", id = project.id);
+                return Ok(self.project_resource(project.clone()));
+            }
+        }
+
+        // no exact match, the path might be abbreviated
+        let candidates = partial_matches(&projects, &expected_path);
+        tracing::info!(
+            target: EXPLAIN,
+            step = "partial_match",
+            candidates = candidates.len(),
+        );
+        match candidates.as_slice() {
+            [] => anyhow::bail!("found no gitlab resource"),
+            // a typo mustn't silently pick another project
+            _ => Err(Ambiguous {
+                path: expected_path,
+                candidates: candidates.into_iter().cloned().collect(),
             }
+            .into()),
         }
-        anyhow::bail!("found no gitlab resource")
     }
 
-    async fn groups(&self) -> anyhow::Result<Vec<GitlabResourceMeta>> {
-        let url = Url::parse(&format!("https://{}/api/v4/groups", self.domain))?;
-        get_all_pages::<GitlabResourceMeta>(&self.client, url, &self.auth_token).await
+    /// The user the token belongs to, from the user store while fresh.
+    pub async fn whoami(&self) -> anyhow::Result<GitlabUser> {
+        if let Some(user) = self.stored_user() {
+            return Ok(user);
+        }
+        let url = Url::parse(&format!("{}/user", self.api_base()))?;
+        let user: GitlabUser = get_json(&self.client, url, &self.auth_token).await?;
+        if let Some(store) = &self.user_store {
+            store.put(&self.domain, &self.auth_token.token, &user);
+        }
+        Ok(user)
     }
 
-    pub async fn projects(&self, group_id: u32) -> anyhow::Result<Vec<GitlabResourceMeta>> {
-        let url = Url::parse(&format!(
-            "https://{}/api/v4/groups/{}/projects",
-            self.domain, group_id
-        ))?;
-        get_all_pages::<GitlabResourceMeta>(&self.client, url, &self.auth_token).await
+    fn stored_user(&self) -> Option<GitlabUser> {
+        let user = self
+            .user_store
+            .as_ref()?
+            .get(&self.domain, &self.auth_token.token)?;
+        tracing::debug!(user.username, "using cached user");
+        Some(user)
     }
-}
 
-impl GitlabResource {
-    pub async fn variables(&self) -> anyhow::Result<Vec<GitlabVariable>> {
-        let url = Url::parse(&(self.url.clone() + "/variables"))?;
-        get_all_pages::<GitlabVariable>(&self.client, url, &self.auth_token).await
+    /// The access token the client authenticates with, personal, project
+    /// and group access tokens alike.
+    pub async fn current_token(&self) -> anyhow::Result<GitlabAccessToken> {
+        let url = Url::parse(&format!("{}/personal_access_tokens/self", self.api_base()))?;
+        get_json(&self.client, url, &self.auth_token).await
+    }
+
+    /// Checks the token by fetching the user it belongs to, a user stored
+    /// for the token is taken as proof enough.
+    pub async fn validate_token(&self) -> anyhow::Result<TokenStatus> {
+        if self.stored_user().is_some() {
+            return Ok(TokenStatus::Valid);
+        }
+        self.token_status().await
+    }
+
+    /// Like [`validate_token`](Self::validate_token), always asking gitlab,
+    /// as once it refused the token a stored user proves nothing.
+    pub async fn token_status(&self) -> anyhow::Result<TokenStatus> {
+        let response = self
+            .client
+            .get(format!("{}/user", self.api_base()))
+            .headers(self.auth_token.header()?)
+            .send()
+            .await?;
+        if response.status() != reqwest::StatusCode::UNAUTHORIZED {
+            let response = response.error_for_status()?;
+            // fetched anyway, keep it for the next check
+            if let Some(store) = &self.user_store {
+                let body = response.text().await.unwrap_or_default();
+                if let Ok(user) = serde_json::from_str::<GitlabUser>(&body) {
+                    store.put(&self.domain, &self.auth_token.token, &user);
+                }
+            }
+            return Ok(TokenStatus::Valid);
+        }
+        // gitlab tells expired tokens apart in the error description
+        let body = response.text().await.unwrap_or_default();
+        match body.contains("expired") {
+            true => Ok(TokenStatus::Expired),
+            false => Ok(TokenStatus::Invalid),
+        }
+    }
+
+    /// Presents the token as an oauth access token, which gitlab only takes
+    /// as a bearer token.
+    pub fn with_oauth(mut self) -> Self {
+        self.auth_token.oauth = true;
+        self
+    }
+
+    pub fn with_scheme(mut self, scheme: &str) -> Self {
+        self.scheme = scheme.to_string();
+        self
+    }
+
+    fn api_base(&self) -> String {
+        format!("{}://{}/{}", self.scheme, self.domain, self.api_path)
+    }
+
+    fn group_resource(&self, meta: GitlabResourceMeta) -> GitlabResource {
+        GitlabResource {
+            url: format!("{}/groups/{}", self.api_base(), meta.id),
+            kind: ResourceKind::Group,
+            auth_token: self.auth_token.clone(),
+            client: self.client.clone(),
+            per_page: self.settings.per_page,
+            meta,
+        }
+    }
+
+    /// The project `meta` describes, as picked among [`Ambiguous`] candidates.
+    pub fn project_resource(&self, meta: GitlabResourceMeta) -> GitlabResource {
+        GitlabResource {
+            url: format!("{}/projects/{}", self.api_base(), meta.id),
+            kind: ResourceKind::Project,
+            auth_token: self.auth_token.clone(),
+            client: self.client.clone(),
+            per_page: self.settings.per_page,
+            meta,
+        }
+    }
+
+    fn per_page(&self) -> Option<u32> {
+        self.settings.per_page
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn project_by_path(&self, path: &str) -> anyhow::Result<Option<GitlabResourceMeta>> {
+        let mut url = Url::parse(&format!("{}/projects", self.api_base()))?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("invalid api url"))?
+            .push(path);
+        match get_json(&self.client, url, &self.auth_token).await {
+            Ok(project) => Ok(Some(project)),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn group_by_path(&self, path: &str) -> anyhow::Result<Option<GitlabResourceMeta>> {
+        let mut url = Url::parse(&format!("{}/groups", self.api_base()))?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("invalid api url"))?
+            .push(path);
+        match get_json(&self.client, url, &self.auth_token).await {
+            Ok(group) => Ok(Some(group)),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether the resource described by `meta` can still be found under
+    /// its path, renamed and deleted ones can't. The old path of a renamed
+    /// resource redirects to the new one, which doesn't count.
+    pub async fn still_exists(&self, meta: &GitlabResourceMeta) -> anyhow::Result<bool> {
+        let found = match (&meta.full_path, &meta.path_with_namespace) {
+            (Some(path), _) => self
+                .group_by_path(path)
+                .await?
+                .and_then(|g| g.full_path)
+                .is_some_and(|p| same_path(&p, path)),
+            (None, Some(path)) => self
+                .project_by_path(path)
+                .await?
+                .and_then(|p| p.path_with_namespace)
+                .is_some_and(|p| same_path(&p, path)),
+            (None, None) => anyhow::bail!("gitlab resource with no path"),
+        };
+        Ok(found)
+    }
+
+    pub async fn groups(&self) -> anyhow::Result<Vec<GitlabResourceMeta>> {
+        let url = Url::parse(&format!("{}/groups", self.api_base()))?;
+        self.pages(url).await
+    }
+
+    /// Like [`GApi::groups`] but served from the group store while fresh,
+    /// unless `relist` is set, along with whether they came from the store.
+    async fn cached_groups(&self, relist: bool) -> anyhow::Result<(Vec<GitlabResourceMeta>, bool)> {
+        let Some(store) = &self.group_store else {
+            return Ok((self.groups().await?, false));
+        };
+        if let Some(groups) = store.get(&self.domain).filter(|_| !relist) {
+            tracing::debug!(count = groups.len(), "using cached groups");
+            return Ok((groups, true));
+        }
+        let groups = self.groups().await?;
+        store.put(&self.domain, &groups);
+        Ok((groups, false))
+    }
+
+    /// Every accessible group ordered by path, with its direct projects when
+    /// `include_projects` is set.
+    pub async fn browse(
+        &self,
+        include_projects: bool,
+    ) -> anyhow::Result<Vec<(GitlabResourceMeta, Vec<GitlabResourceMeta>)>> {
+        // partial listings are still useful for discovery
+        let url = Url::parse(&format!("{}/groups", self.api_base()))?;
+        let (mut groups, failed) = get_all_pages_best_effort::<GitlabResourceMeta>(
+            &self.client,
+            url,
+            &self.auth_token,
+            self.per_page(),
+            BROWSE_RETRIES,
+        )
+        .await?;
+        for failure in failed {
+            tracing::warn!("skipped page {} of groups: {}", failure.page, failure.error);
+        }
+        groups.sort_by(|a, b| a.full_path.cmp(&b.full_path));
+        if !include_projects {
+            return Ok(groups.into_iter().map(|g| (g, vec![])).collect());
+        }
+        let permits = Arc::new(Semaphore::new(self.settings.jobs.max(1)));
+        let mut tasks = vec![];
+        for group in &groups {
+            let api = self.clone();
+            let id = group.id;
+            let permits = permits.clone();
+            tasks.push(tokio::spawn(async move {
+                let _permit = permits.acquire().await?;
+                api.projects(id, false, None).await
+            }));
+        }
+        let mut browsed = vec![];
+        for (group, task) in groups.into_iter().zip(tasks) {
+            let mut projects = task.await??;
+            projects.sort_by(|a, b| a.path_with_namespace.cmp(&b.path_with_namespace));
+            browsed.push((group, projects));
+        }
+        Ok(browsed)
+    }
+
+    /// Accessible projects matching `search`, gitlab matches it anywhere in
+    /// the name or path.
+    async fn search_projects(&self, search: &str) -> anyhow::Result<Vec<GitlabResourceMeta>> {
+        let mut url = Url::parse(&format!("{}/projects", self.api_base()))?;
+        url.query_pairs_mut()
+            .append_pair("search", search)
+            .append_pair("membership", "true")
+            .append_pair("simple", "true");
+        self.pages(url).await
+    }
+
+    /// The projects of group `group_id`, only those tagged with `topic`
+    /// when given.
+    pub async fn projects(
+        &self,
+        group_id: u32,
+        include_subgroups: bool,
+        topic: Option<&str>,
+    ) -> anyhow::Result<Vec<GitlabResourceMeta>> {
+        let mut url = Url::parse(&format!("{}/groups/{}/projects", self.api_base(), group_id))?;
+        if include_subgroups {
+            url.query_pairs_mut()
+                .append_pair("include_subgroups", "true");
+        }
+        if let Some(topic) = topic {
+            url.query_pairs_mut().append_pair("topic", topic);
+        }
+        let mut projects: Vec<GitlabResourceMeta> = self.pages(url).await?;
+        // instances predating the filter ignore it
+        if let Some(topic) = topic {
+            projects.retain(|p| p.topics.iter().any(|t| t.eq_ignore_ascii_case(topic)));
+        }
+        Ok(projects)
+    }
+
+    /// The groups above the resource described by `meta`, outermost first,
+    /// each with its variables. Listing a group's variables takes the
+    /// maintainer role, groups without it are skipped.
+    pub async fn ancestor_variables(
+        &self,
+        meta: &GitlabResourceMeta,
+    ) -> anyhow::Result<Vec<(GitlabResourceMeta, Vec<GitlabVariable>)>> {
+        let path = meta
+            .full_path
+            .as_deref()
+            .or(meta.path_with_namespace.as_deref())
+            .context("gitlab resource with no path")?;
+        let tokens: Vec<&str> = path.split('/').collect();
+        let mut ancestors = vec![];
+        for depth in 1..tokens.len() {
+            let ancestor = tokens[..depth].join("/");
+            let Some(group) = self.group_by_path(&ancestor).await? else {
+                tracing::debug!(ancestor, "ancestor group not found");
+                continue;
+            };
+            let group = self.group_resource(group);
+            match group.variables().await {
+                Ok(variables) => ancestors.push((group.meta, variables)),
+                Err(e) if matches!(e.downcast_ref(), Some(ApiError::Forbidden(_))) => {
+                    tracing::warn!("skipped the variables of {ancestor}, not a maintainer");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(ancestors)
+    }
+
+    /// The projects of group `group_id` and its subgroups tagged with
+    /// `topic`, each with its variables, ordered by path.
+    pub async fn variables_by_topic(
+        &self,
+        group_id: u32,
+        topic: &str,
+    ) -> anyhow::Result<Vec<(GitlabResourceMeta, Vec<GitlabVariable>)>> {
+        let mut projects = self.projects(group_id, true, Some(topic)).await?;
+        projects.sort_by(|a, b| a.path_with_namespace.cmp(&b.path_with_namespace));
+        let permits = Arc::new(Semaphore::new(self.settings.jobs.max(1)));
+        let mut tasks = vec![];
+        for project in projects {
+            let resource = self.project_resource(project);
+            let permits = permits.clone();
+            tasks.push(tokio::spawn(async move {
+                let _permit = permits.acquire().await?;
+                let variables = resource.variables().await?;
+                anyhow::Ok((resource.meta, variables))
+            }));
+        }
+        let mut found = vec![];
+        for task in tasks {
+            found.push(task.await??);
+        }
+        Ok(found)
+    }
+}
+
+impl GitlabResource {
+    /// The API endpoint this resource was resolved to.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn kind(&self) -> ResourceKind {
+        self.kind
+    }
+
+    /// The variables endpoint, which projects and groups alike have.
+    pub fn variables_url(&self) -> String {
+        self.url.clone() + "/variables"
+    }
+
+    pub async fn access_tokens(&self) -> anyhow::Result<Vec<GitlabAccessToken>> {
+        let url = Url::parse(&(self.url.clone() + "/access_tokens"))?;
+        get_all_pages::<GitlabAccessToken>(&self.client, url, &self.auth_token, self.per_page).await
+    }
+
+    /// Revokes the access token `id` and issues a new one, the returned
+    /// token value can't be retrieved again.
+    pub async fn rotate_access_token(&self, id: u32) -> anyhow::Result<GitlabAccessToken> {
+        let url = Url::parse(&format!("{}/access_tokens/{}/rotate", self.url, id))?;
+        post_json(&self.client, url, &self.auth_token, &serde_json::json!({})).await
+    }
+
+    /// Deploy tokens of the resource, listing them takes the maintainer role.
+    pub async fn deploy_tokens(&self) -> anyhow::Result<Vec<GitlabDeployToken>> {
+        let url = Url::parse(&(self.url.clone() + "/deploy_tokens"))?;
+        get_all_pages::<GitlabDeployToken>(&self.client, url, &self.auth_token, self.per_page)
+            .await
+            .map_err(|e| match e.downcast_ref::<ApiError>() {
+                Some(ApiError::Forbidden(_)) => {
+                    e.context("listing deploy tokens requires at least the maintainer role")
+                }
+                _ => e,
+            })
+    }
+
+    /// The resource as gitlab returns it, with the fields
+    /// [`GitlabResourceMeta`] leaves out.
+    pub async fn raw(&self) -> anyhow::Result<serde_json::Value> {
+        get_json(&self.client, Url::parse(&self.url)?, &self.auth_token).await
+    }
+
+    /// Pipeline trigger tokens, only projects have them.
+    pub async fn triggers(&self) -> anyhow::Result<Vec<GitlabTrigger>> {
+        if self.kind != ResourceKind::Project {
+            anyhow::bail!("{}s have no trigger tokens", self.kind)
+        }
+        let url = Url::parse(&(self.url.clone() + "/triggers"))?;
+        get_all_pages::<GitlabTrigger>(&self.client, url, &self.auth_token, self.per_page).await
+    }
+
+    pub async fn variables(&self) -> anyhow::Result<Vec<GitlabVariable>> {
+        let url = Url::parse(&self.variables_url())?;
+        #[cfg(feature = "graphql")]
+        if let Some(path) = &self.meta.path_with_namespace {
+            match self.graphql_variables(path).await {
+                Ok(variables) => return Ok(variables),
+                Err(e) => tracing::warn!("graphql failed, falling back to rest: {e:#}"),
+            }
+        }
+        get_all_pages::<GitlabVariable>(&self.client, url, &self.auth_token, self.per_page).await
+    }
+
+    /// The graphql endpoint next to the rest api, `/api/graphql` for the
+    /// default `/api/v4`.
+    #[cfg(feature = "graphql")]
+    fn graphql_url(&self) -> anyhow::Result<Url> {
+        let (api, _) = self
+            .url
+            .rsplit_once("/projects/")
+            .context("graphql is only used for projects")?;
+        let base = api.strip_suffix("/v4").unwrap_or(api);
+        Ok(Url::parse(&format!("{base}/graphql"))?)
+    }
+
+    /// The variables of the project at `path`, paging by cursor through the
+    /// graphql api rather than by offset.
+    #[cfg(feature = "graphql")]
+    async fn graphql_variables(&self, path: &str) -> anyhow::Result<Vec<GitlabVariable>> {
+        let url = self.graphql_url()?;
+        let mut variables = vec![];
+        let mut after: Option<String> = None;
+        loop {
+            let body = serde_json::json!({
+                "query": GRAPHQL_VARIABLES,
+                "variables": {"fullPath": path, "after": after},
+            });
+            let response: GraphqlResponse =
+                post_json(&self.client, url.clone(), &self.auth_token, &body).await?;
+            if let Some(errors) = response.errors.filter(|e| !e.is_empty()) {
+                let messages: Vec<_> = errors.into_iter().map(|e| e.message).collect();
+                anyhow::bail!("graphql: {}", messages.join(", "))
+            }
+            let page = response
+                .data
+                .and_then(|d| d.project)
+                .with_context(|| format!("graphql found no project {path}"))?
+                .ci_variables;
+            variables.extend(page.nodes.into_iter().map(GitlabVariable::from));
+            match page.page_info {
+                GraphqlPageInfo {
+                    has_next_page: true,
+                    end_cursor: Some(cursor),
+                } => after = Some(cursor),
+                _ => return Ok(variables),
+            }
+        }
+    }
+
+    /// The url of a single variable, keys are only unique per scope so the
+    /// scope is always given as a filter.
+    fn variable_url(&self, variable: &GitlabVariable) -> anyhow::Result<Url> {
+        let mut url = Url::parse(&self.variables_url())?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("{} has no path", self.url))?
+            .push(&variable.key);
+        let scope = variable.environment_scope.as_deref().unwrap_or("*");
+        url.query_pairs_mut()
+            .append_pair("filter[environment_scope]", scope);
+        Ok(url)
+    }
+
+    /// Creates `variable` in its environment scope. Keys are only unique per
+    /// scope, so this doesn't touch variables of the same key in other scopes.
+    pub async fn create_variable(
+        &self,
+        variable: &GitlabVariable,
+    ) -> anyhow::Result<GitlabVariable> {
+        validate_key(&variable.key)?;
+        if variable.masked {
+            validate_maskable(&variable.value)?;
+        }
+        let url = Url::parse(&self.variables_url())?;
+        post_json(
+            &self.client,
+            url,
+            &self.auth_token,
+            &variable_body(variable),
+        )
+        .await
+    }
+
+    pub async fn update_variable(
+        &self,
+        variable: &GitlabVariable,
+    ) -> anyhow::Result<GitlabVariable> {
+        validate_key(&variable.key)?;
+        if variable.masked {
+            validate_maskable(&variable.value)?;
+        }
+        let url = self.variable_url(variable)?;
+        put_json(
+            &self.client,
+            url,
+            &self.auth_token,
+            &variable_body(variable),
+        )
+        .await
+    }
+
+    pub async fn delete_variable(&self, variable: &GitlabVariable) -> anyhow::Result<()> {
+        validate_key(&variable.key)?;
+        let url = self.variable_url(variable)?;
+        send(self.client.delete(url), &self.auth_token).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_with_deadline() {
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        };
+        let result = with_deadline(Some(Duration::from_millis(10)), slow).await;
+        assert!(result.unwrap_err().to_string().contains("timed out"));
+
+        let fast = async { Ok(42) };
+        let result = with_deadline(Some(Duration::from_secs(5)), fast).await;
+        assert_eq!(result.unwrap(), 42);
+
+        let unbounded = async { Ok(42) };
+        assert_eq!(with_deadline(None, unbounded).await.unwrap(), 42);
+    }
+
+    fn group(id: u32, path: &str) -> GitlabResourceMeta {
+        GitlabResourceMeta {
+            id,
+            full_path: Some(path.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn tokens(path: &str) -> Vec<String> {
+        path.split('/').map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_containing_group() {
+        let groups = vec![group(1, "org"), group(2, "org/group")];
+
+        // immediate parent is known
+        let found = containing_group(&groups, &tokens("org/group/project"));
+        assert_eq!(found.map(|g| g.id), Some(2));
+
+        // 4 levels, but org/group/subgroup isn't among the known groups
+        let found = containing_group(&groups, &tokens("org/group/subgroup/project"));
+        assert_eq!(found.map(|g| g.id), Some(2));
+
+        // project directly under the top-level namespace
+        let found = containing_group(&groups, &tokens("org/project"));
+        assert_eq!(found.map(|g| g.id), Some(1));
+
+        // a group never contains itself
+        let found = containing_group(&groups, &tokens("org"));
+        assert!(found.is_none());
+
+        let found = containing_group(&groups, &tokens("user/project"));
+        assert!(found.is_none());
+    }
+
+    fn project(id: u32, path: &str) -> GitlabResourceMeta {
+        GitlabResourceMeta {
+            id,
+            path_with_namespace: Some(path.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_partial_matches() {
+        let projects = vec![
+            project(1, "org/group/backend-api"),
+            project(2, "org/group/frontend"),
+            project(3, "org/group/frontend-legacy"),
+            project(4, "org/group/legacy-tools"),
+        ];
+        let ids = |matches: Vec<&GitlabResourceMeta>| -> Vec<u32> {
+            matches.into_iter().map(|p| p.id).collect()
+        };
+
+        // unique prefix
+        assert_eq!(ids(partial_matches(&projects, "org/group/back")), vec![1]);
+        // ambiguous prefix
+        assert_eq!(
+            ids(partial_matches(&projects, "org/group/front")),
+            vec![2, 3]
+        );
+        // prefixes win over substrings
+        assert_eq!(ids(partial_matches(&projects, "org/group/legacy")), vec![4]);
+        // substring as a fallback
+        assert_eq!(ids(partial_matches(&projects, "api")), vec![1]);
+        assert!(partial_matches(&projects, "org/other").is_empty());
+    }
+
+    #[test]
+    fn test_resource_url() {
+        let api = GApi::new("gitlab.com", "token");
+        let meta = GitlabResourceMeta {
+            id: 42,
+            path_with_namespace: Some("org/project".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            api.project_resource(meta.clone()).url(),
+            "https://gitlab.com/api/v4/projects/42"
+        );
+        assert_eq!(
+            api.group_resource(meta).url(),
+            "https://gitlab.com/api/v4/groups/42"
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct SpanNames(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanNames {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _: &tracing::span::Id,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let name = attrs.metadata().name().to_string();
+            self.0.lock().unwrap().push(name);
+        }
+    }
+
+    // the `field` of events at `level` or for `target`, whichever is set
+    #[derive(Clone)]
+    struct Captured {
+        level: Option<tracing::Level>,
+        target: Option<&'static str>,
+        field: &'static str,
+        values: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl Captured {
+        fn new(
+            level: Option<tracing::Level>,
+            target: Option<&'static str>,
+            field: &'static str,
+        ) -> Self {
+            Self {
+                level,
+                target,
+                field,
+                values: Default::default(),
+            }
+        }
+
+        // the messages of warnings
+        fn warnings() -> Self {
+            Self::new(Some(tracing::Level::WARN), None, "message")
+        }
+
+        // the steps of explain events
+        fn explained() -> Self {
+            Self::new(None, Some(EXPLAIN), "step")
+        }
+
+        fn values(&self) -> Vec<String> {
+            self.values.lock().unwrap().clone()
+        }
+    }
+
+    impl tracing::field::Visit for Captured {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            if field.name() == self.field {
+                self.values.lock().unwrap().push(value.to_string());
+            }
+        }
+
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            if field.name() == self.field {
+                self.values.lock().unwrap().push(format!("{value:?}"));
+            }
+        }
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Captured {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let metadata = event.metadata();
+            if self.level.is_none_or(|l| *metadata.level() == l)
+                && self.target.is_none_or(|t| metadata.target() == t)
+            {
+                event.record(&mut self.clone());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_explain() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let explained = Captured::explained();
+        let subscriber = tracing_subscriber::registry().with(explained.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        // the direct lookup is refused, the project is found in its group
+        let addr = mock_server(vec![
+            ("/api/v4/groups", r#"[{"id": 1, "full_path": "org"}]"#),
+            (
+                "/api/v4/groups/1/projects",
+                r#"[{"id": 11, "path_with_namespace": "org/sub/app"}]"#,
+            ),
+        ])
+        .await;
+        let api = GApi::new(&addr, "token");
+        let uri = UriMeta {
+            tokens: vec!["org".into(), "sub".into(), "app".into()],
+            ..Default::default()
+        };
+        assert_eq!(api.resource_from_uri(&uri).await.unwrap().meta.id, 11);
+        assert_eq!(
+            explained.values(),
+            [
+                "direct_lookup",
+                "list_groups",
+                "subgroup_lookup",
+                "containing_group",
+                "list_projects",
+                "project_in_group"
+            ]
+        );
+
+        explained.values.lock().unwrap().clear();
+        let uri = UriMeta {
+            tokens: vec!["org".into()],
+            resource: Some(Resource::Group),
+            ..Default::default()
+        };
+        assert_eq!(api.resource_from_uri(&uri).await.unwrap().meta.id, 1);
+        assert_eq!(explained.values(), ["list_groups", "exact_group"]);
+    }
+
+    #[tokio::test]
+    async fn test_resolution_span() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let spans = SpanNames::default();
+        let subscriber = tracing_subscriber::registry().with(spans.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        // nothing listens on port 1, the resolution fails but is still traced
+        let api = GApi::new("localhost:1", "token");
+        let uri = UriMeta {
+            url: "localhost:1/org/project".to_string(),
+            tokens: vec!["org".to_string(), "project".to_string()],
+            ..Default::default()
+        };
+        assert!(api.resource_from_uri(&uri).await.is_err());
+
+        let spans = spans.0.lock().unwrap();
+        assert!(spans.contains(&"resource_from_uri".to_string()));
+        assert!(spans.contains(&"project_by_path".to_string()));
+    }
+
+    /// A canned response of [`mock_server`], served to requests for `path`
+    /// whatever their query unless narrowed down.
+    #[derive(Clone)]
+    pub(crate) struct Route {
+        path: &'static str,
+        query: Option<&'static str>,
+        posted: Option<&'static str>,
+        times: Option<usize>,
+        status: u16,
+        headers: Vec<(&'static str, String)>,
+        body: String,
+        delay: Duration,
+    }
+
+    impl Route {
+        pub(crate) fn new(path: &'static str, body: impl Into<String>) -> Self {
+            Self {
+                path,
+                query: None,
+                posted: None,
+                times: None,
+                status: 200,
+                headers: vec![("x-total-pages", "1".to_string())],
+                body: body.into(),
+                delay: Duration::ZERO,
+            }
+        }
+
+        pub(crate) fn status(mut self, status: u16) -> Self {
+            self.status = status;
+            self
+        }
+
+        /// Sets the response header `name`, replacing any previous value.
+        pub(crate) fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+            self.headers.retain(|(n, _)| *n != name);
+            self.headers.push((name, value.into()));
+            self
+        }
+
+        /// Waits before answering, requests are served concurrently.
+        pub(crate) fn delay(mut self, delay: Duration) -> Self {
+            self.delay = delay;
+            self
+        }
+
+        /// Only matches requests whose query contains `query`.
+        pub(crate) fn query(mut self, query: &'static str) -> Self {
+            self.query = Some(query);
+            self
+        }
+
+        /// Only matches requests whose body contains `content`.
+        #[cfg(feature = "graphql")]
+        pub(crate) fn posted(mut self, content: &'static str) -> Self {
+            self.posted = Some(content);
+            self
+        }
+
+        /// Stops matching after answering `times` requests.
+        pub(crate) fn times(mut self, times: usize) -> Self {
+            self.times = Some(times);
+            self
+        }
+
+        fn matches(&self, request: &Request) -> bool {
+            self.path == request.path
+                && self.times != Some(0)
+                && self.query.is_none_or(|q| request.query.contains(q))
+                && self.posted.is_none_or(|p: &str| request.body.contains(p))
+        }
+
+        fn response(&self) -> String {
+            let reason = reqwest::StatusCode::from_u16(self.status)
+                .ok()
+                .and_then(|s| s.canonical_reason())
+                .unwrap_or_default();
+            let mut response = format!("HTTP/1.1 {} {reason}\r\n", self.status);
+            for (name, value) in &self.headers {
+                response.push_str(&format!("{name}: {value}\r\n"));
+            }
+            response.push_str(&format!(
+                "content-length: {}\r\nconnection: close\r\n\r\n{}",
+                self.body.len(),
+                self.body
+            ));
+            response
+        }
+    }
+
+    impl From<(&'static str, &'static str)> for Route {
+        fn from((path, body): (&'static str, &'static str)) -> Self {
+            Self::new(path, body)
+        }
+    }
+
+    /// A request received by [`mock_server_logged`], header names lowercased.
+    #[derive(Clone, Debug)]
+    pub(crate) struct Request {
+        pub path: String,
+        pub query: String,
+        pub headers: Vec<(String, String)>,
+        pub body: String,
+    }
+
+    impl Request {
+        pub(crate) fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    /// The requests served by [`mock_server_logged`], along with how many
+    /// were being answered at once at most.
+    #[derive(Default)]
+    pub(crate) struct MockLog {
+        requests: std::sync::Mutex<Vec<Request>>,
+        active: std::sync::atomic::AtomicUsize,
+        peak: std::sync::atomic::AtomicUsize,
+    }
+
+    impl MockLog {
+        pub(crate) fn requests(&self) -> Vec<Request> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        pub(crate) fn paths(&self) -> Vec<String> {
+            self.requests().into_iter().map(|r| r.path).collect()
+        }
+
+        pub(crate) fn clear(&self) {
+            self.requests.lock().unwrap().clear();
+            self.peak.store(0, std::sync::atomic::Ordering::SeqCst);
+        }
+
+        pub(crate) fn peak(&self) -> usize {
+            self.peak.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    // reads a whole request, its body included
+    async fn read_request(stream: &mut tokio::net::TcpStream) -> Option<Request> {
+        use tokio::io::AsyncReadExt;
+
+        let mut buf = vec![];
+        let mut chunk = [0; 4096];
+        let (head, body_start) = loop {
+            let n = stream.read(&mut chunk).await.ok().filter(|n| *n > 0)?;
+            buf.extend_from_slice(&chunk[..n]);
+            if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break (String::from_utf8_lossy(&buf[..end]).to_string(), end + 4);
+            }
+        };
+        let mut lines = head.lines();
+        let target = lines.next()?.split_whitespace().nth(1)?.to_string();
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(n, v)| (n.trim().to_lowercase(), v.trim().to_string()))
+            .collect();
+        let length = headers
+            .iter()
+            .find(|(n, _)| n == "content-length")
+            .and_then(|(_, v)| v.parse().ok())
+            .unwrap_or(0);
+        while buf.len() < body_start + length {
+            let n = stream.read(&mut chunk).await.ok().filter(|n| *n > 0)?;
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+        Some(Request {
+            path: path.to_string(),
+            query: query.to_string(),
+            headers,
+            body: String::from_utf8_lossy(&buf[body_start..]).to_string(),
+        })
+    }
+
+    // serves the first matching route, 404 without one
+    pub(crate) async fn mock_server<R: Into<Route>>(routes: Vec<R>) -> String {
+        mock_server_logged(routes).await.0
+    }
+
+    // like `mock_server`, also recording every request
+    pub(crate) async fn mock_server_logged<R: Into<Route>>(
+        routes: Vec<R>,
+    ) -> (String, Arc<MockLog>) {
+        use std::sync::atomic::Ordering;
+        use tokio::io::AsyncWriteExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let log = Arc::new(MockLog::default());
+        let routes: Arc<std::sync::Mutex<Vec<Route>>> = Arc::new(std::sync::Mutex::new(
+            routes.into_iter().map(Into::into).collect(),
+        ));
+        let served = log.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let (log, routes) = (served.clone(), routes.clone());
+                tokio::spawn(async move {
+                    let Some(request) = read_request(&mut stream).await else {
+                        return;
+                    };
+                    let now = log.active.fetch_add(1, Ordering::SeqCst) + 1;
+                    log.peak.fetch_max(now, Ordering::SeqCst);
+                    let route = {
+                        let mut routes = routes.lock().unwrap();
+                        let route = routes.iter_mut().find(|r| r.matches(&request));
+                        route.map(|r| {
+                            r.times = r.times.map(|t| t - 1);
+                            r.clone()
+                        })
+                    };
+                    log.requests.lock().unwrap().push(request);
+                    let response = match route {
+                        Some(route) => {
+                            tokio::time::sleep(route.delay).await;
+                            route.response()
+                        }
+                        None => Route::new("", "").status(404).response(),
+                    };
+                    log.active.fetch_sub(1, Ordering::SeqCst);
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        (addr, log)
+    }
+
+    #[tokio::test]
+    async fn test_maintenance() {
+        use crate::output::ErrorKind;
+
+        let body = r#"{"message":"You cannot perform write operations on a read-only instance"}"#;
+        let addr = mock_server(vec![Route::new("/api/v4/groups", body).status(503)]).await;
+        let error = GApi::new(&addr, "token").groups().await.unwrap_err();
+        assert!(
+            matches!(error.downcast_ref::<ApiError>(), Some(ApiError::Maintenance(host)) if host == "127.0.0.1"),
+            "expected a maintenance error, got {error}"
+        );
+
+        let addr = mock_server(vec![Route::new("/api/v4/groups", "")
+            .status(503)
+            .header("x-gitlab-maintenance-mode", "true")])
+        .await;
+        let error = GApi::new(&addr, "token").groups().await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ApiError>(),
+            Some(ApiError::Maintenance(_))
+        ));
+
+        // an overloaded instance is not in maintenance, whatever its page says
+        for body in ["", "<h1>scheduled maintenance window next week</h1>"] {
+            let addr = mock_server(vec![Route::new("/api/v4/groups", body).status(503)]).await;
+            let error = GApi::new(&addr, "token").groups().await.unwrap_err();
+            assert!(
+                matches!(
+                    error.downcast_ref::<ApiError>(),
+                    Some(ApiError::Unavailable(_))
+                ),
+                "{error}"
+            );
+            assert_eq!(
+                ErrorKind::of(&error, ErrorKind::Internal),
+                ErrorKind::Unavailable
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oauth_bearer() {
+        let (addr, log) = mock_server_logged(vec![
+            ("/api/v4/groups", "[]"),
+            ("/api/v4/user", r#"{"id": 1, "username": "alice"}"#),
+        ])
+        .await;
+        let api = GApi::new(&addr, "access").with_oauth();
+        api.groups().await.unwrap();
+        api.whoami().await.unwrap();
+        assert_eq!(api.token_status().await.unwrap(), TokenStatus::Valid);
+        let sent = log.requests();
+        assert_eq!(sent.len(), 4);
+        for request in sent {
+            assert_eq!(request.header("authorization"), Some("Bearer access"));
+            assert_eq!(request.header("private-token"), None);
+        }
+
+        log.clear();
+        GApi::new(&addr, "personal").whoami().await.unwrap();
+        let sent = log.requests();
+        assert_eq!(sent[0].header("private-token"), Some("personal"));
+        assert_eq!(sent[0].header("authorization"), None);
+    }
+
+    #[tokio::test]
+    async fn test_user_agent() {
+        let (addr, log) = mock_server_logged(vec![("/api/v4/groups", "[]")]).await;
+
+        // the first page is fetched twice, once for the page count
+        let drain = || {
+            let sent = log.requests();
+            log.clear();
+            sent
+        };
+        let api = GApi::new(&addr, "token");
+        api.groups().await.unwrap();
+        let sent = drain();
+        assert_eq!(sent.len(), 2);
+        for request in sent {
+            assert_eq!(request.header("user-agent"), Some(USER_AGENT));
+        }
+
+        let api = api
+            .with_client_settings(ClientSettings {
+                user_agent: "corp-ci/1.0".to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+        api.groups().await.unwrap();
+        let sent = drain();
+        assert_eq!(sent.len(), 2);
+        for request in sent {
+            assert_eq!(request.header("user-agent"), Some("corp-ci/1.0"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_renamed_project() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let warnings = Captured::warnings();
+        let subscriber = tracing_subscriber::registry().with(warnings.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let addr = mock_server(vec![
+            Route::new("/api/v4/projects/org%2Fapp", "")
+                .status(301)
+                .header("location", "/api/v4/projects/7"),
+            Route::new(
+                "/api/v4/projects/7",
+                r#"{"id": 7, "path_with_namespace": "org/app-renamed"}"#,
+            ),
+        ])
+        .await;
+        let api = GApi::new(&addr, "token");
+        let uri = UriMeta {
+            tokens: vec!["org".into(), "app".into()],
+            resource: Some(Resource::Repo),
+            ..Default::default()
+        };
+        let resource = api.resource_from_uri(&uri).await.unwrap();
+        assert_eq!(resource.meta.id, 7);
+        assert_eq!(
+            resource.meta.path_with_namespace.as_deref(),
+            Some("org/app-renamed")
+        );
+        let warned = warnings.values();
+        assert_eq!(
+            warned,
+            vec!["org/app was renamed to org/app-renamed, use the new path"]
+        );
+
+        let old = GitlabResourceMeta {
+            id: 7,
+            path_with_namespace: Some("org/app".into()),
+            ..Default::default()
+        };
+        assert!(!api.still_exists(&old).await.unwrap(), "renamed away");
+    }
+
+    #[tokio::test]
+    async fn test_redirects() {
+        let addr = mock_server(vec![
+            Route::new("/api/v4/projects/1", "")
+                .status(301)
+                .header("location", "/api/v4/projects/2"),
+            Route::new(
+                "/api/v4/projects/2",
+                r#"{"id": 2, "path_with_namespace": "org/moved"}"#,
+            ),
+            Route::new("/api/v4/groups", "")
+                .status(301)
+                .header("location", "http://localhost:1/api/v4/groups"),
+            Route::new("/api/v4/projects/3", "")
+                .status(301)
+                .header("location", "http://127.0.0.1:1/api/v4/projects/3"),
+        ])
+        .await;
+        let api = GApi::new(&addr, "token");
+        let port = addr.rsplit(':').next().unwrap();
+
+        // same host, followed
+        let uri = UriMeta {
+            id: Some(1),
+            resource: Some(Resource::Repo),
+            ..Default::default()
+        };
+        let resource = api.resource_from_id(&uri).await.unwrap();
+        assert_eq!(resource.meta.id, 2);
+
+        // another host, reported instead of followed
+        let error = api.groups().await.unwrap_err();
+        match error.downcast_ref::<ApiError>() {
+            Some(ApiError::Redirected(from, to)) => {
+                assert_eq!(*from, format!("127.0.0.1:{port}"));
+                assert_eq!(to, "localhost:1");
+            }
+            _ => panic!("expected a redirect error, got {error}"),
+        }
+
+        // the same host on another port, not followed either
+        let uri = UriMeta {
+            id: Some(3),
+            resource: Some(Resource::Repo),
+            ..Default::default()
+        };
+        let error = api.resource_from_id(&uri).await.unwrap_err();
+        assert!(
+            matches!(error.root_cause().downcast_ref::<ApiError>(), Some(ApiError::Redirected(_, to)) if to == "127.0.0.1:1"),
+            "expected a redirect error, got {error:#}"
+        );
+    }
+
+    #[test]
+    fn test_followable() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        let from = url("http://gitlab.corp/api/v4/groups");
+        assert!(followable(&from, &url("http://gitlab.corp/api/v4/groups/")));
+        assert!(followable(&from, &url("https://gitlab.corp/api/v4/groups")));
+        assert!(!followable(&from, &url("https://gitlab.corp:8443/api")));
+        assert!(!followable(&from, &url("http://gitlab.corp:8080/api")));
+        assert!(!followable(&from, &url("http://evil.example/api")));
+
+        let from = url("https://gitlab.corp/api/v4/groups");
+        assert!(followable(&from, &url("https://gitlab.corp:443/api")));
+        assert!(
+            !followable(&from, &url("http://gitlab.corp/api")),
+            "downgrade"
+        );
+        assert_eq!(
+            origin(&url("http://gitlab.corp/api"), &from),
+            "http://gitlab.corp"
+        );
+        assert_eq!(
+            origin(&url("https://gitlab.corp:8443/api"), &from),
+            "gitlab.corp:8443"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_settings() {
+        let addr = mock_server(vec![("/api/v4/groups", "[]")]).await;
+        let uri = UriMeta {
+            tokens: vec!["org".into(), "sub".into()],
+            resource: Some(Resource::Group),
+            ..Default::default()
+        };
+        let api = GApi::new(&addr, "token");
+        assert_eq!(api.client_settings(), &ClientSettings::default());
+        let tuned = ClientSettings {
+            pool_max_idle_per_host: 2,
+            ..Default::default()
+        };
+        let api = api.with_client_settings(tuned.clone()).unwrap();
+        assert_eq!(api.client_settings(), &tuned);
+        let error = api.resource_from_uri(&uri).await.unwrap_err();
+        assert_eq!(error.to_string(), "no containing group found");
+
+        // the mock only speaks HTTP/1, a prior knowledge client can't reach it
+        let api = api
+            .with_client_settings(ClientSettings {
+                http2_prior_knowledge: true,
+                ..tuned
+            })
+            .unwrap();
+        let error = api.resource_from_uri(&uri).await.unwrap_err();
+        assert_ne!(error.to_string(), "no containing group found");
+    }
+
+    #[test]
+    fn test_unique_name_match() {
+        let projects = vec![
+            project(1, "org/api"),
+            project(2, "org/api-gateway"),
+            project(3, "other/tools/api"),
+            project(4, "org/web"),
+        ];
+        assert_eq!(unique_name_match(&projects, "web").unwrap().id, 4);
+        // a suffix of the name isn't enough
+        assert!(unique_name_match(&projects, "gateway").is_err());
+        let error = unique_name_match(&projects, "api").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "api is ambiguous, candidates: org/api, other/tools/api"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_bare_name() {
+        let addr = mock_server(vec![
+            ("/api/v4/groups", r#"[{"id": 1, "full_path": "org"}]"#),
+            (
+                "/api/v4/projects",
+                r#"[{"id": 10, "path_with_namespace": "org/api"}, {"id": 11, "path_with_namespace": "other/api"}, {"id": 12, "path_with_namespace": "org/web"}]"#,
+            ),
+        ])
+        .await;
+        let api = GApi::new(&addr, "token");
+        let bare = |name: &str| UriMeta {
+            tokens: vec![name.into()],
+            ..Default::default()
+        };
+        let resource = api.resource_from_uri(&bare("web")).await.unwrap();
+        assert_eq!(resource.meta.id, 12);
+        let error = api.resource_from_uri(&bare("api")).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "api is ambiguous, candidates: org/api, other/api"
+        );
+        // a matching group still wins
+        let resource = api.resource_from_uri(&bare("org")).await.unwrap();
+        assert_eq!(resource.meta.id, 1);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_subgroup() {
+        let addr = mock_server(vec![
+            (
+                "/api/v4/groups",
+                r#"[{"id": 1, "full_path": "org"}, {"id": 2, "full_path": "org/team"}]"#,
+            ),
+            (
+                "/api/v4/groups/org%2Fteam%2Fbackend",
+                r#"{"id": 3, "full_path": "org/team/backend"}"#,
+            ),
+        ])
+        .await;
+        let api = GApi::new(&addr, "token");
+        let uri = |path: &str| UriMeta {
+            tokens: path.split('/').map(String::from).collect(),
+            ..Default::default()
+        };
+        // listed subgroup
+        let resource = api.resource_from_uri(&uri("org/team")).await.unwrap();
+        assert_eq!(resource.meta.id, 2);
+        assert!(resource.url().ends_with("/groups/2"));
+        // only reachable through its ancestors
+        let resource = api
+            .resource_from_uri(&uri("org/team/backend"))
+            .await
+            .unwrap();
+        assert_eq!(resource.meta.id, 3);
+        assert!(resource.url().ends_with("/groups/3"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_case_mismatch() {
+        let addr = mock_server(vec![
+            ("/api/v4/groups", r#"[{"id": 1, "full_path": "Org"}]"#),
+            (
+                "/api/v4/groups/1/projects",
+                r#"[{"id": 10, "path_with_namespace": "Org/My-Repo"}]"#,
+            ),
+        ])
+        .await;
+        let api = GApi::new(&addr, "token");
+        let uri = |path: &str| UriMeta {
+            tokens: path.split('/').map(String::from).collect(),
+            ..Default::default()
+        };
+        let resource = api.resource_from_uri(&uri("org/my-repo")).await.unwrap();
+        assert_eq!(resource.meta.id, 10);
+        // the casing gitlab reports is kept
+        assert_eq!(
+            resource.meta.path_with_namespace.as_deref(),
+            Some("Org/My-Repo")
+        );
+        let resource = api.resource_from_uri(&uri("ORG")).await.unwrap();
+        assert_eq!(resource.meta.full_path.as_deref(), Some("Org"));
+    }
+
+    #[tokio::test]
+    async fn test_cached_groups() {
+        use crate::crypto::PasswdProtectedFile;
+        use crate::gitlab_cache::GroupCache;
+
+        let (addr, log) = mock_server_logged(vec![(
+            "/api/v4/groups",
+            r#"[{"id": 1, "full_path": "org"}, {"id": 2, "full_path": "other"}]"#,
+        )])
+        .await;
+        let file = || PasswdProtectedFile::new("unused", std::env::temp_dir().join("unused"));
+        let store = GroupCache::ephemeral(file(), Duration::from_secs(60));
+        let api = GApi::new(&addr, "token").with_group_store(Arc::new(store));
+        let group = |path: &str| UriMeta {
+            tokens: vec![path.into()],
+            resource: Some(Resource::Group),
+            ..Default::default()
+        };
+        assert_eq!(
+            api.resource_from_uri(&group("org")).await.unwrap().meta.id,
+            1
+        );
+        let listings = || {
+            log.paths()
+                .iter()
+                .filter(|p| p.ends_with("/groups"))
+                .count()
+        };
+        let first = listings();
+        assert!(first > 0);
+        assert_eq!(
+            api.resource_from_uri(&group("other"))
+                .await
+                .unwrap()
+                .meta
+                .id,
+            2
+        );
+        assert_eq!(listings(), first);
+
+        // a group created after the listing was stored
+        let stale = GroupCache::ephemeral(file(), Duration::from_secs(60));
+        let org = GitlabResourceMeta {
+            id: 1,
+            full_path: Some("org".into()),
+            ..Default::default()
+        };
+        stale.put(api.domain(), &[org]);
+        let api = GApi::new(&addr, "token").with_group_store(Arc::new(stale));
+        assert_eq!(
+            api.resource_from_uri(&group("other"))
+                .await
+                .unwrap()
+                .meta
+                .id,
+            2
+        );
+        assert!(listings() > first, "listed again");
+        assert_eq!(
+            api.resource_from_uri(&group("other"))
+                .await
+                .unwrap()
+                .meta
+                .id,
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cached_user() {
+        use crate::crypto::PasswdProtectedFile;
+        use crate::gitlab_cache::UserCache;
+
+        let (addr, log) = mock_server_logged(vec![(
+            "/api/v4/user",
+            r#"{"id": 5, "username": "ada", "name": "Ada"}"#,
+        )])
+        .await;
+        let lookups = || log.requests().len();
+        let file = PasswdProtectedFile::new("unused", std::env::temp_dir().join("unused"));
+        let store: Arc<dyn UserStore> =
+            Arc::new(UserCache::ephemeral(file, Duration::from_secs(60)));
+        let api = GApi::new(&addr, "token").with_user_store(store.clone());
+
+        assert_eq!(api.whoami().await.unwrap().username, "ada");
+        assert_eq!(lookups(), 1);
+        assert_eq!(api.whoami().await.unwrap().id, 5);
+        assert_eq!(api.validate_token().await.unwrap(), TokenStatus::Valid);
+        assert_eq!(lookups(), 1, "served from the cache");
+
+        // another token might belong to someone else
+        let replaced = GApi::new(&addr, "new-token").with_user_store(store);
+        replaced.validate_token().await.unwrap();
+        assert_eq!(lookups(), 2);
+        replaced.whoami().await.unwrap();
+        assert_eq!(lookups(), 2, "validating cached the user");
+    }
+
+    #[cfg(feature = "graphql")]
+    #[tokio::test]
+    async fn test_graphql_variables() {
+        // the second page is asked for with the cursor of the first
+        let (addr, log) = mock_server_logged(vec![
+            Route::new(
+                "/api/graphql",
+                r#"{"data": {"project": {"ciVariables": {
+                "nodes": [{"key": "TOKEN", "value": null, "environmentScope": "production",
+                           "masked": true, "protected": false, "raw": false, "hidden": true}],
+                "pageInfo": {"hasNextPage": false, "endCursor": null}}}}}"#,
+            )
+            .posted(r#""after":"c1""#),
+            Route::new(
+                "/api/graphql",
+                r#"{"data": {"project": {"ciVariables": {
+                "nodes": [{"key": "HOST", "value": "localhost", "environmentScope": "*",
+                           "masked": false, "protected": true, "raw": true}],
+                "pageInfo": {"hasNextPage": true, "endCursor": "c1"}}}}}"#,
+            ),
+        ])
+        .await;
+        let meta = GitlabResourceMeta {
+            id: 3,
+            path_with_namespace: Some("org/repo".into()),
+            ..Default::default()
+        };
+        let resource = GApi::new(&addr, "token").project_resource(meta);
+        let variables = resource.variables().await.unwrap();
+        assert_eq!(
+            variables,
+            vec![
+                GitlabVariable {
+                    key: "HOST".into(),
+                    value: "localhost".into(),
+                    environment_scope: Some("*".into()),
+                    protected: true,
+                    raw: true,
+                    ..Default::default()
+                },
+                GitlabVariable {
+                    key: "TOKEN".into(),
+                    environment_scope: Some("production".into()),
+                    masked: true,
+                    hidden: true,
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(log.paths(), ["/api/graphql", "/api/graphql"]);
+    }
+
+    #[tokio::test]
+    async fn test_page_memo() {
+        let (addr, log) = mock_server_logged(vec![
+            ("/api/v4/groups", r#"[{"id": 1, "full_path": "org"}]"#),
+            ("/api/v4/groups/1/projects", "[]"),
+        ])
+        .await;
+        let requests = || log.requests().len();
+        let api = GApi::new(&addr, "token").with_page_memo();
+        assert_eq!(api.groups().await.unwrap()[0].id, 1);
+        let first = requests();
+        assert_eq!(api.clone().groups().await.unwrap()[0].id, 1);
+        assert_eq!(requests(), first, "clones share the memo");
+
+        // another url is fetched on its own
+        api.projects(1, false, None).await.unwrap();
+        assert_eq!(requests(), first * 2);
+
+        api.clear_page_memo();
+        api.groups().await.unwrap();
+        assert_eq!(requests(), first * 3);
+
+        let api = GApi::new(&addr, "token");
+        api.groups().await.unwrap();
+        api.groups().await.unwrap();
+        assert_eq!(requests(), first * 5, "no memo unless asked for");
+    }
+
+    #[tokio::test]
+    async fn test_best_effort_pages() {
+        // page 3 always fails, page 4 only the first time
+        let page = |n: &'static str, query: &'static str| {
+            Route::new("/api/v4/groups", format!("[{n}]"))
+                .header("x-total-pages", "5")
+                .query(query)
+        };
+        let addr = mock_server(vec![
+            page("", "page=3").status(500),
+            page("", "page=4").status(500).times(1),
+            page("1", "page=1"),
+            page("2", "page=2"),
+            page("4", "page=4"),
+            page("5", "page=5"),
+            // the page count is read off an unnumbered first request
+            page("", ""),
+        ])
+        .await;
+        let client = Client::new();
+        let url = Url::parse(&format!("http://{addr}/api/v4/groups")).unwrap();
+        let token = AuthToken {
+            token: "token".to_string(),
+            oauth: false,
+        };
+
+        let (pages, failed) =
+            get_all_pages_best_effort::<u32>(&client, url.clone(), &token, None, 1)
+                .await
+                .unwrap();
+        assert_eq!(pages, vec![1, 2, 4, 5]);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].page, 3);
+        assert!(failed[0].error.to_string().contains("500"));
+
+        // the strict variant gives up on the first failure
+        assert!(get_all_pages::<u32>(&client, url, &token, None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_browse() {
+        let addr = mock_server(vec![
+            (
+                "/api/v4/groups",
+                r#"[{"id": 2, "full_path": "org/sub"}, {"id": 1, "full_path": "org"}]"#,
+            ),
+            (
+                "/api/v4/groups/1/projects",
+                r#"[{"id": 11, "path_with_namespace": "org/web"}, {"id": 10, "path_with_namespace": "org/api"}]"#,
+            ),
+            ("/api/v4/groups/2/projects", "[]"),
+        ])
+        .await;
+        let api = GApi::new(&addr, "token");
+
+        let browsed = api.browse(true).await.unwrap();
+        assert_eq!(
+            crate::output::render_tree(&browsed),
+            "org\n  org/api\n  org/web\norg/sub"
+        );
+        let browsed = api.browse(false).await.unwrap();
+        assert_eq!(crate::output::render_tree(&browsed), "org\norg/sub");
+    }
+
+    #[tokio::test]
+    async fn test_variables_by_topic() {
+        let addr = mock_server(vec![
+            (
+                "/api/v4/groups/1/projects",
+                r#"[
+                    {"id": 11, "path_with_namespace": "ops/web", "topics": ["Deploy", "frontend"]},
+                    {"id": 12, "path_with_namespace": "ops/docs", "topics": ["docs"]},
+                    {"id": 10, "path_with_namespace": "ops/sub/api", "topics": ["deploy"]}
+                ]"#,
+            ),
+            (
+                "/api/v4/projects/11/variables",
+                r#"[{"key": "HOST", "value": "web.example"}]"#,
+            ),
+            (
+                "/api/v4/projects/10/variables",
+                r#"[{"key": "HOST", "value": "api.example"}, {"key": "PORT", "value": "8080"}]"#,
+            ),
+        ])
+        .await;
+        let api = GApi::new(&addr, "token");
+
+        let found = api.variables_by_topic(1, "deploy").await.unwrap();
+        let paths: Vec<_> = found
+            .iter()
+            .map(|(p, _)| p.path_with_namespace.as_deref().unwrap())
+            .collect();
+        assert_eq!(paths, ["ops/sub/api", "ops/web"], "docs is left out");
+        let aggregated = crate::output::aggregate_by_project(&found, None, false);
+        assert_eq!(
+            serde_json::to_value(&aggregated).unwrap(),
+            serde_json::json!({
+                "ops/sub/api": {"HOST": "api.example", "PORT": "8080"},
+                "ops/web": {"HOST": "web.example"},
+            })
+        );
+        assert!(api
+            .variables_by_topic(1, "missing")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_jobs_limit() {
+        let projects = (0..6)
+            .map(|i| {
+                format!(r#"{{"id": {i}, "path_with_namespace": "ops/p{i}", "topics": ["deploy"]}}"#)
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        // every variables request takes a while, the log tells how many overlap
+        let mut routes = vec![Route::new(
+            "/api/v4/groups/1/projects",
+            format!("[{projects}]"),
+        )];
+        for i in 0..6 {
+            let path = format!("/api/v4/projects/{i}/variables").leak();
+            routes.push(Route::new(path, "[]").delay(Duration::from_millis(50)));
+        }
+        let (addr, log) = mock_server_logged(routes).await;
+
+        for jobs in [1, 2] {
+            log.clear();
+            let api = GApi::new(&addr, "token")
+                .with_client_settings(ClientSettings {
+                    jobs,
+                    ..Default::default()
+                })
+                .unwrap();
+            let found = api.variables_by_topic(1, "deploy").await.unwrap();
+            assert_eq!(found.len(), 6);
+            assert_eq!(log.peak(), jobs);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resource_raw() {
+        const PROJECT: &str = r#"{"id": 42, "path_with_namespace": "org/app", "topics": ["deploy"],
+            "default_branch": "main", "namespace": {"id": 1, "kind": "group"}, "archived": false,
+            "statistics": null, "star_count": 3}"#;
+        let addr = mock_server(vec![
+            ("/api/v4/projects/org%2Fapp", PROJECT),
+            ("/api/v4/projects/42", PROJECT),
+            (
+                "/api/v4/groups/1",
+                r#"{"id": 1, "full_path": "org", "visibility": "private"}"#,
+            ),
+        ])
+        .await;
+        let api = GApi::new(&addr, "token");
+        let expected: serde_json::Value = serde_json::from_str(PROJECT).unwrap();
+
+        let uri = UriMeta {
+            tokens: vec!["org".into(), "app".into()],
+            ..Default::default()
+        };
+        let project = api.resource_from_uri(&uri).await.unwrap();
+        assert_eq!(project.raw().await.unwrap(), expected);
+        let uri = UriMeta {
+            id: Some(42),
+            ..Default::default()
+        };
+        let project = api.resource_from_id(&uri).await.unwrap();
+        assert_eq!(project.raw().await.unwrap(), expected);
+        let uri = UriMeta {
+            id: Some(1),
+            resource: Some(Resource::Group),
+            ..Default::default()
+        };
+        let group = api.resource_from_id(&uri).await.unwrap();
+        assert_eq!(group.raw().await.unwrap()["visibility"], "private");
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ambiguous() {
+        let addr = mock_server(vec![
+            ("/api/v4/groups", r#"[{"id": 1, "full_path": "org"}]"#),
+            (
+                "/api/v4/groups/1/projects",
+                r#"[{"id": 2, "path_with_namespace": "org/backend"},
+                    {"id": 3, "path_with_namespace": "org/backoffice"},
+                    {"id": 4, "path_with_namespace": "org/frontend"}]"#,
+            ),
+        ])
+        .await;
+        let api = GApi::new(&addr, "token");
+        let uri = |path: &str| UriMeta {
+            tokens: path.split('/').map(String::from).collect(),
+            resource: Some(Resource::Repo),
+            ..Default::default()
+        };
+
+        let error = api.resource_from_uri(&uri("org/front")).await.unwrap_err();
+        let ambiguous = error.downcast_ref::<Ambiguous>().unwrap();
+        let ids: Vec<_> = ambiguous.candidates.iter().map(|p| p.id).collect();
+        assert_eq!(ids, [4], "a single partial match is confirmed too");
+
+        let error = api.resource_from_uri(&uri("org/back")).await.unwrap_err();
+        let ambiguous = error.downcast_ref::<Ambiguous>().unwrap();
+        assert_eq!(ambiguous.path, "org/back");
+        let ids: Vec<_> = ambiguous.candidates.iter().map(|p| p.id).collect();
+        assert_eq!(ids, [2, 3]);
+        assert_eq!(
+            error.to_string(),
+            "no exact match for org/back, candidates: org/backend, org/backoffice"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_project_without_group_access() {
+        let addr = mock_server(vec![
+            ("/api/v4/groups", "[]"),
+            (
+                "/api/v4/projects/org%2Frestricted%2Fproject",
+                r#"{"id": 42, "path_with_namespace": "org/restricted/project"}"#,
+            ),
+        ])
+        .await;
+        let api = GApi::new(&addr, "token");
+        let uri = UriMeta {
+            tokens: vec!["org".into(), "restricted".into(), "project".into()],
+            ..Default::default()
+        };
+        let resource = api.resource_from_uri(&uri).await.unwrap();
+        assert_eq!(resource.meta.id, 42);
+        assert_eq!(resource.url(), format!("http://{addr}/api/v4/projects/42"));
+
+        // unknown projects still fall back to scanning groups
+        let uri = UriMeta {
+            tokens: vec!["org".into(), "missing".into()],
+            ..Default::default()
+        };
+        let error = api.resource_from_uri(&uri).await.unwrap_err();
+        assert_eq!(error.to_string(), "no containing group found");
+    }
+
+    #[test]
+    fn test_variable_body() {
+        let variable = GitlabVariable {
+            key: "HOST".to_string(),
+            value: "prod.example.com".to_string(),
+            environment_scope: Some("production".to_string()),
+            protected: true,
+            ..Default::default()
+        };
+        let body = variable_body(&variable);
+        assert_eq!(body["key"], "HOST");
+        assert_eq!(body["value"], "prod.example.com");
+        assert_eq!(body["environment_scope"], "production");
+        assert_eq!(body["protected"], true);
+        assert_eq!(body["masked"], false);
+
+        let unscoped = GitlabVariable {
+            environment_scope: None,
+            ..variable
+        };
+        assert_eq!(variable_body(&unscoped)["environment_scope"], "*");
+    }
+
+    #[test]
+    fn test_variable_url() {
+        let api = GApi::new("gitlab.com", "token");
+        let resource = api.resource_by_id(1, &Resource::Repo);
+        let variable = GitlabVariable {
+            key: "HOST".to_string(),
+            environment_scope: Some("production".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            resource.variable_url(&variable).unwrap().as_str(),
+            "https://gitlab.com/api/v4/projects/1/variables/HOST?filter%5Benvironment_scope%5D=production"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_variable() {
+        let (addr, log) = mock_server_logged(vec![
+            Route::new("/api/v4/projects/1/variables/HOST", "").status(403),
+            Route::new("/api/v4/projects/1/variables/PORT", "").status(204),
+        ])
+        .await;
+        let api = GApi::new(&addr, "token");
+        let resource = api.resource_by_id(1, &Resource::Repo);
+        let variable = |key: &str| GitlabVariable {
+            key: key.to_string(),
+            ..Default::default()
+        };
+
+        resource.delete_variable(&variable("PORT")).await.unwrap();
+        let error = resource
+            .delete_variable(&variable("HOST"))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(error.downcast_ref(), Some(ApiError::Forbidden(_))),
+            "{error}"
+        );
+        let error = resource
+            .delete_variable(&variable("../../HOST"))
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<KeyError>().is_some(), "{error}");
+        assert_eq!(log.paths().len(), 2, "an invalid key is never sent");
+    }
+
+    #[test]
+    fn test_deserialize_triggers() {
+        let body = r#"[
+            {
+                "id": 10,
+                "description": "my trigger",
+                "created_at": "2016-01-07T09:53:58.235Z",
+                "last_used": null,
+                "token": "6d056f63e50fe6f8c5f8f4aa10edb7",
+                "updated_at": "2016-01-07T09:53:58.235Z",
+                "owner": null
+            },
+            {"id": 11, "description": null, "token": "4e5f6a"}
+        ]"#;
+        let triggers: Vec<GitlabTrigger> = serde_json::from_str(body).unwrap();
+        assert_eq!(triggers.len(), 2);
+        assert_eq!(triggers[0].id, 10);
+        assert_eq!(triggers[0].description.as_deref(), Some("my trigger"));
+        assert_eq!(triggers[0].token, "6d056f63e50fe6f8c5f8f4aa10edb7");
+        assert_eq!(triggers[1].description, None);
+    }
+
+    #[test]
+    fn test_default_scheme() {
+        assert_eq!(default_scheme("gitlab.com"), "https");
+        assert_eq!(default_scheme("localhost:8080"), "http");
+        assert_eq!(default_scheme("[::1]:8080"), "http");
+        assert_eq!(default_scheme("[2001:db8::1]"), "https");
+    }
+
+    #[tokio::test]
+    async fn test_deploy_tokens() {
+        let addr = mock_server(vec![(
+            "/api/v4/projects/1/deploy_tokens",
+            r#"[
+                {
+                    "id": 1,
+                    "name": "registry",
+                    "username": "gitlab+deploy-token-1",
+                    "expires_at": "2027-01-01T00:00:00.000Z",
+                    "scopes": ["read_registry", "read_repository"],
+                    "revoked": false,
+                    "expired": false
+                },
+                {"id": 2, "name": "ci", "username": "ci-bot", "expires_at": null, "scopes": []}
+            ]"#,
+        )])
+        .await;
+        let api = GApi::new(&addr, "token");
+        let tokens = api
+            .resource_by_id(1, &Resource::Repo)
+            .deploy_tokens()
+            .await
+            .unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[0].username, "gitlab+deploy-token-1");
+        assert_eq!(tokens[0].scopes, ["read_registry", "read_repository"]);
+        assert_eq!(
+            tokens[0].expires_at.as_deref(),
+            Some("2027-01-01T00:00:00.000Z")
+        );
+        assert_eq!(tokens[1].expires_at, None);
+    }
+
+    #[test]
+    fn test_describe_meta() {
+        // cached before name and description were captured
+        let old: GitlabResourceMeta =
+            serde_json::from_str(r#"{"id": 1, "full_path": "org", "path_with_namespace": null}"#)
+                .unwrap();
+        assert_eq!(old.name, None);
+        assert_eq!(old.describe(), "org");
+
+        let project: GitlabResourceMeta = serde_json::from_str(
+            r#"{"id": 2, "path_with_namespace": "org/api", "name": "API", "description": "public api"}"#,
+        )
+        .unwrap();
+        assert_eq!(project.describe(), "org/api (API): public api");
+        let project = GitlabResourceMeta {
+            description: Some(String::new()),
+            ..project
+        };
+        assert_eq!(project.describe(), "org/api (API)");
+    }
+
+    #[test]
+    fn test_deserialize_hidden_variable() {
+        let variables: Vec<GitlabVariable> = serde_json::from_str(
+            r#"[
+                {"key": "SECRET", "value": null, "description": null, "masked": true, "hidden": true},
+                {"key": "OTHER", "description": null, "masked_and_hidden": true},
+                {"key": "HOST", "value": "localhost", "description": null}
+            ]"#,
+        )
+        .unwrap();
+        assert!(variables[0].hidden && variables[1].hidden);
+        assert_eq!(variables[0].readable_value(), None);
+        assert_eq!(variables[1].readable_value(), None);
+        assert!(!variables[2].hidden);
+        assert_eq!(variables[2].readable_value(), Some("localhost"));
+    }
+
+    #[test]
+    fn test_validate_maskable() {
+        assert_eq!(validate_maskable("abcdefgh"), Ok(()));
+        assert_eq!(validate_maskable("glpat-AbC_123+/=:@.~"), Ok(()));
+        assert_eq!(validate_maskable("short"), Err(MaskError::TooShort));
+        assert_eq!(validate_maskable(""), Err(MaskError::TooShort));
+        assert_eq!(
+            validate_maskable("abcdefgh\nijkl"),
+            Err(MaskError::MultiLine)
+        );
+        assert_eq!(validate_maskable("abcd efgh"), Err(MaskError::Whitespace));
+        assert_eq!(
+            validate_maskable("abcdefgh$"),
+            Err(MaskError::InvalidChar('$'))
+        );
+        assert_eq!(
+            validate_maskable("pässwörd1"),
+            Err(MaskError::InvalidChar('ä'))
+        );
+    }
+
+    #[test]
+    fn test_validate_key() {
+        assert_eq!(validate_key("DATABASE_URL"), Ok(()));
+        assert_eq!(validate_key("_private"), Ok(()));
+        assert_eq!(
+            validate_key("2FA_SECRET"),
+            Ok(()),
+            "leading digits are fine"
+        );
+        assert_eq!(validate_key(""), Err(KeyError::Empty));
+        assert_eq!(
+            validate_key("API KEY"),
+            Err(KeyError::InvalidChar("API KEY".into(), ' '))
+        );
+        assert_eq!(
+            validate_key("api-key"),
+            Err(KeyError::InvalidChar("api-key".into(), '-'))
+        );
+        assert_eq!(
+            validate_key("CLÉ"),
+            Err(KeyError::InvalidChar("CLÉ".into(), 'É'))
+        );
+        let long = "K".repeat(256);
+        assert_eq!(validate_key(&long), Err(KeyError::TooLong(long.clone())));
+    }
+
+    #[tokio::test]
+    async fn test_no_variables() {
+        let addr = mock_server(vec![("/api/v4/projects/1/variables", "[]")]).await;
+        let api = GApi::new(&addr, "token");
+
+        let empty = api.resource_by_id(1, &Resource::Repo).variables().await;
+        let empty = empty.expect("no variables is not a failure");
+        assert!(empty.is_empty());
+        assert_eq!(
+            crate::output::empty_note("org/empty", &empty).as_deref(),
+            Some("org/empty has no variables")
+        );
+        // failures stay failures rather than looking empty
+        let missing = api.resource_by_id(2, &Resource::Repo).variables().await;
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn test_api_path() {
+        let api = GApi::new("gitlab.com", "token").with_api_path("/proxy/gitlab/api/v4/");
+        assert_eq!(api.api_base(), "https://gitlab.com/proxy/gitlab/api/v4");
+        assert_eq!(
+            api.resource_by_id(1, &Resource::Repo).variables_url(),
+            "https://gitlab.com/proxy/gitlab/api/v4/projects/1/variables"
+        );
+        let api = GApi::new("gitlab.com", "token");
+        assert_eq!(api.api_base(), "https://gitlab.com/api/v4");
+    }
+
+    #[tokio::test]
+    async fn test_custom_api_path_mock() {
+        let addr = mock_server(vec![("/mounted/v4/projects/1/variables", "[]")]).await;
+        let api = GApi::new(&addr, "token").with_api_path("mounted/v4");
+        let variables = api.resource_by_id(1, &Resource::Repo).variables().await;
+        assert!(variables.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_resource_by_id() {
+        let api = GApi::new("gitlab.com", "token");
+        let resource = api.resource_by_id(123, &Resource::Repo);
+        assert_eq!(
+            resource.variables_url(),
+            "https://gitlab.com/api/v4/projects/123/variables"
+        );
+        let resource = api.resource_by_id(7, &Resource::Group);
+        assert_eq!(
+            resource.variables_url(),
+            "https://gitlab.com/api/v4/groups/7/variables"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_group_without_triggers() {
+        // nothing listens here, the kind is refused before any request
+        let api = GApi::new("127.0.0.1:9", "token");
+        let resource = api.resource_by_id(5, &Resource::Group);
+        assert_eq!(resource.kind(), ResourceKind::Group);
+        let error = resource.triggers().await.unwrap_err();
+        assert_eq!(error.to_string(), "groups have no trigger tokens");
+        let project = api.resource_by_id(5, &Resource::Repo);
+        assert_eq!(project.kind(), ResourceKind::Project);
+    }
+
+    #[test]
+    fn test_scheme() {
+        let meta = GitlabResourceMeta {
+            id: 42,
+            ..Default::default()
+        };
+        let api = GApi::new("gitlab.com", "token").with_scheme("http");
+        assert_eq!(
+            api.project_resource(meta.clone()).url(),
+            "http://gitlab.com/api/v4/projects/42"
+        );
+
+        let api = GApi::new("localhost:8080", "token");
+        assert_eq!(
+            api.group_resource(meta.clone()).url(),
+            "http://localhost:8080/api/v4/groups/42"
+        );
+
+        let api = GApi::new("127.0.0.1", "token");
+        assert_eq!(api.api_base(), "http://127.0.0.1/api/v4");
     }
 }
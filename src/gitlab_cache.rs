@@ -1,57 +1,239 @@
-use crate::cache::Cache;
+use crate::cache::{Cache, CacheFormat};
 use crate::crypto::EncryptedRW;
-use crate::gapi::{GitlabResourceMeta, GitlabVariable};
+use crate::gapi::{GApi, GitlabResourceMeta, GitlabUser, GitlabVariable, GroupStore, UserStore};
+use crate::uri_meta::UriMeta;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::Duration;
 
 pub type ResourceIdentifier = String;
 
+/// How much a resource cache holds.
+#[derive(Debug, PartialEq)]
+pub struct CacheStats {
+    pub resources: usize,
+    pub variables: usize,
+}
+
 #[derive(Serialize, Deserialize, Default, Clone)]
 pub struct Resource {
     pub meta: GitlabResourceMeta,
     pub variables: Vec<GitlabVariable>,
+    // unix seconds, missing from caches written before it was recorded
+    #[serde(default)]
+    pub fetched_at: Option<u64>,
+    // the gitlab instance, missing from caches written before it was recorded
+    #[serde(default)]
+    pub domain: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Default)]
 struct ResourceMap {
     data: HashMap<ResourceIdentifier, Resource>,
+    // tag -> copy of the resource when it was taken, never overwritten
+    #[serde(default)]
+    snapshots: HashMap<ResourceIdentifier, HashMap<String, Resource>>,
+}
+
+/// The identifier a resource is cached under.
+pub fn identifier(meta: &GitlabResourceMeta) -> ResourceIdentifier {
+    if let Some(path) = &meta.full_path {
+        return path.to_string();
+    } else if let Some(path) = &meta.path_with_namespace {
+        return path.to_string();
+    }
+    panic!("gitlab resource with no path")
+}
+
+pub type Normalizer = fn(&str) -> ResourceIdentifier;
+
+/// Canonical form of an identifier, gitlab paths are case insensitive and
+/// may be given with a domain, `.git` suffix or stray slashes.
+pub fn normalize(identifier: &str) -> ResourceIdentifier {
+    let lowered = identifier.to_lowercase();
+    let mut tokens: Vec<&str> = lowered.split('/').filter(|t| !t.is_empty()).collect();
+    if tokens.len() > 1 && tokens[0].starts_with("gitlab") && tokens[0].contains('.') {
+        tokens.remove(0);
+    }
+    if let Some(last) = tokens.last_mut() {
+        *last = last.strip_suffix(".git").unwrap_or(last);
+    }
+    tokens.join("/")
 }
 
 pub struct CachedResources<Crypto: EncryptedRW> {
     inner: Cache<ResourceMap, Crypto>,
+    normalize: Normalizer,
+    domain: Option<String>,
 }
 
 impl<Crypto: EncryptedRW> CachedResources<Crypto> {
+    /// Whether `file` decrypts to cached resources, leaving it untouched.
+    pub fn verify(file: &Crypto) -> crate::cache::Integrity {
+        crate::cache::verify::<ResourceMap>(file)
+    }
+
     pub fn new(on_disk: Crypto) -> Self {
         Self {
             inner: Cache::new(on_disk),
+            normalize,
+            domain: None,
+        }
+    }
+
+    /// A cache that starts out empty and is never written to disk.
+    pub fn ephemeral(on_disk: Crypto) -> Self {
+        Self {
+            inner: Cache::ephemeral(on_disk),
+            normalize,
+            domain: None,
         }
     }
 
-    pub fn insert(&mut self, meta: &GitlabResourceMeta, variables: &[GitlabVariable]) {
+    /// Writes the cache in `format`, see [`CacheFormat`].
+    pub fn with_format(mut self, format: CacheFormat) -> Self {
+        self.inner = self.inner.with_format(format);
+        self
+    }
+
+    /// Records `domain` as the instance of every resource inserted from now on.
+    pub fn with_domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    /// Replaces how identifiers are canonicalized before insert and lookup.
+    pub fn with_normalizer(mut self, normalize: Normalizer) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Caches the variables of a resource, returning the identifier it was
+    /// stored under.
+    pub fn insert(
+        &mut self,
+        meta: &GitlabResourceMeta,
+        variables: &[GitlabVariable],
+    ) -> ResourceIdentifier {
         let resource = Resource {
             meta: meta.clone(),
             variables: variables.to_vec(),
+            fetched_at: Some(crate::oauth::now()),
+            domain: self.domain.clone(),
         };
-        let identifier = 'a: {
-            if let Some(path) = &meta.full_path {
-                break 'a path;
-            } else if let Some(path) = &meta.path_with_namespace {
-                break 'a path;
+        let identifier = (self.normalize)(&identifier(meta));
+        self.inner.in_mem.data.insert(identifier.clone(), resource);
+        if let Err(e) = self.inner.update() {
+            tracing::warn!("failed to cache resource map: {e}");
+        }
+        identifier
+    }
+
+    /// Caches a freshly resolved resource under its canonical path, moving
+    /// `uri` over to it and dropping any entry left under the typed path.
+    pub fn insert_resolved(
+        &mut self,
+        uri: &mut UriMeta,
+        meta: &GitlabResourceMeta,
+        variables: &[GitlabVariable],
+    ) -> ResourceIdentifier {
+        let canonical = identifier(meta);
+        if let Some(typed) = uri.canonicalize(&canonical) {
+            let typed = (self.normalize)(&typed);
+            if typed != (self.normalize)(&canonical) && self.remove(&typed).is_some() {
+                tracing::debug!(typed, canonical, "dropped stale cache entry");
             }
-            panic!("gitlab resource with no path")
-        };
-        self.inner
+        }
+        self.insert(meta, variables)
+    }
+
+    pub fn remove(&mut self, identifier: &ResourceIdentifier) -> Option<Resource> {
+        let identifier = (self.normalize)(identifier);
+        let removed = self.inner.in_mem.data.remove(&identifier)?;
+        if let Err(e) = self.inner.update() {
+            tracing::warn!("failed to cache resource map: {e}");
+        }
+        Some(removed)
+    }
+
+    pub fn get(&self, identifier: &ResourceIdentifier) -> Option<Resource> {
+        let identifier = (self.normalize)(identifier);
+        self.inner.in_mem.data.get(&identifier).cloned()
+    }
+
+    /// Keeps the cached variables of `identifier` as they are now under
+    /// `tag`, a tag once taken can't be reused.
+    pub fn snapshot(&mut self, identifier: &ResourceIdentifier, tag: &str) -> anyhow::Result<()> {
+        let identifier = (self.normalize)(identifier);
+        let resource = self
+            .inner
             .in_mem
             .data
-            .insert(identifier.to_string(), resource);
-        if self.inner.update().is_err() {
-            println!("failed to cache resource map");
+            .get(&identifier)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("{identifier} is not cached"))?;
+        let tags = self
+            .inner
+            .in_mem
+            .snapshots
+            .entry(identifier.clone())
+            .or_default();
+        if tags.contains_key(tag) {
+            anyhow::bail!("{identifier} already has a snapshot {tag}")
         }
+        tags.insert(tag.to_string(), resource);
+        self.inner.update()
     }
 
-    pub fn get(&self, identifier: &ResourceIdentifier) -> Option<Resource> {
-        self.inner.in_mem.data.get(identifier).cloned()
+    pub fn get_snapshot(&self, identifier: &ResourceIdentifier, tag: &str) -> Option<Resource> {
+        let identifier = (self.normalize)(identifier);
+        self.inner
+            .in_mem
+            .snapshots
+            .get(&identifier)?
+            .get(tag)
+            .cloned()
+    }
+
+    pub fn contains(&self, identifier: &ResourceIdentifier) -> bool {
+        let identifier = (self.normalize)(identifier);
+        self.inner.in_mem.data.contains_key(&identifier)
+    }
+
+    /// The cached variables of `identifier` in the given environment scope.
+    pub fn get_variables_for_scope(
+        &self,
+        identifier: &ResourceIdentifier,
+        scope: &str,
+    ) -> Option<Vec<GitlabVariable>> {
+        let resource = self.inner.in_mem.data.get(&(self.normalize)(identifier))?;
+        Some(
+            resource
+                .variables
+                .iter()
+                .filter(|v| v.scope() == scope)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// The cached variables of the groups above `identifier`, outermost
+    /// first so that nearer groups override, as [`ancestors`] lists them.
+    pub fn inherited(&self, identifier: &ResourceIdentifier) -> Vec<GitlabVariable> {
+        ancestors(&(self.normalize)(identifier))
+            .iter()
+            .filter_map(|ancestor| self.get(ancestor))
+            .flat_map(|group| group.variables)
+            .collect()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let data = &self.inner.in_mem.data;
+        CacheStats {
+            resources: data.len(),
+            variables: data.values().map(|r| r.variables.len()).sum(),
+        }
     }
 
     pub fn list(&self) -> Vec<ResourceIdentifier> {
@@ -63,3 +245,707 @@ impl<Crypto: EncryptedRW> CachedResources<Crypto> {
             .collect()
     }
 }
+
+/// The paths of the groups above `identifier`, outermost first.
+pub fn ancestors(identifier: &str) -> Vec<ResourceIdentifier> {
+    let tokens: Vec<&str> = identifier.split('/').collect();
+    (1..tokens.len())
+        .map(|depth| tokens[..depth].join("/"))
+        .collect()
+}
+
+/// Fetches the variables of the groups above `meta` and caches them next
+/// to it, returning the identifiers they were stored under.
+pub async fn cache_ancestors<Crypto: EncryptedRW>(
+    cache: &mut CachedResources<Crypto>,
+    api: &GApi,
+    meta: &GitlabResourceMeta,
+) -> anyhow::Result<Vec<ResourceIdentifier>> {
+    let ancestors = api.ancestor_variables(meta).await?;
+    Ok(ancestors
+        .iter()
+        .map(|(group, variables)| cache.insert(group, variables))
+        .collect())
+}
+
+/// Drops the cached resources of the domain of `api` it no longer finds,
+/// returning what was pruned, or with `dry_run` what would be. Resources of
+/// other domains, or of none recorded, and failed checks keep the entry.
+pub async fn prune<Crypto: EncryptedRW>(
+    cache: &mut CachedResources<Crypto>,
+    api: &GApi,
+    dry_run: bool,
+) -> Vec<ResourceIdentifier> {
+    let mut identifiers = cache.list();
+    identifiers.sort();
+    let mut pruned = Vec::new();
+    for identifier in identifiers {
+        let Some(resource) = cache.get(&identifier) else {
+            continue;
+        };
+        if resource.domain.as_deref() != Some(api.domain()) {
+            continue;
+        }
+        match api.still_exists(&resource.meta).await {
+            Ok(true) => (),
+            Ok(false) => {
+                if !dry_run {
+                    cache.remove(&identifier);
+                }
+                pruned.push(identifier);
+            }
+            Err(e) => tracing::warn!("kept {identifier}, failed to check it: {e:#}"),
+        }
+    }
+    pruned
+}
+
+/// Thread safe [`CachedResources`] for long running processes, the file is
+/// decrypted once on first use and kept in memory from then on. Inserts
+/// write through to disk, keeping memory and disk in step.
+pub struct SharedResources<Crypto: EncryptedRW> {
+    file: Mutex<Option<Crypto>>,
+    loaded: OnceLock<RwLock<CachedResources<Crypto>>>,
+}
+
+impl<Crypto: EncryptedRW> SharedResources<Crypto> {
+    pub fn new(on_disk: Crypto) -> Self {
+        Self {
+            file: Mutex::new(Some(on_disk)),
+            loaded: OnceLock::new(),
+        }
+    }
+
+    fn inner(&self) -> &RwLock<CachedResources<Crypto>> {
+        self.loaded.get_or_init(|| {
+            let file = self.file.lock().unwrap().take();
+            RwLock::new(CachedResources::new(file.expect("cache loaded twice")))
+        })
+    }
+
+    pub fn get(&self, identifier: &ResourceIdentifier) -> Option<Resource> {
+        self.inner().read().unwrap().get(identifier)
+    }
+
+    pub fn contains(&self, identifier: &ResourceIdentifier) -> bool {
+        self.inner().read().unwrap().contains(identifier)
+    }
+
+    pub fn insert(
+        &self,
+        meta: &GitlabResourceMeta,
+        variables: &[GitlabVariable],
+    ) -> ResourceIdentifier {
+        self.inner().write().unwrap().insert(meta, variables)
+    }
+
+    pub fn list(&self) -> Vec<ResourceIdentifier> {
+        self.inner().read().unwrap().list()
+    }
+}
+
+// how far behind an entry the clock may be, as after an ntp correction,
+// for the entry to still count as just fetched
+const MAX_SKEW: u64 = 5 * 60;
+
+/// Whether an entry fetched at `fetched_at` is younger than `ttl` at `now`,
+/// all in unix seconds. An entry from the future means the clock went back,
+/// by up to [`MAX_SKEW`] it is taken as just fetched. Further off its age
+/// can't be told, and rather than fresh until the clock catches up it is
+/// stale.
+fn is_fresh(fetched_at: u64, ttl: Duration, now: u64) -> bool {
+    match now.checked_sub(fetched_at) {
+        Some(age) => age < ttl.as_secs(),
+        None if fetched_at - now <= MAX_SKEW => true,
+        None => {
+            let ahead = Duration::from_secs(fetched_at - now);
+            tracing::warn!(
+                "cache entry is dated {} ahead, the clock went back, refetching",
+                humantime::format_duration(ahead)
+            );
+            false
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct GroupMap {
+    data: HashMap<String, CachedGroups>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CachedGroups {
+    // unix seconds
+    fetched_at: u64,
+    groups: Vec<GitlabResourceMeta>,
+}
+
+/// Group listings by domain, reused for `ttl` after they were fetched.
+pub struct GroupCache<Crypto: EncryptedRW> {
+    inner: Mutex<Cache<GroupMap, Crypto>>,
+    ttl: Duration,
+}
+
+impl<Crypto: EncryptedRW> GroupCache<Crypto> {
+    pub fn new(on_disk: Crypto, ttl: Duration) -> Self {
+        Self {
+            inner: Mutex::new(Cache::new(on_disk)),
+            ttl,
+        }
+    }
+
+    /// A cache that starts out empty and is never written to disk.
+    pub fn ephemeral(on_disk: Crypto, ttl: Duration) -> Self {
+        Self {
+            inner: Mutex::new(Cache::ephemeral(on_disk)),
+            ttl,
+        }
+    }
+}
+
+impl<Crypto: EncryptedRW + Send> GroupStore for GroupCache<Crypto> {
+    fn get(&self, domain: &str) -> Option<Vec<GitlabResourceMeta>> {
+        let inner = self.inner.lock().unwrap();
+        let cached = inner.in_mem.data.get(domain)?;
+        is_fresh(cached.fetched_at, self.ttl, crate::oauth::now()).then(|| cached.groups.clone())
+    }
+
+    fn put(&self, domain: &str, groups: &[GitlabResourceMeta]) {
+        let mut inner = self.inner.lock().unwrap();
+        let cached = CachedGroups {
+            fetched_at: crate::oauth::now(),
+            groups: groups.to_vec(),
+        };
+        inner.in_mem.data.insert(domain.to_string(), cached);
+        if let Err(e) = inner.update() {
+            tracing::warn!("failed to cache groups: {e}");
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct UserMap {
+    data: HashMap<String, CachedUser>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CachedUser {
+    // unix seconds
+    fetched_at: u64,
+    // tells the token the user was fetched with apart, without keeping it
+    token: u64,
+    user: GitlabUser,
+}
+
+// only ever compared with itself, a changed hash merely refetches the user
+fn fingerprint(token: &str) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    token.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The user of each domain's token, reused for `ttl` after it was fetched
+/// and dropped once the token changes.
+pub struct UserCache<Crypto: EncryptedRW> {
+    inner: Mutex<Cache<UserMap, Crypto>>,
+    ttl: Duration,
+}
+
+impl<Crypto: EncryptedRW> UserCache<Crypto> {
+    pub fn new(on_disk: Crypto, ttl: Duration) -> Self {
+        Self {
+            inner: Mutex::new(Cache::new(on_disk)),
+            ttl,
+        }
+    }
+
+    /// A cache that starts out empty and is never written to disk.
+    pub fn ephemeral(on_disk: Crypto, ttl: Duration) -> Self {
+        Self {
+            inner: Mutex::new(Cache::ephemeral(on_disk)),
+            ttl,
+        }
+    }
+}
+
+impl<Crypto: EncryptedRW + Send> UserStore for UserCache<Crypto> {
+    fn get(&self, domain: &str, token: &str) -> Option<GitlabUser> {
+        let inner = self.inner.lock().unwrap();
+        let cached = inner.in_mem.data.get(domain)?;
+        let fresh = is_fresh(cached.fetched_at, self.ttl, crate::oauth::now());
+        (fresh && cached.token == fingerprint(token)).then(|| cached.user.clone())
+    }
+
+    fn put(&self, domain: &str, token: &str, user: &GitlabUser) {
+        let mut inner = self.inner.lock().unwrap();
+        let cached = CachedUser {
+            fetched_at: crate::oauth::now(),
+            token: fingerprint(token),
+            user: user.clone(),
+        };
+        inner.in_mem.data.insert(domain.to_string(), cached);
+        if let Err(e) = inner.update() {
+            tracing::warn!("failed to cache user: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::CryptoError;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Default, Clone)]
+    struct MemoryFile(Rc<RefCell<Option<Vec<u8>>>>);
+
+    impl EncryptedRW for MemoryFile {
+        fn read(&self) -> Result<Vec<u8>, CryptoError> {
+            let content = self.0.borrow().clone();
+            content.ok_or(std::io::Error::from(std::io::ErrorKind::NotFound).into())
+        }
+
+        fn write(&self, content: &[u8]) -> Result<(), CryptoError> {
+            *self.0.borrow_mut() = Some(content.to_vec());
+            Ok(())
+        }
+
+        fn path(&self) -> String {
+            "memory".to_string()
+        }
+    }
+
+    fn var(key: &str, value: &str, scope: Option<&str>) -> GitlabVariable {
+        GitlabVariable {
+            key: key.to_string(),
+            value: value.to_string(),
+            environment_scope: scope.map(|s| s.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_ephemeral() {
+        let file = MemoryFile::default();
+        let mut cache = CachedResources::ephemeral(file.clone());
+
+        let meta = GitlabResourceMeta {
+            id: 1,
+            path_with_namespace: Some("org/project".to_string()),
+            ..Default::default()
+        };
+        let identifier = cache.insert(&meta, &[var("HOST", "localhost", None)]);
+        assert_eq!(cache.get(&identifier).unwrap().variables.len(), 1);
+        assert!(file.0.borrow().is_none(), "nothing written to disk");
+    }
+
+    #[cfg(not(feature = "msgpack"))]
+    #[test]
+    fn test_msgpack_cache_kept() {
+        // an empty map, as written by a build with msgpack
+        let file = MemoryFile(Rc::new(RefCell::new(Some(vec![0x80]))));
+        let mut cache = CachedResources::new(file.clone());
+        assert_eq!(cache.stats().resources, 0);
+        let meta = GitlabResourceMeta {
+            path_with_namespace: Some("org/api".into()),
+            ..Default::default()
+        };
+        cache.insert(&meta, &[var("HOST", "localhost", None)]);
+        assert_eq!(*file.0.borrow(), Some(vec![0x80]), "not overwritten");
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack_round_trip() {
+        let file = MemoryFile::default();
+        let mut cache = CachedResources::new(file.clone()).with_format(CacheFormat::Msgpack);
+        for id in 0..20 {
+            let meta = GitlabResourceMeta {
+                id,
+                path_with_namespace: Some(format!("org/project-{id}")),
+                ..Default::default()
+            };
+            let mut masked = var("TOKEN", &"s".repeat(id as usize * 20), Some("production"));
+            masked.masked = true;
+            cache.insert(&meta, &[var("HOST", "localhost", None), masked]);
+        }
+        cache.snapshot(&"org/project-3".to_string(), "v1").unwrap();
+        let encoded = file.0.borrow().clone().unwrap();
+        assert!(encoded[0] >= 0x80, "written as msgpack");
+        assert_eq!(
+            CachedResources::verify(&file),
+            crate::cache::Integrity::Valid
+        );
+
+        // detected on read, whatever the reader writes
+        let mut read = CachedResources::new(file.clone());
+        assert_eq!(read.stats(), cache.stats());
+        for identifier in cache.list() {
+            let (written, back) = (
+                cache.get(&identifier).unwrap(),
+                read.get(&identifier).unwrap(),
+            );
+            assert_eq!(back.meta.id, written.meta.id);
+            assert_eq!(back.variables, written.variables);
+            assert_eq!(back.fetched_at, written.fetched_at);
+        }
+        let snapshot = read.get_snapshot(&"org/project-3".to_string(), "v1");
+        assert_eq!(snapshot.unwrap().variables[1].value, "s".repeat(60));
+
+        read.remove(&"org/project-0".to_string());
+        let json = file.0.borrow().clone().unwrap();
+        assert_eq!(json[0], b'{', "rewritten as json");
+        assert!(encoded.len() < json.len());
+    }
+
+    #[derive(Default)]
+    struct CountingFile {
+        content: Mutex<Option<Vec<u8>>>,
+        reads: std::sync::atomic::AtomicUsize,
+    }
+
+    impl EncryptedRW for std::sync::Arc<CountingFile> {
+        fn read(&self) -> Result<Vec<u8>, CryptoError> {
+            self.reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let content = self.content.lock().unwrap().clone();
+            content.ok_or(std::io::Error::from(std::io::ErrorKind::NotFound).into())
+        }
+
+        fn write(&self, content: &[u8]) -> Result<(), CryptoError> {
+            *self.content.lock().unwrap() = Some(content.to_vec());
+            Ok(())
+        }
+
+        fn path(&self) -> String {
+            "counting".to_string()
+        }
+    }
+
+    #[test]
+    fn test_shared_single_decrypt() {
+        let file = std::sync::Arc::new(CountingFile::default());
+        let shared = SharedResources::new(file.clone());
+
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                let shared = &shared;
+                s.spawn(move || {
+                    for i in 0..50 {
+                        let path = format!("org/project-{}", (t + i) % 5);
+                        if !shared.contains(&path) {
+                            let meta = GitlabResourceMeta {
+                                id: i,
+                                path_with_namespace: Some(path.clone()),
+                                ..Default::default()
+                            };
+                            shared.insert(&meta, &[var("HOST", "localhost", None)]);
+                        }
+                        assert!(shared.get(&path).is_some());
+                    }
+                });
+            }
+        });
+
+        assert_eq!(shared.list().len(), 5);
+        assert_eq!(file.reads.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(file.content.lock().unwrap().is_some(), "inserts reach disk");
+    }
+
+    #[test]
+    fn test_normalize() {
+        let equivalent = [
+            "org/repo",
+            "GitLab.com/Org/Repo.git",
+            "gitlab.com/org/repo",
+            "org/repo/",
+            "/Org//Repo.git",
+        ];
+        for identifier in equivalent {
+            assert_eq!(normalize(identifier), "org/repo", "{identifier}");
+        }
+        assert_eq!(normalize("my.group/repo"), "my.group/repo");
+    }
+
+    #[test]
+    fn test_insert_resolved_canonical() {
+        let mut cache = CachedResources::new(MemoryFile::default());
+        let old = GitlabResourceMeta {
+            id: 1,
+            path_with_namespace: Some("org/old-name".to_string()),
+            ..Default::default()
+        };
+        cache.insert(&old, &[var("HOST", "stale", None)]);
+
+        // the project was renamed, gitlab still resolves the old path
+        let mut uri = UriMeta {
+            identifier: "org/old-name".to_string(),
+            tokens: vec!["org".into(), "old-name".into()],
+            ..Default::default()
+        };
+        let renamed = GitlabResourceMeta {
+            path_with_namespace: Some("org/new-name".to_string()),
+            ..old
+        };
+        let identifier = cache.insert_resolved(&mut uri, &renamed, &[var("HOST", "fresh", None)]);
+        assert_eq!(identifier, "org/new-name");
+        assert_eq!(uri.identifier, "org/new-name");
+        assert_eq!(cache.list(), vec!["org/new-name".to_string()]);
+        assert_eq!(cache.get(&identifier).unwrap().variables[0].value, "fresh");
+
+        // same path in another case, nothing to drop
+        let mut uri = UriMeta {
+            identifier: "Org/New-Name".to_string(),
+            ..Default::default()
+        };
+        cache.insert_resolved(&mut uri, &renamed, &[]);
+        assert_eq!(cache.list(), vec!["org/new-name".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_prune() {
+        let addr = crate::gapi::tests::mock_server(vec![(
+            "/api/v4/projects/org%2Fapi",
+            r#"{"id": 1, "path_with_namespace": "org/api"}"#,
+        )])
+        .await;
+        let api = GApi::new(&addr, "token");
+        let project = |path: &str| GitlabResourceMeta {
+            path_with_namespace: Some(path.into()),
+            ..Default::default()
+        };
+        let mut cache = CachedResources::new(MemoryFile::default()).with_domain("gitlab.other.org");
+        cache.insert(&project("other/app"), &[var("A", "1", None)]);
+        let mut cache = cache.with_domain(&addr);
+        cache.insert(&project("org/api"), &[var("A", "1", None)]);
+        cache.insert(&project("org/deleted"), &[var("A", "1", None)]);
+
+        assert_eq!(prune(&mut cache, &api, true).await, vec!["org/deleted"]);
+        assert_eq!(cache.stats().resources, 3, "dry run keeps entries");
+        assert_eq!(prune(&mut cache, &api, false).await, vec!["org/deleted"]);
+        let mut listed = cache.list();
+        listed.sort();
+        assert_eq!(
+            listed,
+            vec!["org/api", "other/app"],
+            "other domains are kept"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache_ancestors() {
+        let addr = crate::gapi::tests::mock_server(vec![
+            ("/api/v4/groups/org", r#"{"id": 1, "full_path": "org"}"#),
+            ("/api/v4/groups/org%2Fsub", r#"{"id": 2, "full_path": "org/sub"}"#),
+            (
+                "/api/v4/groups/1/variables",
+                r#"[{"key": "REGISTRY", "value": "registry.org"}, {"key": "HOST", "value": "org"}]"#,
+            ),
+            (
+                "/api/v4/groups/2/variables",
+                r#"[{"key": "HOST", "value": "sub"}]"#,
+            ),
+        ])
+        .await;
+        let api = GApi::new(&addr, "token");
+        let mut cache = CachedResources::new(MemoryFile::default());
+        let project = GitlabResourceMeta {
+            id: 3,
+            path_with_namespace: Some("org/sub/app".into()),
+            ..Default::default()
+        };
+        cache.insert(&project, &[var("HOST", "app", None)]);
+
+        let cached = cache_ancestors(&mut cache, &api, &project).await.unwrap();
+        assert_eq!(cached, ["org", "org/sub"]);
+        let mut listed = cache.list();
+        listed.sort();
+        assert_eq!(listed, ["org", "org/sub", "org/sub/app"]);
+
+        let identifier = "org/sub/app".to_string();
+        let inherited = cache.inherited(&identifier);
+        let values: Vec<_> = inherited.iter().map(|v| v.value.as_str()).collect();
+        assert_eq!(values, ["registry.org", "org", "sub"]);
+        let mut variables = inherited;
+        variables.extend(cache.get(&identifier).unwrap().variables);
+        let effective = crate::output::effective_variables(&variables, None);
+        assert_eq!(effective["HOST"].value, "app");
+        assert_eq!(effective["REGISTRY"].value, "registry.org");
+        // get-var and extract with --with-ancestors print the project's own
+        let host = crate::output::get_var(&variables, "HOST", None, false).unwrap();
+        assert_eq!(host, "app");
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let file = MemoryFile::default();
+        let mut cache = CachedResources::new(file.clone());
+        let meta = GitlabResourceMeta {
+            path_with_namespace: Some("org/api".into()),
+            ..Default::default()
+        };
+        let identifier = "org/api".to_string();
+        assert!(
+            cache.snapshot(&identifier, "release-1.2").is_err(),
+            "not cached"
+        );
+
+        cache.insert(&meta, &[var("VERSION", "1.2", None)]);
+        cache.snapshot(&identifier, "release-1.2").unwrap();
+        cache.insert(&meta, &[var("VERSION", "1.3", None)]);
+        assert!(
+            cache.snapshot(&identifier, "release-1.2").is_err(),
+            "immutable"
+        );
+
+        let reloaded = CachedResources::new(file);
+        let snapshot = reloaded
+            .get_snapshot(&"Org/API".to_string(), "release-1.2")
+            .unwrap();
+        assert_eq!(snapshot.variables, vec![var("VERSION", "1.2", None)]);
+        assert_eq!(reloaded.get(&identifier).unwrap().variables[0].value, "1.3");
+        assert!(reloaded.get_snapshot(&identifier, "release-1.3").is_none());
+    }
+
+    #[test]
+    fn test_stats() {
+        let file = MemoryFile::default();
+        let mut cache = CachedResources::new(file.clone());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                resources: 0,
+                variables: 0
+            }
+        );
+        let meta = |path: &str| GitlabResourceMeta {
+            full_path: Some(path.into()),
+            ..Default::default()
+        };
+        cache.insert(
+            &meta("org/api"),
+            &[var("A", "1", None), var("B", "2", None)],
+        );
+        cache.insert(&meta("org/web"), &[var("A", "1", Some("production"))]);
+        cache.insert(&meta("org/empty"), &[]);
+        let reloaded = CachedResources::new(file);
+        assert_eq!(
+            reloaded.stats(),
+            CacheStats {
+                resources: 3,
+                variables: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_group_ttl() {
+        let file = std::sync::Arc::new(CountingFile {
+            content: Mutex::new(None),
+            reads: Default::default(),
+        });
+        let groups = vec![GitlabResourceMeta {
+            id: 1,
+            full_path: Some("org".into()),
+            ..Default::default()
+        }];
+        let fresh = GroupCache::new(file.clone(), Duration::from_secs(60));
+        fresh.put("gitlab.com", &groups);
+        assert_eq!(fresh.get("gitlab.com").unwrap()[0].id, 1);
+        assert!(fresh.get("gitlab.example.com").is_none());
+        // reloaded from disk, but already past its ttl
+        let stale = GroupCache::new(file, Duration::ZERO);
+        assert!(stale.get("gitlab.com").is_none());
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let ttl = Duration::from_secs(60);
+        assert!(is_fresh(1000, ttl, 1059));
+        assert!(!is_fresh(1000, ttl, 1060));
+        // the clock went back a little, or a lot
+        assert!(is_fresh(1000 + MAX_SKEW, ttl, 1000));
+        assert!(!is_fresh(1001 + MAX_SKEW, ttl, 1000));
+
+        let file = std::sync::Arc::new(CountingFile::default());
+        let groups = GroupCache::ephemeral(file, Duration::from_secs(3600));
+        groups.put("gitlab.com", &[GitlabResourceMeta::default()]);
+        let jump_back = |by: u64| {
+            let mut inner = groups.inner.lock().unwrap();
+            let cached = inner.in_mem.data.get_mut("gitlab.com").unwrap();
+            cached.fetched_at = crate::oauth::now() + by;
+        };
+        jump_back(30);
+        assert!(groups.get("gitlab.com").is_some());
+        // an hour ahead would otherwise stay fresh for two hours
+        jump_back(3600);
+        assert!(groups.get("gitlab.com").is_none());
+    }
+
+    #[test]
+    fn test_normalized_lookup() {
+        let mut cache = CachedResources::new(MemoryFile::default());
+        let meta = GitlabResourceMeta {
+            id: 1,
+            path_with_namespace: Some("Org/Repo".to_string()),
+            ..Default::default()
+        };
+        cache.insert(&meta, &[var("HOST", "localhost", None)]);
+
+        assert_eq!(cache.list(), vec!["org/repo".to_string()]);
+        for identifier in [
+            "GitLab.com/Org/Repo.git",
+            "gitlab.com/org/repo",
+            "org/repo/",
+        ] {
+            assert!(cache.contains(&identifier.to_string()), "{identifier}");
+            assert!(cache.get(&identifier.to_string()).is_some(), "{identifier}");
+        }
+
+        // a custom normalizer replaces the default one
+        let mut exact =
+            CachedResources::new(MemoryFile::default()).with_normalizer(|i| i.to_string());
+        exact.insert(&meta, &[]);
+        assert!(exact.contains(&"Org/Repo".to_string()));
+        assert!(!exact.contains(&"org/repo".to_string()));
+    }
+
+    #[test]
+    fn test_variables_for_scope() {
+        let mut cache = CachedResources::new(MemoryFile::default());
+        let meta = GitlabResourceMeta {
+            id: 1,
+            path_with_namespace: Some("org/project".to_string()),
+            ..Default::default()
+        };
+        let identifier = cache.insert(
+            &meta,
+            &[
+                var("HOST", "localhost", Some("*")),
+                var("HOST", "prod.example.com", Some("production")),
+                var("PORT", "8080", None),
+                var("DEBUG", "true", Some("staging")),
+            ],
+        );
+
+        let production = cache
+            .get_variables_for_scope(&identifier, "production")
+            .unwrap();
+        assert_eq!(
+            production,
+            vec![var("HOST", "prod.example.com", Some("production"))]
+        );
+
+        let global = cache.get_variables_for_scope(&identifier, "*").unwrap();
+        assert_eq!(global.len(), 2);
+
+        let missing = cache
+            .get_variables_for_scope(&identifier, "review")
+            .unwrap();
+        assert!(missing.is_empty());
+        assert!(cache
+            .get_variables_for_scope(&"org/other".to_string(), "*")
+            .is_none());
+    }
+}
@@ -1,7 +1,18 @@
+pub mod agent;
+pub mod apply;
 pub mod cache;
 pub mod cmdline;
+pub mod config;
 pub mod crypto;
+pub mod diff;
 pub mod gapi;
 pub mod gitlab_cache;
+pub mod lock;
+pub mod oauth;
+pub mod output;
+pub mod paths;
 pub mod token;
+pub mod transform;
 pub mod uri_meta;
+pub mod varfile;
+pub mod watch;
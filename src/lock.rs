@@ -0,0 +1,78 @@
+use anyhow::Context;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A lock between rvar processes, an advisory lock on a file. The OS
+/// releases it with the process however that ends, so no lock is left
+/// behind by one that exited or crashed.
+#[derive(Debug)]
+pub struct ProcessLock {
+    _file: File,
+}
+
+impl ProcessLock {
+    /// Takes the lock at `path`, failing right away if another process
+    /// holds it.
+    pub fn try_acquire(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("failed to create lock {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(TryLockError::WouldBlock) => {
+                anyhow::bail!("another rvar holds {}", path.display())
+            }
+            Err(TryLockError::Error(e)) => {
+                Err(e).with_context(|| format!("failed to lock {}", path.display()))
+            }
+        }
+    }
+
+    /// Takes the lock at `path`, waiting up to `timeout` for another process
+    /// to release it.
+    pub fn acquire(path: &Path, timeout: Duration) -> anyhow::Result<Self> {
+        let start = Instant::now();
+        let mut waiting = false;
+        loop {
+            match Self::try_acquire(path) {
+                Ok(lock) => return Ok(lock),
+                Err(e) if start.elapsed() >= timeout => return Err(e),
+                Err(e) => {
+                    if !waiting {
+                        eprintln!("{e}, waiting for it to finish");
+                        waiting = true;
+                    }
+                    std::thread::sleep(RETRY_INTERVAL);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_lock() {
+        let path = std::env::temp_dir().join(format!("ruggit-lock-{}", std::process::id()));
+
+        let lock = ProcessLock::try_acquire(&path).unwrap();
+        let error = ProcessLock::try_acquire(&path).unwrap_err();
+        assert!(error.to_string().contains("another rvar"));
+        assert!(ProcessLock::acquire(&path, Duration::from_millis(250)).is_err());
+        drop(lock);
+
+        // the file outlives the lock, it alone doesn't hold anyone up
+        assert!(path.exists());
+        let lock = ProcessLock::acquire(&path, Duration::ZERO).unwrap();
+        drop(lock);
+        std::fs::remove_file(path).unwrap();
+    }
+}
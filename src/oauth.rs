@@ -0,0 +1,198 @@
+use crate::gapi::ApiError;
+use crate::gapi::ClientSettings;
+use anyhow::Result;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// refresh a bit ahead of time so the token doesn't expire mid run
+const EXPIRY_MARGIN_SECS: u64 = 60;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OAuthToken {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub client_id: String,
+    // unix timestamp in seconds
+    pub expires_at: u64,
+}
+
+impl OAuthToken {
+    pub fn needs_refresh(&self, now: u64) -> bool {
+        self.expires_at <= now + EXPIRY_MARGIN_SECS
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Exchanges a refresh token for a new access token.
+pub trait Refresher {
+    fn refresh(&self, token: &OAuthToken) -> impl Future<Output = Result<OAuthToken>>;
+}
+
+/// Refreshes against the `/oauth/token` endpoint of a gitlab instance.
+pub struct GitlabRefresher {
+    base: String,
+    client: Client,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    expires_in: u64,
+}
+
+impl GitlabRefresher {
+    pub fn new(scheme: &str, domain: &str) -> Self {
+        Self {
+            base: format!("{}://{}", scheme, domain),
+            client: ClientSettings::default()
+                .build()
+                .expect("default http client"),
+        }
+    }
+
+    /// Rebuilds the http client with `settings`.
+    pub fn with_client_settings(mut self, settings: &ClientSettings) -> Result<Self> {
+        self.client = settings.build()?;
+        Ok(self)
+    }
+}
+
+impl Refresher for GitlabRefresher {
+    async fn refresh(&self, token: &OAuthToken) -> Result<OAuthToken> {
+        let params = [
+            ("grant_type", "refresh_token"),
+            ("refresh_token", &token.refresh_token),
+            ("client_id", &token.client_id),
+        ];
+        let response = self
+            .client
+            .post(format!("{}/oauth/token", self.base))
+            .form(&params)
+            .send()
+            .await?
+            .error_for_status()?;
+        let response: TokenResponse = serde_json::from_str(&response.text().await?)?;
+        Ok(OAuthToken {
+            access_token: response.access_token,
+            refresh_token: response.refresh_token,
+            client_id: token.client_id.clone(),
+            expires_at: now() + response.expires_in,
+        })
+    }
+}
+
+/// Refreshes `token` in place when it's about to expire, returns whether it
+/// was refreshed.
+pub async fn ensure_fresh(
+    token: &mut OAuthToken,
+    refresher: &impl Refresher,
+    now: u64,
+) -> Result<bool> {
+    if !token.needs_refresh(now) {
+        return Ok(false);
+    }
+    tracing::debug!("refreshing expiring oauth token");
+    *token = refresher.refresh(token).await?;
+    Ok(true)
+}
+
+/// Runs `call` with a fresh access token, refreshing and retrying once should
+/// the token still be rejected.
+pub async fn call_with_refresh<T>(
+    token: &mut OAuthToken,
+    refresher: &impl Refresher,
+    now: u64,
+    mut call: impl AsyncFnMut(String) -> Result<T>,
+) -> Result<T> {
+    ensure_fresh(token, refresher, now).await?;
+    match call(token.access_token.clone()).await {
+        Err(e) if matches!(e.downcast_ref(), Some(ApiError::Unauthorized)) => {
+            tracing::debug!("access token rejected, refreshing");
+            *token = refresher.refresh(token).await?;
+            call(token.access_token.clone()).await
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[derive(Default)]
+    struct MockRefresher {
+        calls: Cell<u32>,
+    }
+
+    impl Refresher for MockRefresher {
+        async fn refresh(&self, token: &OAuthToken) -> Result<OAuthToken> {
+            self.calls.set(self.calls.get() + 1);
+            Ok(OAuthToken {
+                access_token: format!("access-{}", self.calls.get()),
+                refresh_token: format!("refresh-{}", self.calls.get()),
+                client_id: token.client_id.clone(),
+                expires_at: 2000,
+            })
+        }
+    }
+
+    fn token(expires_at: u64) -> OAuthToken {
+        OAuthToken {
+            access_token: "access-0".to_string(),
+            refresh_token: "refresh-0".to_string(),
+            client_id: "client".to_string(),
+            expires_at,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ensure_fresh() {
+        let refresher = MockRefresher::default();
+
+        let mut valid = token(1000);
+        assert!(!ensure_fresh(&mut valid, &refresher, 500).await.unwrap());
+        assert_eq!(valid.access_token, "access-0");
+
+        // within the margin counts as expired
+        let mut expiring = token(1000);
+        assert!(ensure_fresh(&mut expiring, &refresher, 990).await.unwrap());
+        assert_eq!(expiring.access_token, "access-1");
+        assert_eq!(expiring.refresh_token, "refresh-1");
+        assert_eq!(refresher.calls.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_call_with_refresh() {
+        let refresher = MockRefresher::default();
+        let api = |access: String| async move {
+            match access.as_str() {
+                "access-1" => Ok(access),
+                _ => Err(ApiError::Unauthorized.into()),
+            }
+        };
+
+        // expired token is refreshed before the call
+        let mut expired = token(100);
+        let result = call_with_refresh(&mut expired, &refresher, 500, api).await;
+        assert_eq!(result.unwrap(), "access-1");
+        assert_eq!(refresher.calls.get(), 1);
+
+        // revoked but not expired, the rejected call is retried once
+        let refresher = MockRefresher::default();
+        let mut revoked = token(1000);
+        let result = call_with_refresh(&mut revoked, &refresher, 500, api).await;
+        assert_eq!(result.unwrap(), "access-1");
+        assert_eq!(revoked.access_token, "access-1");
+        assert_eq!(refresher.calls.get(), 1);
+    }
+}
@@ -0,0 +1,1313 @@
+use crate::config::Setting;
+use crate::gapi::{ApiError, GitlabResourceMeta, GitlabVariable, TokenStatus};
+use crate::gitlab_cache::{CacheStats, Resource};
+use crate::token::TokenReport;
+use anyhow::Context;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::str::FromStr;
+
+static DEFAULT_SCOPE: &str = "*";
+static REDACTED: &str = "[MASKED]";
+static HIDDEN: &str = "[HIDDEN]";
+
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub enum OutputFormat {
+    #[default]
+    Json,
+    // variables nested under their environment scope
+    Scoped,
+    // KEY=value lines
+    Dotenv,
+    // sorted keys only, safe to share
+    Keys,
+    // kubernetes Secret manifest
+    K8sSecret,
+    // key,value,scope,masked,protected rows
+    Csv,
+    // key to value json, the data of `vault kv put`
+    VaultKv,
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Json => "json",
+            Self::Scoped => "scoped",
+            Self::Dotenv => "dotenv",
+            Self::Keys => "keys",
+            Self::K8sSecret => "k8s-secret",
+            Self::Csv => "csv",
+            Self::VaultKv => "vault-kv",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "scoped" => Ok(Self::Scoped),
+            "dotenv" => Ok(Self::Dotenv),
+            "keys" => Ok(Self::Keys),
+            "k8s-secret" => Ok(Self::K8sSecret),
+            "csv" => Ok(Self::Csv),
+            "vault-kv" => Ok(Self::VaultKv),
+            other => anyhow::bail!("unknown output format: {other}"),
+        }
+    }
+}
+
+/// What the formats beyond the variables themselves depend on.
+#[derive(Default, Debug, Clone)]
+pub struct FormatOptions {
+    // environment to select scoped variables for
+    pub environment: Option<String>,
+    // include variable descriptions as comments
+    pub comments: bool,
+    // of the k8s-secret output
+    pub name: Option<String>,
+    pub namespace: Option<String>,
+    // expand references in the effective values, see `expand_references`
+    pub expand: bool,
+}
+
+/// Renders a resource in one output format.
+pub trait OutputFormatter {
+    fn format(&self, resource: &Resource) -> anyhow::Result<String>;
+}
+
+pub struct JsonFormatter;
+
+impl OutputFormatter for JsonFormatter {
+    fn format(&self, resource: &Resource) -> anyhow::Result<String> {
+        Ok(serde_json::to_string(resource)?)
+    }
+}
+
+pub struct ScopedFormatter;
+
+impl OutputFormatter for ScopedFormatter {
+    fn format(&self, resource: &Resource) -> anyhow::Result<String> {
+        Ok(serde_json::to_string(&group_by_scope(&resource.variables))?)
+    }
+}
+
+pub struct KeysFormatter;
+
+impl OutputFormatter for KeysFormatter {
+    fn format(&self, resource: &Resource) -> anyhow::Result<String> {
+        Ok(render_keys(&resource.variables))
+    }
+}
+
+pub struct CsvFormatter;
+
+impl OutputFormatter for CsvFormatter {
+    fn format(&self, resource: &Resource) -> anyhow::Result<String> {
+        Ok(render_csv(&resource.variables))
+    }
+}
+
+pub struct DotenvFormatter {
+    pub environment: Option<String>,
+    pub comments: bool,
+    pub expand: bool,
+}
+
+impl OutputFormatter for DotenvFormatter {
+    fn format(&self, resource: &Resource) -> anyhow::Result<String> {
+        let effective = effective(
+            &resource.variables,
+            self.environment.as_deref(),
+            self.expand,
+        );
+        Ok(render_dotenv(&effective, self.comments))
+    }
+}
+
+pub struct K8sSecretFormatter {
+    pub environment: Option<String>,
+    pub name: String,
+    pub namespace: Option<String>,
+    pub expand: bool,
+}
+
+impl OutputFormatter for K8sSecretFormatter {
+    fn format(&self, resource: &Resource) -> anyhow::Result<String> {
+        let effective = effective(
+            &resource.variables,
+            self.environment.as_deref(),
+            self.expand,
+        );
+        let variables: Vec<_> = effective.into_values().collect();
+        render_k8s_secret(&variables, &self.name, self.namespace.as_deref())
+    }
+}
+
+pub struct VaultKvFormatter {
+    pub environment: Option<String>,
+    pub expand: bool,
+}
+
+impl OutputFormatter for VaultKvFormatter {
+    fn format(&self, resource: &Resource) -> anyhow::Result<String> {
+        let effective = effective(
+            &resource.variables,
+            self.environment.as_deref(),
+            self.expand,
+        );
+        let variables: Vec<_> = effective.into_values().collect();
+        render_vault_kv(&variables)
+    }
+}
+
+impl OutputFormat {
+    /// The formatter rendering this format.
+    pub fn formatter(self, options: FormatOptions) -> Box<dyn OutputFormatter> {
+        match self {
+            Self::Json => Box::new(JsonFormatter),
+            Self::Scoped => Box::new(ScopedFormatter),
+            Self::Keys => Box::new(KeysFormatter),
+            Self::Csv => Box::new(CsvFormatter),
+            Self::Dotenv => Box::new(DotenvFormatter {
+                environment: options.environment,
+                comments: options.comments,
+                expand: options.expand,
+            }),
+            Self::K8sSecret => Box::new(K8sSecretFormatter {
+                environment: options.environment,
+                name: options.name.unwrap_or_default(),
+                namespace: options.namespace,
+                expand: options.expand,
+            }),
+            Self::VaultKv => Box::new(VaultKvFormatter {
+                environment: options.environment,
+                expand: options.expand,
+            }),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub enum ErrorFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => anyhow::bail!("unknown error format: {other}"),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub enum ColorChoice {
+    // decided by NO_COLOR, CLICOLOR_FORCE and whether it's a terminal
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            other => anyhow::bail!("unknown color choice: {other}"),
+        }
+    }
+}
+
+impl ColorChoice {
+    /// Whether to style output going to a terminal when `is_terminal`. Left
+    /// to `Auto`, a non empty NO_COLOR disables styling, otherwise a
+    /// CLICOLOR_FORCE other than 0 forces it even when piped.
+    pub fn enabled(self, env: impl Fn(&str) -> Option<String>, is_terminal: bool) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto if env("NO_COLOR").is_some_and(|v| !v.is_empty()) => false,
+            Self::Auto if env("CLICOLOR_FORCE").is_some_and(|v| !v.is_empty() && v != "0") => true,
+            Self::Auto => is_terminal,
+        }
+    }
+}
+
+#[derive(Serialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Usage,
+    Config,
+    Auth,
+    Resolution,
+    Timeout,
+    NotFound,
+    // the instance is up but refuses requests, such as during maintenance
+    Unavailable,
+    Internal,
+}
+
+impl ErrorKind {
+    /// The kind of `error`, falling back to `fallback` for errors that
+    /// don't carry one themselves.
+    pub fn of(error: &anyhow::Error, fallback: ErrorKind) -> ErrorKind {
+        match error.downcast_ref::<ApiError>() {
+            Some(ApiError::Unauthorized | ApiError::Forbidden(_)) => Self::Auth,
+            Some(ApiError::Timeout(_)) => Self::Timeout,
+            Some(ApiError::Redirected(..)) => Self::Config,
+            Some(ApiError::Maintenance(_) | ApiError::Unavailable(_)) => Self::Unavailable,
+            None => fallback,
+        }
+    }
+}
+
+/// Groups one per line, each followed by its projects indented below it.
+pub fn render_tree(groups: &[(GitlabResourceMeta, Vec<GitlabResourceMeta>)]) -> String {
+    let mut lines = vec![];
+    for (group, projects) in groups {
+        lines.push(group.full_path.clone().unwrap_or_default());
+        for project in projects {
+            let path = project.path_with_namespace.as_deref().unwrap_or_default();
+            lines.push(format!("  {path}"));
+        }
+    }
+    lines.join("\n")
+}
+
+/// The stable shape of errors reported with `--error-format json`.
+#[derive(Serialize, Debug)]
+pub struct ErrorReport {
+    pub error: String,
+    pub kind: ErrorKind,
+}
+
+pub fn render_error(error: &anyhow::Error, kind: ErrorKind, format: ErrorFormat) -> String {
+    match format {
+        ErrorFormat::Text => format!("{error:#}"),
+        ErrorFormat::Json => {
+            let report = ErrorReport {
+                error: format!("{error:#}"),
+                kind: ErrorKind::of(error, kind),
+            };
+            serde_json::to_string(&report).unwrap_or_default()
+        }
+    }
+}
+
+pub type ScopedVariables = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Groups variables by their environment scope, variables lacking a scope
+/// end up under "*".
+pub fn group_by_scope(variables: &[GitlabVariable]) -> ScopedVariables {
+    let mut scoped = ScopedVariables::new();
+    for variable in variables {
+        scoped
+            .entry(variable.scope().to_string())
+            .or_default()
+            .insert(
+                variable.key.clone(),
+                variable.readable_value().unwrap_or(HIDDEN).to_string(),
+            );
+    }
+    scoped
+}
+
+/// The effective values of several projects' variables, keyed by project
+/// path and then variable key.
+pub fn aggregate_by_project(
+    projects: &[(GitlabResourceMeta, Vec<GitlabVariable>)],
+    environment: Option<&str>,
+    expand: bool,
+) -> BTreeMap<String, BTreeMap<String, String>> {
+    projects
+        .iter()
+        .map(|(meta, variables)| {
+            let values = effective(variables, environment, expand)
+                .into_iter()
+                .map(|(key, v)| {
+                    let value = v.readable_value().unwrap_or(HIDDEN).to_string();
+                    (key, value)
+                })
+                .collect();
+            (meta.path_with_namespace.clone().unwrap_or_default(), values)
+        })
+        .collect()
+}
+
+/// Whether the environment scope `pattern` applies to `environment`, a `*`
+/// standing for any run of characters as in gitlab, so `review/*` applies
+/// to `review/feature-x`.
+pub fn scope_matches(pattern: &str, environment: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = environment.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// The variables in effect for `environment`, those whose scope applies to
+/// it override the ones of the same key applying to every environment. The
+/// most specific scope wins, the environment itself over any wildcard and a
+/// longer wildcard over a shorter one. Without an environment only the
+/// variables of every environment are in effect. Later variables override
+/// earlier ones of the same scope, so inherited group variables go first.
+/// Values are as stored, see [`expand_references`] for what a job sees.
+pub fn effective_variables(
+    variables: &[GitlabVariable],
+    environment: Option<&str>,
+) -> BTreeMap<String, GitlabVariable> {
+    let mut effective = BTreeMap::new();
+    for v in variables.iter().filter(|v| v.scope() == DEFAULT_SCOPE) {
+        effective.insert(v.key.clone(), v.clone());
+    }
+    if let Some(environment) = environment {
+        let mut scoped: Vec<_> = variables
+            .iter()
+            .filter(|v| v.scope() != DEFAULT_SCOPE && scope_matches(v.scope(), environment))
+            .collect();
+        // stable, variables of the same scope keep their order
+        scoped.sort_by_key(|v| (v.scope() == environment, v.scope().replace('*', "").len()));
+        for v in scoped {
+            effective.insert(v.key.clone(), v.clone());
+        }
+    }
+    effective
+}
+
+// the effective variables, their references expanded when asked
+fn effective(
+    variables: &[GitlabVariable],
+    environment: Option<&str>,
+    expand: bool,
+) -> BTreeMap<String, GitlabVariable> {
+    let mut effective = effective_variables(variables, environment);
+    if expand {
+        expand_references(&mut effective);
+    }
+    effective
+}
+
+/// Replaces `$KEY` and `${KEY}` in the values of `variables` by the value
+/// of `KEY`, like a job would see them. `$$` stands for a literal `$` and
+/// raw variables are left alone. References to unknown or hidden variables,
+/// and those forming a cycle, are kept as written.
+pub fn expand_references(variables: &mut BTreeMap<String, GitlabVariable>) {
+    let mut expanded = HashMap::new();
+    for key in variables.keys() {
+        expand_variable(key, variables, &mut expanded, &mut vec![]);
+    }
+    for (key, variable) in variables.iter_mut() {
+        if let Some(value) = expanded.remove(key) {
+            variable.value = value;
+        }
+    }
+}
+
+// the expanded value of `key`, `None` when it can't be known
+fn expand_variable(
+    key: &str,
+    variables: &BTreeMap<String, GitlabVariable>,
+    expanded: &mut HashMap<String, String>,
+    visiting: &mut Vec<String>,
+) -> Option<String> {
+    if let Some(value) = expanded.get(key) {
+        return Some(value.clone());
+    }
+    let variable = variables.get(key)?;
+    let value = variable.readable_value()?;
+    if variable.raw {
+        return Some(value.to_string());
+    }
+    if visiting.iter().any(|k| k == key) {
+        tracing::warn!("{key} references itself through {}", visiting.join(" -> "));
+        return None;
+    }
+    visiting.push(key.to_string());
+    let mut result = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            result.push('$');
+            rest = after;
+            continue;
+        }
+        let (name, written) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(after.len());
+                (&after[..end], end)
+            }
+        };
+        let reference = &rest[start..start + 1 + written];
+        match expand_variable(name, variables, expanded, visiting) {
+            Some(value) if !name.is_empty() => result.push_str(&value),
+            _ => {
+                if !name.is_empty() && !variables.contains_key(name) {
+                    tracing::warn!("{key} references {name}, which isn't defined");
+                }
+                result.push_str(reference);
+            }
+        }
+        rest = &after[written..];
+    }
+    result.push_str(rest);
+    visiting.pop();
+    expanded.insert(key.to_string(), result.clone());
+    Some(result)
+}
+
+fn dotenv_value(value: &str) -> String {
+    let needs_quotes = value.is_empty()
+        || value
+            .chars()
+            .any(|c| c.is_whitespace() || "\"'#$\\".contains(c));
+    if !needs_quotes {
+        return value.to_string();
+    }
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{escaped}\"")
+}
+
+/// `KEY=value` lines, preceded by the description as a comment when
+/// `comments` is set.
+pub fn render_dotenv(variables: &BTreeMap<String, GitlabVariable>, comments: bool) -> String {
+    let mut lines = vec![];
+    for (key, variable) in variables {
+        if let Some(description) = variable.description.as_ref().filter(|_| comments) {
+            for line in description.lines() {
+                lines.push(format!("# {line}").trim_end().to_string());
+            }
+        }
+        match variable.readable_value() {
+            Some(value) => lines.push(format!("{}={}", key, dotenv_value(value))),
+            // an empty value would be silently wrong
+            None => lines.push(format!("# {key} is hidden, its value can't be read")),
+        }
+    }
+    lines.join("\n")
+}
+
+// kubernetes object names are dns subdomains, namespaces dns labels
+fn is_dns_name(name: &str, max_len: usize, dots: bool) -> bool {
+    let valid =
+        |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || (dots && c == '.');
+    !name.is_empty()
+        && name.len() <= max_len
+        && name.chars().all(valid)
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric())
+}
+
+/// A yaml kubernetes `Secret` named `name` holding `variables` base64
+/// encoded, later variables win over earlier ones of the same key. Hidden
+/// variables can't be included and are left as comments.
+pub fn render_k8s_secret(
+    variables: &[GitlabVariable],
+    name: &str,
+    namespace: Option<&str>,
+) -> anyhow::Result<String> {
+    use base64::Engine;
+
+    if !is_dns_name(name, 253, true) {
+        anyhow::bail!(
+            "invalid secret name {name:?}, expected lowercase letters, digits, '-' or '.'"
+        )
+    }
+    let mut lines = vec![
+        "apiVersion: v1".to_string(),
+        "kind: Secret".to_string(),
+        "metadata:".to_string(),
+        format!("  name: {name}"),
+    ];
+    if let Some(namespace) = namespace {
+        if !is_dns_name(namespace, 63, false) {
+            anyhow::bail!(
+                "invalid namespace {namespace:?}, expected lowercase letters, digits or '-'"
+            )
+        }
+        lines.push(format!("  namespace: {namespace}"));
+    }
+    lines.push("type: Opaque".to_string());
+
+    let by_key: BTreeMap<&str, &GitlabVariable> =
+        variables.iter().map(|v| (v.key.as_str(), v)).collect();
+    if by_key.is_empty() {
+        lines.push("data: {}".to_string());
+    } else {
+        lines.push("data:".to_string());
+    }
+    for (key, variable) in by_key {
+        match variable.readable_value() {
+            Some(value) => {
+                let encoded = base64::engine::general_purpose::STANDARD.encode(value);
+                lines.push(format!("  {key}: {encoded}"));
+            }
+            None => lines.push(format!("  # {key} is hidden, its value can't be read")),
+        }
+    }
+    Ok(lines.join("\n"))
+}
+
+/// The values of `variables` by key, `{"KEY": "value"}`, as `vault kv put`
+/// takes them. Vault would store hidden values as empty, so they fail the
+/// export instead.
+pub fn render_vault_kv(variables: &[GitlabVariable]) -> anyhow::Result<String> {
+    let hidden: Vec<&str> = variables
+        .iter()
+        .filter(|v| v.readable_value().is_none())
+        .map(|v| v.key.as_str())
+        .collect();
+    if !hidden.is_empty() {
+        anyhow::bail!("hidden variables can't be exported: {}", hidden.join(", "))
+    }
+    let data: BTreeMap<&str, &str> = variables
+        .iter()
+        .map(|v| (v.key.as_str(), v.value.as_str()))
+        .collect();
+    Ok(serde_json::to_string(&data)?)
+}
+
+/// The `vault kv put` storing the vault-kv output at the vault `path`, read
+/// from `file` or else from stdin.
+pub fn vault_kv_command(path: &str, file: Option<&Path>) -> anyhow::Result<String> {
+    let path = path.trim_matches('/');
+    if path.is_empty()
+        || path
+            .split('/')
+            .any(|s| s.is_empty() || s.contains(char::is_whitespace))
+    {
+        anyhow::bail!("invalid vault path {path:?}, expected segments such as secret/app")
+    }
+    Ok(match file {
+        Some(file) => format!("vault kv put {path} @{}", file.display()),
+        None => format!("vault kv put {path} -"),
+    })
+}
+
+// quoted when it holds a separator, quote or line break, quotes doubled
+fn csv_field(field: &str) -> String {
+    if !field.contains([',', '"', '\n', '\r']) {
+        return field.to_string();
+    }
+    format!("\"{}\"", field.replace('"', "\"\""))
+}
+
+/// A header row followed by one `key,value,scope,masked,protected` row per
+/// variable, as in RFC 4180. Hidden values are left as a placeholder.
+pub fn render_csv(variables: &[GitlabVariable]) -> String {
+    let mut rows = vec!["key,value,scope,masked,protected".to_string()];
+    for variable in variables {
+        let fields = [
+            csv_field(&variable.key),
+            csv_field(variable.readable_value().unwrap_or(HIDDEN)),
+            csv_field(variable.scope()),
+            variable.masked.to_string(),
+            variable.protected.to_string(),
+        ];
+        rows.push(fields.join(","));
+    }
+    rows.join("\r\n")
+}
+
+/// A note that `identifier` has no variables at all, so that an empty
+/// listing isn't mistaken for a failure, those never get this far.
+pub fn empty_note(identifier: &str, variables: &[GitlabVariable]) -> Option<String> {
+    match variables.is_empty() {
+        true => Some(format!("{identifier} has no variables")),
+        false => None,
+    }
+}
+
+/// The sorted keys of `variables` one per line, each listed once however
+/// many scopes define it.
+pub fn render_keys(variables: &[GitlabVariable]) -> String {
+    let mut keys: Vec<&str> = variables.iter().map(|v| v.key.as_str()).collect();
+    keys.sort();
+    keys.dedup();
+    keys.join("\n")
+}
+
+/// Where the resource cache lives and what it holds, `size` in bytes.
+pub fn render_cache_info(path: &str, size: Option<u64>, stats: &CacheStats) -> String {
+    let size = match size {
+        Some(size) => format!("{size} bytes"),
+        None => "not written yet".to_string(),
+    };
+    format!(
+        "path: {path}\nsize: {size}\nresources: {}\nvariables: {}",
+        stats.resources, stats.variables
+    )
+}
+
+/// The keys of `required` that none of `variables` define, in the order
+/// required. Hidden variables count as defined.
+pub fn missing_keys(variables: &[GitlabVariable], required: &[String]) -> Vec<String> {
+    required
+        .iter()
+        .filter(|key| !variables.iter().any(|v| v.key == **key))
+        .cloned()
+        .collect()
+}
+
+/// Values over this many bytes are warned about unless configured.
+pub const DEFAULT_SIZE_WARNING: usize = 10 * 1024;
+
+/// A warning for every variable whose value exceeds `threshold` bytes,
+/// gitlab caps values and huge ones are usually pasted by accident.
+pub fn size_warnings(variables: &[GitlabVariable], threshold: usize) -> Vec<String> {
+    variables
+        .iter()
+        .filter(|v| v.value.len() > threshold)
+        .map(|v| {
+            format!(
+                "warning: {} is {} bytes, over the {threshold} byte threshold",
+                v.key,
+                v.value.len()
+            )
+        })
+        .collect()
+}
+
+/// One `name = value (source)` line per setting, values aligned.
+pub fn render_settings(settings: &[Setting]) -> String {
+    let width = settings
+        .iter()
+        .map(|s| s.name.len())
+        .max()
+        .unwrap_or_default();
+    settings
+        .iter()
+        .map(|s| format!("{:width$} = {} ({})", s.name, s.value, s.source))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// One `domain: status` line per report.
+pub fn render_token_reports(reports: &[TokenReport]) -> String {
+    reports
+        .iter()
+        .map(|r| {
+            let status = match &r.status {
+                Ok(TokenStatus::Valid) => format!("{}: valid", r.domain),
+                Ok(TokenStatus::Invalid) => format!("{}: invalid", r.domain),
+                Ok(TokenStatus::Expired) => format!("{}: expired", r.domain),
+                Err(e) => format!("{}: unknown, {e}", r.domain),
+            };
+            match &r.origin {
+                Some(origin) => format!("{status} (from {origin})"),
+                None => status,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The raw value of the variable named `key` in effect for `environment`,
+/// see [`effective_variables`], masked values are replaced when `redact` is
+/// set.
+pub fn get_var(
+    variables: &[GitlabVariable],
+    key: &str,
+    environment: Option<&str>,
+    redact: bool,
+) -> anyhow::Result<String> {
+    let effective = effective_variables(variables, environment);
+    let Some(variable) = effective.get(key) else {
+        let scopes: Vec<_> = variables
+            .iter()
+            .filter(|v| v.key == key)
+            .map(|v| v.scope())
+            .collect();
+        if scopes.is_empty() {
+            anyhow::bail!("no variable named {key}")
+        }
+        anyhow::bail!(
+            "{key} is only set for {}, pick one with --env",
+            scopes.join(", ")
+        )
+    };
+    if redact && variable.masked {
+        return Ok(REDACTED.to_string());
+    }
+    match variable.readable_value() {
+        Some(value) => Ok(value.to_string()),
+        None => anyhow::bail!("{key} is hidden, its value can't be read through the api"),
+    }
+}
+
+/// Writes `value` to `path` readable by the owner only, replacing any
+/// previous content and tightening permissions of an existing file.
+pub fn write_private(path: &Path, value: &str) -> anyhow::Result<()> {
+    crate::paths::write_atomic(path, value.as_bytes(), Some(0o600))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(key: &str, value: &str, scope: Option<&str>) -> GitlabVariable {
+        GitlabVariable {
+            key: key.to_string(),
+            value: value.to_string(),
+            environment_scope: scope.map(|s| s.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_formatters() {
+        let resource = Resource {
+            variables: vec![
+                var("HOST", "localhost", None),
+                var("HOST", "prod.example.com", Some("production")),
+            ],
+            ..Default::default()
+        };
+        let production = FormatOptions {
+            environment: Some("production".into()),
+            ..Default::default()
+        };
+        let dotenv = OutputFormat::Dotenv.formatter(production.clone());
+        assert_eq!(dotenv.format(&resource).unwrap(), "HOST=prod.example.com");
+        let keys = OutputFormat::Keys.formatter(production);
+        assert_eq!(keys.format(&resource).unwrap(), "HOST");
+
+        let json = OutputFormat::Json.formatter(FormatOptions::default());
+        let parsed: Resource = serde_json::from_str(&json.format(&resource).unwrap()).unwrap();
+        assert_eq!(parsed.variables, resource.variables);
+        // an invalid secret name surfaces as an error rather than output
+        let k8s = OutputFormat::K8sSecret.formatter(FormatOptions::default());
+        assert!(k8s.format(&resource).is_err());
+
+        // exported as stored, so the file can be applied back as is
+        let resource = Resource {
+            variables: vec![
+                var("HOST", "localhost", None),
+                var("URL", "http://$HOST", None),
+            ],
+            ..Default::default()
+        };
+        let dotenv = OutputFormat::Dotenv.formatter(FormatOptions::default());
+        assert!(dotenv
+            .format(&resource)
+            .unwrap()
+            .ends_with("URL=\"http://$HOST\""));
+        let expanded = OutputFormat::Dotenv.formatter(FormatOptions {
+            expand: true,
+            ..Default::default()
+        });
+        assert!(expanded
+            .format(&resource)
+            .unwrap()
+            .ends_with("URL=http://localhost"));
+    }
+
+    #[test]
+    fn test_group_by_scope() {
+        let variables = vec![
+            var("HOST", "localhost", Some("*")),
+            var("HOST", "prod.example.com", Some("production")),
+            var("PORT", "8080", None),
+            var("HOST", "staging.example.com", Some("staging")),
+            var("DEBUG", "false", Some("production")),
+        ];
+        let scoped = group_by_scope(&variables);
+
+        assert_eq!(
+            scoped.keys().collect::<Vec<_>>(),
+            vec!["*", "production", "staging"]
+        );
+        assert_eq!(scoped["*"]["HOST"], "localhost");
+        assert_eq!(scoped["*"]["PORT"], "8080");
+        assert_eq!(scoped["production"]["HOST"], "prod.example.com");
+        assert_eq!(scoped["production"]["DEBUG"], "false");
+        assert_eq!(scoped["staging"].len(), 1);
+        assert!(group_by_scope(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_effective_dotenv() {
+        let variables = vec![
+            var("HOST", "localhost", Some("*")),
+            var("HOST", "prod.example.com", Some("production")),
+            var("PORT", "8080", None),
+            var("DEBUG", "true", Some("staging")),
+            var("GREETING", "hello \"world\"", Some("production")),
+        ];
+
+        let effective = effective_variables(&variables, Some("production"));
+        assert_eq!(
+            render_dotenv(&effective, false),
+            "GREETING=\"hello \\\"world\\\"\"\nHOST=prod.example.com\nPORT=8080"
+        );
+
+        let effective = effective_variables(&variables, None);
+        assert_eq!(
+            render_dotenv(&effective, false),
+            "HOST=localhost\nPORT=8080"
+        );
+    }
+
+    #[test]
+    fn test_scope_matches() {
+        assert!(scope_matches("*", "production"));
+        assert!(scope_matches("*", ""));
+        assert!(scope_matches("review/*", "review/x"));
+        assert!(scope_matches("review/*", "review/feature/nested"));
+        assert!(scope_matches("*-prod", "eu-prod"));
+        assert!(scope_matches("eu-*-db", "eu-west-db"));
+        assert!(scope_matches("production", "production"));
+        assert!(!scope_matches("review/*", "review"));
+        assert!(!scope_matches("review/*", "staging/review/x"));
+        assert!(!scope_matches("production", "production-eu"));
+        assert!(!scope_matches("*-prod", "eu-prod-2"));
+        assert!(!scope_matches("eu-*-db", "eu-db"));
+    }
+
+    #[test]
+    fn test_effective_wildcard_scopes() {
+        let variables = vec![
+            var("HOST", "localhost", None),
+            var("HOST", "review.example.com", Some("review/*")),
+            var("HOST", "x.example.com", Some("review/x")),
+            var("DB", "shared", Some("review/*")),
+            var("DB", "feature", Some("review/feature-*")),
+            var("DEBUG", "true", Some("staging")),
+        ];
+        let value = |environment: &str, key: &str| {
+            let effective = effective_variables(&variables, Some(environment));
+            effective.get(key).map(|v| v.value.clone())
+        };
+        assert_eq!(
+            value("review/feature-x", "HOST").unwrap(),
+            "review.example.com"
+        );
+        assert_eq!(
+            value("review/x", "HOST").unwrap(),
+            "x.example.com",
+            "exact wins"
+        );
+        assert_eq!(
+            value("review/feature-x", "DB").unwrap(),
+            "feature",
+            "longer wins"
+        );
+        assert_eq!(value("review/x", "DB").unwrap(), "shared");
+        assert_eq!(value("production", "HOST").unwrap(), "localhost");
+        assert_eq!(value("production", "DB"), None);
+        assert_eq!(value("review/x", "DEBUG"), None);
+    }
+
+    #[test]
+    fn test_expand_references() {
+        // inherited from the group, then the project's own
+        let mut template = var("TEMPLATE", "$HOST:${PORT}", None);
+        template.raw = true;
+        let variables = vec![
+            var("HOST", "group.example.com", None),
+            var("PORT", "80", None),
+            var("HOST", "project.example.com", None),
+            var("URL", "https://${HOST}:$PORT/$$HOME", None),
+            var("PORT", "8080", Some("production")),
+            template,
+            var("COPY", "$TEMPLATE", None),
+            var("MISSING", "$UNDEFINED-$", None),
+        ];
+        let mut effective = effective_variables(&variables, Some("production"));
+        assert_eq!(
+            effective["URL"].value, "https://${HOST}:$PORT/$$HOME",
+            "only expanded when asked"
+        );
+        expand_references(&mut effective);
+        let value = |key: &str| effective[key].value.clone();
+        assert_eq!(value("URL"), "https://project.example.com:8080/$HOME");
+        assert_eq!(value("TEMPLATE"), "$HOST:${PORT}", "raw is never expanded");
+        assert_eq!(value("COPY"), "$HOST:${PORT}");
+        assert_eq!(value("MISSING"), "$UNDEFINED-$");
+
+        let variables = vec![
+            var("A", "a$B", None),
+            var("B", "b${A}", None),
+            var("SELF", "$SELF", None),
+        ];
+        let mut effective = effective_variables(&variables, None);
+        expand_references(&mut effective);
+        assert_eq!(
+            effective["A"].value, "ab${A}",
+            "the cycle is kept as written"
+        );
+        assert_eq!(effective["SELF"].value, "$SELF");
+    }
+
+    #[test]
+    fn test_dotenv_comments() {
+        let mut host = var("HOST", "localhost", None);
+        host.description = Some("where the api lives".to_string());
+        let mut multiline = var("TOKEN", "secret", None);
+        multiline.description = Some("rotated monthly\n\nask ops".to_string());
+        let variables = vec![host, var("PORT", "8080", None), multiline];
+        let effective = effective_variables(&variables, None);
+
+        assert_eq!(
+            render_dotenv(&effective, true),
+            "# where the api lives\nHOST=localhost\nPORT=8080\n# rotated monthly\n#\n# ask ops\nTOKEN=secret"
+        );
+        assert_eq!(
+            render_dotenv(&effective, false),
+            "HOST=localhost\nPORT=8080\nTOKEN=secret"
+        );
+    }
+
+    #[test]
+    fn test_write_private() {
+        let path = std::env::temp_dir().join(format!("ruggit-extract-{}", std::process::id()));
+        let value = "-----BEGIN CERTIFICATE-----\n".to_string() + &"A".repeat(20_000);
+        std::fs::write(
+            &path,
+            "previous, longer content than what follows ".repeat(1000),
+        )
+        .unwrap();
+
+        write_private(&path, &value).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), value);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_render_error() {
+        let error = anyhow::anyhow!("no variable named HOST");
+        assert_eq!(
+            render_error(&error, ErrorKind::NotFound, ErrorFormat::Text),
+            "no variable named HOST"
+        );
+
+        let rendered = render_error(&error, ErrorKind::NotFound, ErrorFormat::Json);
+        let parsed: serde_json::Value = serde_json::from_str(&rendered).unwrap();
+        assert_eq!(
+            parsed,
+            serde_json::json!({"error": "no variable named HOST", "kind": "not_found"})
+        );
+
+        // api errors know their own kind
+        let error = ApiError::Unauthorized.into();
+        let rendered = render_error(&error, ErrorKind::Resolution, ErrorFormat::Json);
+        let parsed: serde_json::Value = serde_json::from_str(&rendered).unwrap();
+        assert_eq!(parsed["kind"], "auth");
+    }
+
+    #[test]
+    fn test_render_keys() {
+        let variables = vec![
+            var("PORT", "8080", None),
+            var("HOST", "localhost", Some("*")),
+            var("HOST", "prod.example.com", Some("production")),
+            var("API_TOKEN", "secret", None),
+        ];
+        let rendered = render_keys(&variables);
+        assert_eq!(rendered, "API_TOKEN\nHOST\nPORT");
+        assert!(!rendered.contains("secret"));
+        assert_eq!(render_keys(&[]), "");
+    }
+
+    #[test]
+    fn test_color_choice() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |key: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+        let auto = ColorChoice::Auto;
+        assert!(auto.enabled(env(&[]), true));
+        assert!(!auto.enabled(env(&[]), false));
+        assert!(!auto.enabled(env(&[("NO_COLOR", "1")]), true));
+        assert!(
+            auto.enabled(env(&[("NO_COLOR", "")]), true),
+            "empty is unset"
+        );
+        assert!(auto.enabled(env(&[("CLICOLOR_FORCE", "1")]), false));
+        assert!(!auto.enabled(env(&[("CLICOLOR_FORCE", "0")]), false));
+        let both = env(&[("NO_COLOR", "1"), ("CLICOLOR_FORCE", "1")]);
+        assert!(!auto.enabled(both, false), "NO_COLOR wins");
+        // the flag wins over the environment
+        assert!(ColorChoice::Always.enabled(env(&[("NO_COLOR", "1")]), false));
+        assert!(!ColorChoice::Never.enabled(env(&[("CLICOLOR_FORCE", "1")]), true));
+        assert_eq!("never".parse::<ColorChoice>().unwrap(), ColorChoice::Never);
+        assert!("sometimes".parse::<ColorChoice>().is_err());
+    }
+
+    #[test]
+    fn test_render_csv() {
+        assert_eq!(render_csv(&[]), "key,value,scope,masked,protected");
+
+        let mut secret = var("TOKEN", "hunter2", Some("production"));
+        secret.masked = true;
+        secret.protected = true;
+        let mut hidden = var("SECRET", "", None);
+        hidden.hidden = true;
+        let variables = vec![
+            var("HOSTS", "a,b", None),
+            var("GREETING", "say \"hi\"\nthere", None),
+            secret,
+            hidden,
+        ];
+        assert_eq!(
+            render_csv(&variables),
+            [
+                "key,value,scope,masked,protected",
+                "HOSTS,\"a,b\",*,false,false",
+                "GREETING,\"say \"\"hi\"\"\nthere\",*,false,false",
+                "TOKEN,hunter2,production,true,true",
+                "SECRET,[HIDDEN],*,false,false",
+            ]
+            .join("\r\n")
+        );
+    }
+
+    #[test]
+    fn test_missing_keys() {
+        let mut hidden = var("SECRET", "", None);
+        hidden.hidden = true;
+        let variables = vec![var("HOST", "localhost", Some("production")), hidden];
+        let required = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+
+        assert!(missing_keys(&variables, &required(&["HOST", "SECRET"])).is_empty());
+        assert_eq!(
+            missing_keys(&variables, &required(&["PORT", "HOST", "TOKEN"])),
+            ["PORT", "TOKEN"]
+        );
+    }
+
+    #[test]
+    fn test_render_settings() {
+        let settings = [
+            Setting::new("format", "json", "default"),
+            Setting::new("per_page", 50, "flag"),
+        ];
+        assert_eq!(
+            render_settings(&settings),
+            "format   = json (default)\nper_page = 50 (flag)"
+        );
+    }
+
+    #[test]
+    fn test_size_warnings() {
+        let variables = vec![
+            var("SMALL", "tiny", None),
+            var("CERT", &"x".repeat(DEFAULT_SIZE_WARNING + 1), None),
+            var("EXACT", &"x".repeat(DEFAULT_SIZE_WARNING), None),
+        ];
+        assert_eq!(
+            size_warnings(&variables, DEFAULT_SIZE_WARNING),
+            ["warning: CERT is 10241 bytes, over the 10240 byte threshold"]
+        );
+        assert_eq!(size_warnings(&variables, 4).len(), 2);
+    }
+
+    #[test]
+    fn test_get_var() {
+        let mut secret = var("TOKEN", "hunter2", None);
+        secret.masked = true;
+        let variables = vec![var("HOST", "localhost", None), secret];
+
+        assert_eq!(
+            get_var(&variables, "HOST", None, false).unwrap(),
+            "localhost"
+        );
+        assert_eq!(
+            get_var(&variables, "TOKEN", None, false).unwrap(),
+            "hunter2"
+        );
+        assert_eq!(get_var(&variables, "TOKEN", None, true).unwrap(), REDACTED);
+        assert!(get_var(&variables, "MISSING", None, false).is_err());
+    }
+
+    #[test]
+    fn test_get_var_scoped() {
+        let variables = vec![
+            var("HOST", "prod.example.com", Some("production")),
+            var("HOST", "localhost", Some("*")),
+            var("DSN", "db.example.com", Some("production")),
+        ];
+        assert_eq!(
+            get_var(&variables, "HOST", Some("production"), false).unwrap(),
+            "prod.example.com"
+        );
+        assert_eq!(
+            get_var(&variables, "HOST", Some("staging"), false).unwrap(),
+            "localhost"
+        );
+        assert_eq!(
+            get_var(&variables, "HOST", None, false).unwrap(),
+            "localhost"
+        );
+        let error = get_var(&variables, "DSN", None, false).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "DSN is only set for production, pick one with --env"
+        );
+    }
+
+    #[test]
+    fn test_render_vault_kv() {
+        let variables = vec![
+            var("HOST", "localhost", None),
+            var("GREETING", "hello \"world\"\n", None),
+            var("EMPTY", "", None),
+        ];
+        let payload = render_vault_kv(&variables).unwrap();
+        assert_eq!(
+            payload,
+            r#"{"EMPTY":"","GREETING":"hello \"world\"\n","HOST":"localhost"}"#
+        );
+        assert_eq!(render_vault_kv(&[]).unwrap(), "{}");
+
+        let mut hidden = var("TOKEN", "", None);
+        hidden.hidden = true;
+        let error = render_vault_kv(&[hidden]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "hidden variables can't be exported: TOKEN"
+        );
+
+        // only the effective values of the environment
+        let resource = Resource {
+            variables: vec![
+                var("HOST", "localhost", None),
+                var("HOST", "prod.example.com", Some("production")),
+            ],
+            ..Default::default()
+        };
+        let vault = OutputFormat::VaultKv.formatter(FormatOptions {
+            environment: Some("production".into()),
+            ..Default::default()
+        });
+        assert_eq!(
+            vault.format(&resource).unwrap(),
+            r#"{"HOST":"prod.example.com"}"#
+        );
+    }
+
+    #[test]
+    fn test_vault_kv_command() {
+        assert_eq!(
+            vault_kv_command("/secret/myapp/", Some(Path::new("vars.json"))).unwrap(),
+            "vault kv put secret/myapp @vars.json"
+        );
+        assert_eq!(
+            vault_kv_command("secret/myapp", None).unwrap(),
+            "vault kv put secret/myapp -"
+        );
+        for path in ["", "/", "secret//app", "secret/my app"] {
+            assert!(vault_kv_command(path, None).is_err(), "{path:?}");
+        }
+    }
+
+    #[test]
+    fn test_render_k8s_secret() {
+        let mut hidden = var("SECRET", "", None);
+        hidden.hidden = true;
+        let variables = vec![
+            var("HOST", "localhost", None),
+            var("GREETING", "hello world\n", None),
+            hidden,
+        ];
+        let manifest = render_k8s_secret(&variables, "app-env", Some("prod")).unwrap();
+        assert_eq!(
+            manifest,
+            "apiVersion: v1\nkind: Secret\nmetadata:\n  name: app-env\n  namespace: prod\ntype: Opaque\ndata:\n  GREETING: aGVsbG8gd29ybGQK\n  HOST: bG9jYWxob3N0\n  # SECRET is hidden, its value can't be read"
+        );
+
+        let manifest = render_k8s_secret(&[], "app.env", None).unwrap();
+        assert!(!manifest.contains("namespace"));
+        assert!(manifest.ends_with("data: {}"));
+
+        for name in ["", "App", "app_env", "-app", "app-"] {
+            assert!(render_k8s_secret(&[], name, None).is_err(), "{name}");
+        }
+        assert!(render_k8s_secret(&[], "app", Some("prod.eu")).is_err());
+    }
+
+    #[test]
+    fn test_render_token_reports() {
+        let report = |domain: &str, status| TokenReport {
+            domain: domain.to_string(),
+            status,
+            origin: None,
+        };
+        let mut reports = vec![
+            report("gitlab.com", Ok(TokenStatus::Valid)),
+            report("gitlab.corp", Ok(TokenStatus::Expired)),
+            report("gitlab.gone", Err("connection refused".to_string())),
+        ];
+        assert_eq!(
+            render_token_reports(&reports),
+            "gitlab.com: valid\ngitlab.corp: expired\ngitlab.gone: unknown, connection refused"
+        );
+        reports[1].origin = Some("/etc/ruggit/team".into());
+        assert_eq!(
+            render_token_reports(&reports[1..2]),
+            "gitlab.corp: expired (from /etc/ruggit/team)"
+        );
+    }
+
+    #[test]
+    fn test_hidden_output() {
+        let mut hidden = var("SECRET", "", None);
+        hidden.hidden = true;
+        let variables = vec![var("HOST", "localhost", None), hidden];
+
+        let error = get_var(&variables, "SECRET", None, false).unwrap_err();
+        assert!(error.to_string().contains("hidden"));
+        assert_eq!(group_by_scope(&variables)["*"]["SECRET"], HIDDEN);
+        let effective = effective_variables(&variables, None);
+        assert_eq!(
+            render_dotenv(&effective, false),
+            "HOST=localhost\n# SECRET is hidden, its value can't be read"
+        );
+    }
+}
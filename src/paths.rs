@@ -0,0 +1,234 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Where tokens live, $RUGGIT_CONFIG_DIR, $XDG_CONFIG_HOME/ruggit or
+/// ~/.config/ruggit, in that order.
+pub fn config_dir(env: impl Fn(&str) -> Option<String>) -> Option<PathBuf> {
+    resolve(env, "RUGGIT_CONFIG_DIR", "XDG_CONFIG_HOME", ".config")
+}
+
+/// Where cached resources live, $RUGGIT_CACHE_DIR, $XDG_CACHE_HOME/ruggit or
+/// ~/.cache/ruggit, in that order.
+pub fn cache_dir(env: impl Fn(&str) -> Option<String>) -> Option<PathBuf> {
+    resolve(env, "RUGGIT_CACHE_DIR", "XDG_CACHE_HOME", ".cache")
+}
+
+/// The variable [`config_dir`] is derived from.
+pub fn config_dir_origin(env: impl Fn(&str) -> Option<String>) -> &'static str {
+    origin(env, "RUGGIT_CONFIG_DIR", "XDG_CONFIG_HOME")
+}
+
+/// The variable [`cache_dir`] is derived from.
+pub fn cache_dir_origin(env: impl Fn(&str) -> Option<String>) -> &'static str {
+    origin(env, "RUGGIT_CACHE_DIR", "XDG_CACHE_HOME")
+}
+
+fn origin(
+    env: impl Fn(&str) -> Option<String>,
+    override_key: &'static str,
+    xdg_key: &'static str,
+) -> &'static str {
+    if env(override_key).is_some() {
+        return override_key;
+    }
+    if env(xdg_key).is_some() {
+        return xdg_key;
+    }
+    "HOME"
+}
+
+/// Looks variables up in the process environment, ignoring empty values.
+pub fn from_env(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|v| !v.is_empty())
+}
+
+/// Replaces `path` with `content` through a temporary file renamed over it,
+/// so readers never see a partial file. A symlink is followed, the file it
+/// points to is replaced. On unix the file gets `mode`, or without one keeps
+/// the permissions of the file it replaces, a new file those the umask leaves.
+pub fn write_atomic(path: &Path, content: &[u8], mode: Option<u32>) -> std::io::Result<()> {
+    let path = &resolve_link(path)?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".{name}.{}.tmp", std::process::id()));
+    let written = (|| {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+        }
+        let mut file = options.open(&temp)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = match (mode, std::fs::metadata(path)) {
+                (Some(mode), _) => Some(std::fs::Permissions::from_mode(mode)),
+                (None, Ok(existing)) => Some(existing.permissions()),
+                (None, Err(_)) => None,
+            };
+            if let Some(permissions) = permissions {
+                file.set_permissions(permissions)?;
+            }
+        }
+        file.write_all(content)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, path)
+    })();
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    written
+}
+
+// the file `path` points to when it is a symlink, even a dangling one
+fn resolve_link(path: &Path) -> std::io::Result<PathBuf> {
+    if !path.is_symlink() {
+        return Ok(path.to_path_buf());
+    }
+    match std::fs::canonicalize(path) {
+        Ok(target) => Ok(target),
+        Err(_) => {
+            let target = std::fs::read_link(path)?;
+            Ok(path.parent().unwrap_or(Path::new("")).join(target))
+        }
+    }
+}
+
+fn resolve(
+    env: impl Fn(&str) -> Option<String>,
+    override_key: &str,
+    xdg_key: &str,
+    home_fallback: &str,
+) -> Option<PathBuf> {
+    if let Some(path) = env(override_key) {
+        return Some(PathBuf::from(path));
+    }
+    if let Some(path) = env(xdg_key) {
+        return Some(PathBuf::from(path).join("ruggit"));
+    }
+    let home = env("HOME")?;
+    Some(PathBuf::from(home).join(home_fallback).join("ruggit"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |k| vars.get(k).cloned()
+    }
+
+    #[test]
+    fn test_config_dir() {
+        let home = ("HOME", "/home/user");
+        assert_eq!(
+            config_dir(env(&[home])),
+            Some(PathBuf::from("/home/user/.config/ruggit"))
+        );
+        assert_eq!(
+            config_dir(env(&[home, ("XDG_CONFIG_HOME", "/xdg/config")])),
+            Some(PathBuf::from("/xdg/config/ruggit"))
+        );
+        assert_eq!(
+            config_dir(env(&[
+                home,
+                ("XDG_CONFIG_HOME", "/xdg/config"),
+                ("RUGGIT_CONFIG_DIR", "/custom")
+            ])),
+            Some(PathBuf::from("/custom"))
+        );
+        assert_eq!(config_dir(env(&[])), None);
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = std::env::temp_dir().join(format!("ruggit-atomic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.env");
+        std::fs::write(&path, "OLD=1").unwrap();
+        let before = std::fs::File::open(&path).unwrap();
+
+        write_atomic(&path, b"NEW=2", Some(0o600)).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "NEW=2");
+        // replaced rather than rewritten in place
+        let old = std::io::read_to_string(before).unwrap();
+        assert_eq!(old, "OLD=1");
+        let entries: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(entries.len(), 1, "no temporary file left behind");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |p: &Path| std::fs::metadata(p).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&path), 0o600);
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+            write_atomic(&path, b"NEWER=3", None).unwrap();
+            assert_eq!(mode(&path), 0o640, "kept without a mode");
+
+            // a new file is left to the umask, like any other
+            let plain = dir.join("plain.env");
+            std::fs::write(&plain, "").unwrap();
+            write_atomic(&dir.join("new.env"), b"NEW=4", None).unwrap();
+            assert_eq!(mode(&dir.join("new.env")), mode(&plain));
+
+            // a symlink stays one, pointing to the new content
+            let link = dir.join("link.env");
+            std::os::unix::fs::symlink(&path, &link).unwrap();
+            write_atomic(&link, b"LINKED=5", Some(0o600)).unwrap();
+            assert!(link.is_symlink());
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "LINKED=5");
+            let dangling = dir.join("dangling.env");
+            std::os::unix::fs::symlink("target.env", &dangling).unwrap();
+            write_atomic(&dangling, b"CREATED=6", None).unwrap();
+            assert!(dangling.is_symlink());
+            assert_eq!(
+                std::fs::read_to_string(dir.join("target.env")).unwrap(),
+                "CREATED=6"
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_dir_origin() {
+        let home = ("HOME", "/home/user");
+        assert_eq!(config_dir_origin(env(&[home])), "HOME");
+        assert_eq!(
+            config_dir_origin(env(&[home, ("XDG_CONFIG_HOME", "/xdg/config")])),
+            "XDG_CONFIG_HOME"
+        );
+        let both = [
+            home,
+            ("XDG_CACHE_HOME", "/xdg/cache"),
+            ("RUGGIT_CACHE_DIR", "/ruggit"),
+        ];
+        assert_eq!(cache_dir_origin(env(&both)), "RUGGIT_CACHE_DIR");
+    }
+
+    #[test]
+    fn test_cache_dir() {
+        let home = ("HOME", "/home/user");
+        assert_eq!(
+            cache_dir(env(&[home])),
+            Some(PathBuf::from("/home/user/.cache/ruggit"))
+        );
+        assert_eq!(
+            cache_dir(env(&[home, ("XDG_CACHE_HOME", "/xdg/cache")])),
+            Some(PathBuf::from("/xdg/cache/ruggit"))
+        );
+        assert_eq!(
+            cache_dir(env(&[
+                home,
+                ("XDG_CACHE_HOME", "/xdg/cache"),
+                ("XDG_CONFIG_HOME", "/xdg/config"),
+                ("RUGGIT_CACHE_DIR", "/custom")
+            ])),
+            Some(PathBuf::from("/custom"))
+        );
+        assert_eq!(cache_dir(env(&[("XDG_CONFIG_HOME", "/xdg/config")])), None);
+    }
+}
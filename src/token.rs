@@ -1,47 +1,171 @@
 use crate::crypto::{CryptoError, EncryptedRW};
+use crate::gapi::{GApi, GitlabAccessToken, GitlabResource, TokenStatus};
+use crate::oauth::OAuthToken;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
 
 #[derive(Serialize, Deserialize, Default)]
 struct OnDisk {
     tokens: HashMap<String, String>,
+    #[serde(default)]
+    oauth: HashMap<String, OAuthToken>,
+}
+
+/// Anything that can hand out a token for a given domain.
+pub trait TokenSource {
+    fn token(&self, domain: &str) -> Option<String>;
+}
+
+/// A prioritized list of token sources, the first source yielding a token
+/// for a domain wins.
+#[derive(Default)]
+pub struct TokenChain<'a> {
+    sources: Vec<Box<dyn TokenSource + 'a>>,
+}
+
+impl<'a> TokenChain<'a> {
+    pub fn new() -> Self {
+        Self { sources: vec![] }
+    }
+
+    pub fn push(&mut self, source: impl TokenSource + 'a) {
+        self.sources.push(Box::new(source));
+    }
+}
+
+impl TokenSource for TokenChain<'_> {
+    fn token(&self, domain: &str) -> Option<String> {
+        self.sources.iter().find_map(|s| s.token(domain))
+    }
+}
+
+/// A token source that can also persist new tokens.
+pub trait TokenBackend: TokenSource {
+    fn add_token(&mut self, domain: &str, token: &str) -> anyhow::Result<()>;
+
+    fn oauth(&self, _domain: &str) -> Option<OAuthToken> {
+        None
+    }
+
+    fn set_oauth(&mut self, _domain: &str, _token: OAuthToken) -> anyhow::Result<()> {
+        anyhow::bail!("backend can't store oauth tokens")
+    }
+
+    /// Domains with a stored token, empty for backends that can't list.
+    fn domains(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// Where the token of `domain` is read from, for backends layering
+    /// several sources.
+    fn origin(&self, _domain: &str) -> Option<String> {
+        None
+    }
+}
+
+/// Where tokens are kept, the `token_backend` of the config.
+#[derive(Deserialize, Clone, Copy, Default, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    // the passphrase protected tokens file
+    #[default]
+    File,
+    // the OS keyring, built with the keyring feature
+    Keyring,
+}
+
+impl Backend {
+    /// Whether the tokens are encrypted with the passphrase.
+    pub fn needs_passphrase(&self) -> bool {
+        *self == Self::File
+    }
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File => write!(f, "file"),
+            Self::Keyring => write!(f, "keyring"),
+        }
+    }
 }
 
 pub struct TokenStore<T: EncryptedRW> {
     file: T,
     on_disk: OnDisk,
+    // read only token files by path, consulted in order after `file`
+    shared: Vec<(String, OnDisk)>,
+}
+
+// the tokens in `file`, failing if it can't be decrypted
+fn load<T: EncryptedRW>(file: &T) -> Result<OnDisk> {
+    let content = match file.read() {
+        Ok(content) => content,
+        Err(e) => match e {
+            CryptoError::DecryptError(_) => {
+                let path = file.path();
+                return Err(anyhow::Error::new(e)
+                    .context(format!("failed to decrypt {path}, wrong passphrase?")));
+            }
+            CryptoError::IO(_) => {
+                tracing::info!("IO error, clearing disk content");
+                return Ok(OnDisk::default());
+            }
+            CryptoError::EncryptError(_) => {
+                panic!("this should be impossible")
+            }
+        },
+    };
+    Ok(serde_json::from_slice(&content).unwrap_or_default())
 }
 
 impl<T: EncryptedRW> TokenStore<T> {
-    pub fn new(file: T) -> Self {
-        let content = match file.read() {
-            Ok(content) => content,
-            Err(e) => match e {
-                CryptoError::DecryptError(err) => {
-                    println!("{}", err);
-                    std::process::exit(1);
-                }
-                CryptoError::IO(_) => {
-                    println!("IO error, clearing disk content");
-                    return Self {
-                        file,
-                        on_disk: OnDisk::default(),
-                    };
-                }
-                CryptoError::EncryptError(_) => {
-                    panic!("this should be impossible")
-                }
-            },
-        };
+    /// Whether `file` decrypts to stored tokens, leaving it untouched.
+    pub fn verify(file: &T) -> crate::cache::Integrity {
+        crate::cache::verify::<OnDisk>(file)
+    }
 
-        let Ok(on_disk) = serde_json::from_slice(&content) else {
-            return Self {
-                file,
-                on_disk: OnDisk::default(),
-            };
+    /// Loads the stored tokens, failing if `file` can't be decrypted, most
+    /// likely because of a wrong passphrase.
+    pub fn new(file: T) -> Result<Self> {
+        let on_disk = load(&file)?;
+        Ok(Self {
+            file,
+            on_disk,
+            shared: vec![],
+        })
+    }
+
+    /// Adds token files shared by a team, looked up in order when `file`
+    /// lacks a domain. They are never written to, new tokens always go to
+    /// `file` and so override the shared ones. Files the passphrase doesn't
+    /// decrypt are skipped with a warning.
+    pub fn with_shared(mut self, files: impl IntoIterator<Item = T>) -> Self {
+        for file in files {
+            match load(&file) {
+                Ok(on_disk) => self.shared.push((file.path(), on_disk)),
+                Err(e) => tracing::warn!("skipping shared tokens: {e:#}"),
+            }
+        }
+        self
+    }
+
+    /// The path of the file the token of `domain` is read from.
+    pub fn origin(&self, domain: &str) -> Option<String> {
+        let knows = |on_disk: &OnDisk| {
+            on_disk.tokens.contains_key(domain) || on_disk.oauth.contains_key(domain)
         };
-        Self { file, on_disk }
+        if knows(&self.on_disk) {
+            return Some(self.file.path());
+        }
+        self.shared
+            .iter()
+            .find(|(_, on_disk)| knows(on_disk))
+            .map(|(path, _)| path.clone())
     }
 
     fn write(&self) -> Result<()> {
@@ -50,8 +174,8 @@ impl<T: EncryptedRW> TokenStore<T> {
         Ok(())
     }
 
-    pub fn add_token(&mut self, domain: &str, token: &str) -> anyhow::Result<()> {
-        print!("input token for {}: ", domain);
+    pub fn add_token(&mut self, domain: &str, token: &str) -> Result<()> {
+        tracing::debug!(domain, "storing token");
         self.on_disk
             .tokens
             .insert(domain.to_string(), token.to_string());
@@ -59,10 +183,559 @@ impl<T: EncryptedRW> TokenStore<T> {
     }
 
     pub fn list_domains(&self) -> Vec<String> {
-        self.on_disk.tokens.keys().cloned().collect()
+        let mut domains: Vec<String> = self
+            .layers()
+            .flat_map(|l| l.tokens.keys())
+            .cloned()
+            .collect();
+        domains.sort();
+        domains.dedup();
+        domains
+    }
+
+    // the personal tokens followed by the shared ones
+    fn layers(&self) -> impl Iterator<Item = &OnDisk> {
+        std::iter::once(&self.on_disk).chain(self.shared.iter().map(|(_, on_disk)| on_disk))
     }
 
     pub fn get(&self, domain: &str) -> Option<String> {
-        self.on_disk.tokens.get(domain).cloned()
+        self.layers().find_map(|on_disk| {
+            if let Some(token) = on_disk.tokens.get(domain) {
+                return Some(token.clone());
+            }
+            on_disk.oauth.get(domain).map(|t| t.access_token.clone())
+        })
+    }
+
+    pub fn get_oauth(&self, domain: &str) -> Option<OAuthToken> {
+        self.layers()
+            .find_map(|on_disk| on_disk.oauth.get(domain).cloned())
+    }
+
+    pub fn add_oauth(&mut self, domain: &str, token: OAuthToken) -> Result<()> {
+        self.on_disk.oauth.insert(domain.to_string(), token);
+        self.write()
+    }
+}
+
+impl<T: EncryptedRW> TokenSource for TokenStore<T> {
+    fn token(&self, domain: &str) -> Option<String> {
+        self.get(domain)
+    }
+}
+
+impl<T: EncryptedRW> TokenBackend for TokenStore<T> {
+    fn add_token(&mut self, domain: &str, token: &str) -> Result<()> {
+        TokenStore::add_token(self, domain, token)
+    }
+
+    fn oauth(&self, domain: &str) -> Option<OAuthToken> {
+        self.get_oauth(domain)
+    }
+
+    fn set_oauth(&mut self, domain: &str, token: OAuthToken) -> Result<()> {
+        self.add_oauth(domain, token)
+    }
+
+    fn domains(&self) -> Vec<String> {
+        self.list_domains()
+    }
+
+    fn origin(&self, domain: &str) -> Option<String> {
+        TokenStore::origin(self, domain)
+    }
+}
+
+/// Minimal interface over a credential store such as the OS keyring.
+pub trait Keyring {
+    fn set(&self, domain: &str, secret: &str) -> Result<()>;
+    fn get(&self, domain: &str) -> Result<Option<String>>;
+    fn delete(&self, domain: &str) -> Result<()>;
+}
+
+/// Token storage backed by a keyring instead of an encrypted file,
+/// no passphrase required.
+pub struct KeyringStore<K: Keyring> {
+    keyring: K,
+}
+
+impl<K: Keyring> KeyringStore<K> {
+    pub fn new(keyring: K) -> Self {
+        Self { keyring }
+    }
+
+    pub fn add_token(&mut self, domain: &str, token: &str) -> Result<()> {
+        self.keyring.set(domain, token)
+    }
+
+    pub fn remove_token(&mut self, domain: &str) -> Result<()> {
+        self.keyring.delete(domain)
+    }
+
+    pub fn get(&self, domain: &str) -> Option<String> {
+        self.keyring.get(domain).ok().flatten()
+    }
+}
+
+impl<K: Keyring> TokenSource for KeyringStore<K> {
+    fn token(&self, domain: &str) -> Option<String> {
+        self.get(domain)
+    }
+}
+
+impl<K: Keyring> TokenBackend for KeyringStore<K> {
+    fn add_token(&mut self, domain: &str, token: &str) -> Result<()> {
+        KeyringStore::add_token(self, domain, token)
+    }
+}
+
+#[cfg(feature = "keyring")]
+pub struct OsKeyring;
+
+#[cfg(feature = "keyring")]
+impl OsKeyring {
+    const SERVICE: &'static str = "ruggit";
+}
+
+#[cfg(feature = "keyring")]
+impl Keyring for OsKeyring {
+    fn set(&self, domain: &str, secret: &str) -> Result<()> {
+        Ok(keyring::Entry::new(Self::SERVICE, domain)?.set_password(secret)?)
+    }
+
+    fn get(&self, domain: &str) -> Result<Option<String>> {
+        match keyring::Entry::new(Self::SERVICE, domain)?.get_password() {
+            Ok(secret) => Ok(Some(secret)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn delete(&self, domain: &str) -> Result<()> {
+        match keyring::Entry::new(Self::SERVICE, domain)?.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// A token given on the command line, valid for whichever domain is asked.
+pub struct InlineToken(String);
+
+impl TokenSource for InlineToken {
+    fn token(&self, _domain: &str) -> Option<String> {
+        Some(self.0.clone())
+    }
+}
+
+/// Takes a token passed on the command line, warning through `warn` that it
+/// leaks into shell history. It's only persisted for `domain` when `save`
+/// is set.
+pub fn inline_token(
+    token: &str,
+    domain: &str,
+    save: bool,
+    store: &mut dyn TokenBackend,
+    warn: &mut impl std::io::Write,
+) -> Result<InlineToken> {
+    writeln!(
+        warn,
+        "warning: tokens passed inline end up in shell history and process listings"
+    )?;
+    if save {
+        store.add_token(domain, token)?;
+    }
+    Ok(InlineToken(token.to_string()))
+}
+
+/// Stores the token produced by `token` for `domain` unless the store has
+/// one already, returning whether it stored. `token` is only asked when
+/// needed.
+pub fn ensure_token(
+    store: &mut dyn TokenBackend,
+    domain: &str,
+    token: impl FnOnce() -> Result<String>,
+) -> Result<bool> {
+    if store.token(domain).is_some() {
+        return Ok(false);
+    }
+    store.add_token(domain, &token()?)?;
+    Ok(true)
+}
+
+pub trait TokenRotator {
+    fn rotate(&self, id: u32) -> impl Future<Output = Result<GitlabAccessToken>>;
+}
+
+impl TokenRotator for GitlabResource {
+    async fn rotate(&self, id: u32) -> Result<GitlabAccessToken> {
+        self.rotate_access_token(id).await
+    }
+}
+
+/// Rotates the access token `id`, storing the new value for `domain` only
+/// when `current`, the id of the token stored for it, is the one rotated.
+/// Any other token reaches a single project or group, stored it would take
+/// over the whole domain. The value is only shown once by gitlab, so it's
+/// handed back along with whether it was stored, even when storing fails,
+/// rather than being lost.
+pub async fn rotate_and_store(
+    rotator: &impl TokenRotator,
+    store: &mut dyn TokenBackend,
+    domain: &str,
+    id: u32,
+    current: Option<u32>,
+) -> Result<(String, Result<bool>)> {
+    let rotated = rotator.rotate(id).await?;
+    let Some(token) = rotated.token else {
+        anyhow::bail!("gitlab returned no value for the rotated token")
+    };
+    if current != Some(id) {
+        return Ok((token, Ok(false)));
+    }
+    let stored = store.add_token(domain, &token).map(|_| true);
+    Ok((token, stored))
+}
+
+/// The outcome of validating the token of a domain, never the token itself.
+#[derive(Serialize, Debug)]
+pub struct TokenReport {
+    pub domain: String,
+    pub status: std::result::Result<TokenStatus, String>,
+    // the file the token came from, when the backend tells
+    pub origin: Option<String>,
+}
+
+/// Validates the token of every `(domain, token)` pair, at most `limit` at
+/// a time, with clients built by `api`. Reports are in the order given.
+pub async fn check_tokens(
+    tokens: Vec<(String, String)>,
+    limit: usize,
+    api: impl Fn(&str, &str) -> GApi,
+) -> Vec<TokenReport> {
+    let permits = Arc::new(Semaphore::new(limit.max(1)));
+    let mut tasks = vec![];
+    for (domain, token) in &tokens {
+        let api = api(domain, token);
+        let permits = permits.clone();
+        tasks.push(tokio::spawn(async move {
+            let _permit = permits.acquire().await?;
+            api.validate_token().await
+        }));
+    }
+    let mut reports = vec![];
+    for ((domain, _), task) in tokens.into_iter().zip(tasks) {
+        let status = match task.await {
+            Ok(status) => status.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        reports.push(TokenReport {
+            domain,
+            status,
+            origin: None,
+        });
+    }
+    reports
+}
+
+impl<S: TokenSource + ?Sized> TokenSource for &S {
+    fn token(&self, domain: &str) -> Option<String> {
+        (*self).token(domain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PasswdProtectedFile;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Fake(&'static str, Option<&'static str>);
+
+    impl TokenSource for Fake {
+        fn token(&self, domain: &str) -> Option<String> {
+            if domain != self.0 {
+                return None;
+            }
+            self.1.map(|t| t.to_string())
+        }
+    }
+
+    #[derive(Default)]
+    struct MockKeyring(std::cell::RefCell<HashMap<String, String>>);
+
+    impl Keyring for MockKeyring {
+        fn set(&self, domain: &str, secret: &str) -> Result<()> {
+            self.0
+                .borrow_mut()
+                .insert(domain.to_string(), secret.to_string());
+            Ok(())
+        }
+
+        fn get(&self, domain: &str) -> Result<Option<String>> {
+            Ok(self.0.borrow().get(domain).cloned())
+        }
+
+        fn delete(&self, domain: &str) -> Result<()> {
+            self.0.borrow_mut().remove(domain);
+            Ok(())
+        }
+    }
+
+    struct MockRotator;
+
+    impl TokenRotator for MockRotator {
+        async fn rotate(&self, id: u32) -> Result<GitlabAccessToken> {
+            Ok(GitlabAccessToken {
+                id: id + 1,
+                name: "deploy".to_string(),
+                token: Some(format!("rotated-{id}")),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_tokens() {
+        use crate::gapi::tests::{mock_server, Route};
+
+        let user = |body: &'static str, status| {
+            mock_server(vec![Route::new("/api/v4/user", body).status(status)])
+        };
+        let valid = user(r#"{"id": 1}"#, 200).await;
+        let expired = user(
+            r#"{"error":"invalid_token","error_description":"Token has expired."}"#,
+            401,
+        )
+        .await;
+        let invalid = user("", 401).await;
+
+        let tokens = vec![
+            (valid, "good".to_string()),
+            (expired, "old".to_string()),
+            (invalid, "bad".to_string()),
+        ];
+        let reports = check_tokens(tokens.clone(), 2, GApi::new).await;
+        let statuses: Vec<_> = reports.iter().map(|r| r.status.clone()).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                Ok(TokenStatus::Valid),
+                Ok(TokenStatus::Expired),
+                Ok(TokenStatus::Invalid)
+            ]
+        );
+        for (report, (domain, token)) in reports.iter().zip(&tokens) {
+            assert_eq!(&report.domain, domain);
+            assert!(!serde_json::to_string(report)
+                .unwrap()
+                .contains(token.as_str()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rotate_and_store() {
+        let mut store = KeyringStore::new(MockKeyring::default());
+        store.add_token("gitlab.com", "original").unwrap();
+
+        // a project's token, the domain keeps its own
+        let (token, stored) = rotate_and_store(&MockRotator, &mut store, "gitlab.com", 7, Some(3))
+            .await
+            .unwrap();
+        assert!(!stored.unwrap());
+        assert_eq!(token, "rotated-7");
+        assert_eq!(store.token("gitlab.com"), Some("original".to_string()));
+        let (_, stored) = rotate_and_store(&MockRotator, &mut store, "gitlab.com", 7, None)
+            .await
+            .unwrap();
+        assert!(!stored.unwrap(), "an unknown token is left alone");
+
+        let (token, stored) = rotate_and_store(&MockRotator, &mut store, "gitlab.com", 7, Some(7))
+            .await
+            .unwrap();
+        assert!(stored.unwrap());
+        assert_eq!(token, "rotated-7");
+        assert_eq!(store.token("gitlab.com"), Some("rotated-7".to_string()));
+    }
+
+    #[test]
+    fn test_wrong_passphrase() {
+        let path = std::env::temp_dir().join(format!("ruggit-tokens-{}", std::process::id()));
+        let mut store = TokenStore::new(PasswdProtectedFile::new("right", path.clone())).unwrap();
+        store.add_token("gitlab.com", "secret").unwrap();
+
+        let error = TokenStore::new(PasswdProtectedFile::new("wrong", path.clone()))
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<CryptoError>(),
+            Some(CryptoError::DecryptError(_))
+        ));
+        let store = TokenStore::new(PasswdProtectedFile::new("right", path.clone())).unwrap();
+        assert_eq!(store.get("gitlab.com"), Some("secret".to_string()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_verify() {
+        use crate::cache::Integrity;
+
+        let path = std::env::temp_dir().join(format!("ruggit-verify-{}", std::process::id()));
+        let file = PasswdProtectedFile::new("right", path.clone());
+        assert_eq!(TokenStore::verify(&file), Integrity::Missing);
+        let mut store = TokenStore::new(PasswdProtectedFile::new("right", path.clone())).unwrap();
+        store.add_token("gitlab.com", "secret").unwrap();
+        assert_eq!(TokenStore::verify(&file), Integrity::Valid);
+
+        let wrong = PasswdProtectedFile::new("wrong", path.clone());
+        assert_eq!(TokenStore::verify(&wrong), Integrity::WrongPassphrase);
+
+        // encrypted fine, but not tokens
+        file.write(b"[1, 2, 3]").unwrap();
+        assert!(matches!(TokenStore::verify(&file), Integrity::Corrupt(_)));
+        let written = std::fs::read(&path).unwrap();
+        TokenStore::verify(&file);
+        assert_eq!(std::fs::read(&path).unwrap(), written, "left untouched");
+
+        std::fs::write(&path, b"not age at all").unwrap();
+        assert!(matches!(TokenStore::verify(&file), Integrity::Corrupt(_)));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[derive(Default, Clone)]
+    struct MemoryFile(&'static str, Rc<RefCell<Option<Vec<u8>>>>);
+
+    impl EncryptedRW for MemoryFile {
+        fn read(&self) -> Result<Vec<u8>, CryptoError> {
+            let content = self.1.borrow().clone();
+            content.ok_or(std::io::Error::from(std::io::ErrorKind::NotFound).into())
+        }
+
+        fn write(&self, content: &[u8]) -> Result<(), CryptoError> {
+            *self.1.borrow_mut() = Some(content.to_vec());
+            Ok(())
+        }
+
+        fn path(&self) -> String {
+            self.0.to_string()
+        }
+    }
+
+    #[test]
+    fn test_shared_tokens() {
+        let shared = MemoryFile("shared", Default::default());
+        let mut team = TokenStore::new(shared.clone()).unwrap();
+        team.add_token("gitlab.com", "team").unwrap();
+        team.add_token("gitlab.corp", "team-corp").unwrap();
+
+        let personal = MemoryFile("personal", Default::default());
+        let mut store = TokenStore::new(personal.clone())
+            .unwrap()
+            .with_shared([shared.clone()]);
+        assert_eq!(store.get("gitlab.com"), Some("team".to_string()));
+        assert_eq!(store.origin("gitlab.com").as_deref(), Some("shared"));
+
+        store.add_token("gitlab.com", "mine").unwrap();
+        assert_eq!(store.get("gitlab.com"), Some("mine".to_string()));
+        assert_eq!(store.origin("gitlab.com").as_deref(), Some("personal"));
+        assert_eq!(store.get("gitlab.corp"), Some("team-corp".to_string()));
+        assert_eq!(store.list_domains(), ["gitlab.com", "gitlab.corp"]);
+        assert_eq!(store.origin("gitlab.example.com"), None);
+
+        // only the personal file was written
+        let team = TokenStore::new(shared).unwrap();
+        assert_eq!(team.get("gitlab.com"), Some("team".to_string()));
+        let mine = TokenStore::new(personal).unwrap();
+        assert_eq!(mine.list_domains(), ["gitlab.com"]);
+    }
+
+    #[test]
+    fn test_unreadable_shared_tokens() {
+        let dir = std::env::temp_dir().join(format!("ruggit-shared-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let team = PasswdProtectedFile::new("team secret", dir.join("team"));
+        TokenStore::new(team)
+            .unwrap()
+            .add_token("gitlab.com", "team")
+            .unwrap();
+
+        let personal = PasswdProtectedFile::new("mine", dir.join("personal"));
+        let store = TokenStore::new(personal)
+            .unwrap()
+            .with_shared([PasswdProtectedFile::new("mine", dir.join("team"))]);
+        assert_eq!(store.get("gitlab.com"), None);
+        assert_eq!(store.origin("gitlab.com"), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_inline_token() {
+        let mut store = KeyringStore::new(MockKeyring::default());
+        let mut warning = vec![];
+        let inline = inline_token("inline", "gitlab.com", false, &mut store, &mut warning).unwrap();
+        assert!(String::from_utf8(warning)
+            .unwrap()
+            .contains("shell history"));
+        assert_eq!(inline.token("gitlab.com"), Some("inline".to_string()));
+        assert_eq!(store.token("gitlab.com"), None, "not saved without --save");
+
+        let mut warning = vec![];
+        inline_token("inline", "gitlab.com", true, &mut store, &mut warning).unwrap();
+        assert!(!warning.is_empty());
+        assert_eq!(store.token("gitlab.com"), Some("inline".to_string()));
+    }
+
+    #[test]
+    fn test_ensure_token() {
+        let mut store = KeyringStore::new(MockKeyring::default());
+        assert!(ensure_token(&mut store, "gitlab.com", || Ok("first".into())).unwrap());
+        assert_eq!(store.token("gitlab.com"), Some("first".to_string()));
+
+        let stored = ensure_token(&mut store, "gitlab.com", || panic!("token read")).unwrap();
+        assert!(!stored);
+        assert_eq!(store.token("gitlab.com"), Some("first".to_string()));
+        assert!(ensure_token(&mut store, "gitlab.example.com", || anyhow::bail!("unset")).is_err());
+        assert_eq!(store.token("gitlab.example.com"), None);
+    }
+
+    #[test]
+    fn test_keyring_store() {
+        let mut store = KeyringStore::new(MockKeyring::default());
+        assert_eq!(store.token("gitlab.com"), None);
+
+        store.add_token("gitlab.com", "secret").unwrap();
+        assert_eq!(store.token("gitlab.com"), Some("secret".to_string()));
+
+        store.add_token("gitlab.com", "rotated").unwrap();
+        assert_eq!(store.token("gitlab.com"), Some("rotated".to_string()));
+
+        store.remove_token("gitlab.com").unwrap();
+        assert_eq!(store.token("gitlab.com"), None);
+    }
+
+    #[cfg(feature = "keyring")]
+    #[test]
+    #[ignore = "requires access to the OS keyring"]
+    fn test_os_keyring() {
+        let mut store = KeyringStore::new(OsKeyring);
+        let domain = "ruggit.test.invalid";
+        store.add_token(domain, "secret").unwrap();
+        assert_eq!(store.token(domain), Some("secret".to_string()));
+        store.remove_token(domain).unwrap();
+        assert_eq!(store.token(domain), None);
+    }
+
+    #[test]
+    fn test_chain_first_match() {
+        let mut chain = TokenChain::new();
+        chain.push(Fake("gitlab.com", None));
+        chain.push(Fake("gitlab.com", Some("first")));
+        chain.push(Fake("gitlab.com", Some("second")));
+        chain.push(Fake("gitlab.other.com", Some("other")));
+
+        assert_eq!(chain.token("gitlab.com"), Some("first".to_string()));
+        assert_eq!(chain.token("gitlab.other.com"), Some("other".to_string()));
+        assert_eq!(chain.token("gitlab.missing.com"), None);
     }
 }
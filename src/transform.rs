@@ -0,0 +1,174 @@
+use crate::gapi::GitlabVariable;
+use anyhow::Context;
+use base64::Engine;
+use std::str::FromStr;
+
+/// A built-in rewrite of variable values, applied before output.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Transform {
+    // leaves the value as is
+    Identity,
+    // values stored base64 encoded, such as certificates
+    Base64Decode,
+    // strips surrounding whitespace
+    Trim,
+}
+
+impl FromStr for Transform {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::Identity),
+            "base64-decode" => Ok(Self::Base64Decode),
+            "trim" => Ok(Self::Trim),
+            other => anyhow::bail!("unknown transform: {other}"),
+        }
+    }
+}
+
+impl Transform {
+    /// The variable with its value rewritten, failing if the value doesn't
+    /// fit the transform.
+    pub fn apply(&self, variable: &GitlabVariable) -> anyhow::Result<GitlabVariable> {
+        // there's no value to transform
+        if variable.hidden {
+            return Ok(variable.clone());
+        }
+        let value = match self {
+            Self::Identity => variable.value.clone(),
+            Self::Base64Decode => {
+                let decoded = base64::engine::general_purpose::STANDARD
+                    .decode(variable.value.trim())
+                    .with_context(|| format!("{} is not valid base64", variable.key))?;
+                String::from_utf8(decoded)
+                    .with_context(|| format!("{} does not decode to utf-8", variable.key))?
+            }
+            Self::Trim => variable.value.trim().to_string(),
+        };
+        Ok(GitlabVariable {
+            value,
+            ..variable.clone()
+        })
+    }
+}
+
+/// Applies `transforms` in order to every variable.
+pub fn apply_all(
+    variables: &[GitlabVariable],
+    transforms: &[Transform],
+) -> anyhow::Result<Vec<GitlabVariable>> {
+    variables
+        .iter()
+        .map(|variable| {
+            transforms
+                .iter()
+                .try_fold(variable.clone(), |variable, transform| {
+                    transform.apply(&variable)
+                })
+        })
+        .collect()
+}
+
+/// Overlays `overrides` of key and value on `variables`, in order so that a
+/// later override of a key wins. An overridden key takes the value in every
+/// scope it has, other keys are added for every environment.
+pub fn overlay(
+    variables: &[GitlabVariable],
+    overrides: &[(String, String)],
+) -> Vec<GitlabVariable> {
+    let mut overlaid = variables.to_vec();
+    for (key, value) in overrides {
+        let mut found = false;
+        for variable in overlaid.iter_mut().filter(|v| v.key == *key) {
+            variable.value = value.clone();
+            variable.hidden = false;
+            found = true;
+        }
+        if !found {
+            overlaid.push(GitlabVariable {
+                key: key.clone(),
+                value: value.clone(),
+                ..Default::default()
+            });
+        }
+    }
+    overlaid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(key: &str, value: &str) -> GitlabVariable {
+        GitlabVariable {
+            key: key.to_string(),
+            value: value.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_identity() {
+        let variable = var("CERT", " aGVsbG8= ");
+        assert_eq!(Transform::Identity.apply(&variable).unwrap(), variable);
+        assert_eq!(
+            apply_all(std::slice::from_ref(&variable), &[]).unwrap(),
+            vec![variable]
+        );
+    }
+
+    #[test]
+    fn test_base64_decode() {
+        let decoded = Transform::Base64Decode
+            .apply(&var("CERT", "aGVsbG8=\n"))
+            .unwrap();
+        assert_eq!(decoded, var("CERT", "hello"));
+
+        let error = Transform::Base64Decode
+            .apply(&var("CERT", "not base64!"))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "CERT is not valid base64");
+        // valid base64 of invalid utf-8
+        assert!(Transform::Base64Decode.apply(&var("BIN", "/w==")).is_err());
+    }
+
+    #[test]
+    fn test_apply_all() {
+        let transforms: Vec<Transform> = ["base64-decode", "trim"]
+            .iter()
+            .map(|t| t.parse().unwrap())
+            .collect();
+        // " hi \n"
+        let variables = apply_all(&[var("GREETING", "IGhpIAo=")], &transforms).unwrap();
+        assert_eq!(variables, vec![var("GREETING", "hi")]);
+        assert!("rot13".parse::<Transform>().is_err());
+    }
+
+    #[test]
+    fn test_overlay() {
+        let mut production = var("HOST", "prod.example.com");
+        production.environment_scope = Some("production".to_string());
+        production.masked = true;
+        let variables = vec![var("HOST", "localhost"), production, var("PORT", "80")];
+        let overrides = [("HOST", "test.example.com"), ("DEBUG", "1"), ("DEBUG", "2")]
+            .map(|(k, v)| (k.to_string(), v.to_string()));
+
+        let overlaid = overlay(&variables, &overrides);
+        let values: Vec<_> = overlaid
+            .iter()
+            .map(|v| (v.key.as_str(), v.value.as_str(), v.scope()))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("HOST", "test.example.com", "*"),
+                ("HOST", "test.example.com", "production"),
+                ("PORT", "80", "*"),
+                ("DEBUG", "2", "*"),
+            ]
+        );
+        assert!(overlaid[1].masked, "attributes are kept");
+        assert_eq!(overlay(&variables, &[]), variables);
+    }
+}
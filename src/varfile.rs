@@ -0,0 +1,182 @@
+use crate::gapi::GitlabVariable;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Variables read from a file.
+pub struct VarFile {
+    pub variables: Vec<GitlabVariable>,
+    // dotenv files and plain json objects carry no flags, they're inherited
+    pub values_only: bool,
+}
+
+/// Reads variables from a `.json` file or, for any other extension, a
+/// dotenv file.
+pub fn parse_file(path: &Path) -> Result<VarFile> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("unable to read {}", path.display()))?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => parse_json(&content),
+        _ => Ok(VarFile {
+            variables: parse_dotenv(&content)?,
+            values_only: true,
+        }),
+    }
+}
+
+/// Accepts either a list of variables, as returned by the gitlab api, or a
+/// plain object of keys to values.
+pub fn parse_json(content: &str) -> Result<VarFile> {
+    if let Ok(variables) = serde_json::from_str::<Vec<GitlabVariable>>(content) {
+        return Ok(VarFile {
+            variables,
+            values_only: false,
+        });
+    }
+    let map: BTreeMap<String, String> =
+        serde_json::from_str(content).context("expected a list of variables or an object")?;
+    let variables = map
+        .into_iter()
+        .map(|(key, value)| GitlabVariable {
+            key,
+            value,
+            ..Default::default()
+        })
+        .collect();
+    Ok(VarFile {
+        variables,
+        values_only: true,
+    })
+}
+
+pub fn parse_dotenv(content: &str) -> Result<Vec<GitlabVariable>> {
+    let mut variables = vec![];
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .with_context(|| format!("line {}: expected KEY=VALUE", i + 1))?;
+        variables.push(GitlabVariable {
+            key: key.trim().to_string(),
+            value: unquote(value.trim()),
+            ..Default::default()
+        });
+    }
+    Ok(variables)
+}
+
+// double quotes undo the escaping of `output::render_dotenv`, single quotes
+// are taken literally
+fn unquote(value: &str) -> String {
+    let quoted = |quote| value.len() >= 2 && value.starts_with(quote) && value.ends_with(quote);
+    if quoted('\'') {
+        return value[1..value.len() - 1].to_string();
+    }
+    if !quoted('"') {
+        return value.to_string();
+    }
+    let mut unescaped = String::new();
+    let mut chars = value[1..value.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some(next @ ('\\' | '"'))) => {
+                unescaped.push(next);
+                chars.next();
+            }
+            ('\\', Some('n')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Files of [`VarFile::values_only`] carry no flags, keep those and the
+/// description of the matching remote variables so they aren't reset. An
+/// unset scope matches gitlab's `*`.
+pub fn inherit_attributes(desired: &mut [GitlabVariable], remote: &[GitlabVariable]) {
+    for variable in desired.iter_mut() {
+        let Some(existing) = remote
+            .iter()
+            .find(|r| r.key == variable.key && r.scope() == variable.scope())
+        else {
+            continue;
+        };
+        variable.masked = existing.masked;
+        variable.protected = existing.protected;
+        variable.raw = existing.raw;
+        variable.description = existing.description.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dotenv() {
+        let content =
+            "# comment\n\nHOST=localhost\nexport PORT = 8080\nQUOTED=\"a b\"\nSINGLE='x=y'\n";
+        let variables = parse_dotenv(content).unwrap();
+        let pairs: Vec<_> = variables
+            .iter()
+            .map(|v| (v.key.as_str(), v.value.as_str()))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("HOST", "localhost"),
+                ("PORT", "8080"),
+                ("QUOTED", "a b"),
+                ("SINGLE", "x=y")
+            ]
+        );
+        assert!(parse_dotenv("NOT A VARIABLE").is_err());
+    }
+
+    #[test]
+    fn test_dotenv_round_trip() {
+        let value = "line one\nsaid \"hi\" in C:\\temp\\";
+        let variables = BTreeMap::from([(
+            "CERT".to_string(),
+            GitlabVariable {
+                key: "CERT".to_string(),
+                value: value.to_string(),
+                ..Default::default()
+            },
+        )]);
+        let rendered = crate::output::render_dotenv(&variables, false);
+        let parsed = parse_dotenv(&rendered).unwrap();
+        assert_eq!(parsed[0].value, value);
+        // single quotes keep backslashes
+        assert_eq!(parse_dotenv(r"RAW='a\nb'").unwrap()[0].value, r"a\nb");
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let parsed = parse_json(r#"{"HOST": "localhost"}"#).unwrap();
+        assert!(parsed.values_only);
+        let variables = parsed.variables;
+        assert_eq!(variables[0].key, "HOST");
+        assert_eq!(variables[0].value, "localhost");
+
+        let parsed = parse_json(
+            r#"[{"key": "HOST", "value": "prod", "description": null, "environment_scope": "production", "masked": true}]"#,
+        )
+        .unwrap();
+        assert!(!parsed.values_only);
+        let variables = parsed.variables;
+        assert_eq!(
+            variables[0].environment_scope.as_deref(),
+            Some("production")
+        );
+        assert!(variables[0].masked);
+        assert!(parse_json("[1, 2]").is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use std::future::Future;
+use std::time::Duration;
+
+/// Calls `fetch` every `interval` and hands each result to `show`, until
+/// `stop` completes. Failed fetches are shown too rather than ending the
+/// loop, a dashboard shouldn't go away on a transient error. `sleep` is the
+/// clock, [`tokio::time::sleep`] outside of tests.
+pub async fn watch<T, F, S>(
+    interval: Duration,
+    mut fetch: impl FnMut() -> F,
+    mut show: impl FnMut(anyhow::Result<T>),
+    mut sleep: impl FnMut(Duration) -> S,
+    stop: impl Future<Output = ()>,
+) where
+    F: Future<Output = anyhow::Result<T>>,
+    S: Future<Output = ()>,
+{
+    tokio::pin!(stop);
+    loop {
+        tokio::select! {
+            biased;
+            _ = &mut stop => return,
+            fetched = fetch() => show(fetched),
+        }
+        tokio::select! {
+            biased;
+            _ = &mut stop => return,
+            _ = sleep(interval) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use tokio::sync::Notify;
+
+    #[tokio::test]
+    async fn test_watch() {
+        let fetches = Cell::new(0);
+        let shown = RefCell::new(vec![]);
+        let slept = RefCell::new(vec![]);
+        let stop = Notify::new();
+        let fetch = || {
+            fetches.set(fetches.get() + 1);
+            let n = fetches.get();
+            async move {
+                match n {
+                    2 => Err(anyhow::anyhow!("unavailable")),
+                    n => Ok(n),
+                }
+            }
+        };
+        let show = |fetched: anyhow::Result<u32>| {
+            let mut shown = shown.borrow_mut();
+            shown.push(fetched.map_err(|e| e.to_string()));
+            // cancelled, as by ctrl-c, after the third refresh
+            if shown.len() == 3 {
+                stop.notify_one();
+            }
+        };
+        // the mocked clock returns right away, only recording the wait
+        let slept = &slept;
+        let sleep = |interval| async move { slept.borrow_mut().push(interval) };
+
+        watch(Duration::from_secs(5), fetch, show, sleep, stop.notified()).await;
+        assert_eq!(fetches.get(), 3);
+        assert_eq!(
+            *shown.borrow(),
+            vec![Ok(1), Err("unavailable".to_string()), Ok(3)]
+        );
+        assert_eq!(*slept.borrow(), vec![Duration::from_secs(5); 2]);
+    }
+}